@@ -0,0 +1,156 @@
+//! Wiring of the board the firmware is built for: which pins the pads, the status LED and the
+//! buttons are on, and which drum each pad plays by default.
+//!
+//! The board is picked by the chip feature built with, `esp32c3` by default:
+//!
+//! | Pin              | `esp32c3` (ESP32-C3-DevKitM-1)  | `esp32c6` (ESP32-C6-DevKitC-1) |
+//! |------------------|---------------------------------|--------------------------------|
+//! | Pads, in order   | GPIO0 1 3 4 5 6 7 10 20 21      | GPIO0 1 2 3 6 7 10 11 18 19    |
+//! | Status LED       | GPIO8                           | GPIO8                          |
+//! | Channel button   | GPIO9 (BOOT)                    | GPIO9 (BOOT)                   |
+//! | Panic button     | GPIO2                           | GPIO15                         |
+//! | `sensor-power`   | GPIO18 (USB D-)                 | GPIO20                         |
+//! | `preset-button`  | GPIO19 (USB D+)                 | GPIO21                         |
+//! | `adc-velocity`   | None, no ADC1 pin left          | GPIO4 5                        |
+//!
+//! Supporting another board means adding its own [`board_pins!`] here behind a Cargo feature (and
+//! [`StatusLedPin`] if its status LED isn't on GPIO8), leaving `main` as is.
+
+#[cfg(not(test))]
+use esp_hal::gpio::AnyPin;
+
+use crate::config::PAD_COUNT;
+use crate::tasks::gpio::DrumNote;
+
+/// The pins of the board, as taken by [`board_pins!`].
+#[cfg(not(test))]
+pub struct BoardPins {
+    /// In the order of `Config::pads`.
+    pub pads: [AnyPin<'static>; PAD_COUNT],
+    /// The on-board LED, or the WS2812 on boards that have one instead (the `ws2812` feature).
+    pub status_led: AnyPin<'static>,
+    /// Cycles the global MIDI channel, see
+    /// [`channel_button_task`](crate::tasks::button::channel_button_task).
+    pub channel_button: AnyPin<'static>,
+    /// Silences hanging notes, see [`panic_button_task`](crate::tasks::button::panic_button_task).
+    pub panic_button: AnyPin<'static>,
+    /// Powers the sensor front-end with the `sensor-power` feature.
+    #[cfg(feature = "sensor-power")]
+    pub sensor_power: AnyPin<'static>,
+    /// Loads the next config preset with the `preset-button` feature, see
+    /// [`preset_button_task`](crate::tasks::button::preset_button_task).
+    #[cfg(feature = "preset-button")]
+    pub preset_button: AnyPin<'static>,
+    /// Sampled for the velocity of pads 8 and 9 with the `adc-velocity` feature, see
+    /// [`AdcVelocity`](crate::tasks::gpio::velocity::AdcVelocity). As is, for the ADC.
+    #[cfg(feature = "adc-velocity")]
+    pub velocity_adc: (
+        esp_hal::peripherals::GPIO4<'static>,
+        esp_hal::peripherals::GPIO5<'static>,
+    ),
+}
+
+/// Move the board's pins out of the `esp_hal::peripherals::Peripherals`, into [`BoardPins`]. A
+/// macro so that the rest of the peripherals stay usable.
+#[cfg(all(feature = "esp32c3", not(test)))]
+macro_rules! board_pins {
+    ($peripherals:expr) => {
+        $crate::board::BoardPins {
+            pads: [
+                $peripherals.GPIO0.degrade(),
+                $peripherals.GPIO1.degrade(),
+                $peripherals.GPIO3.degrade(),
+                $peripherals.GPIO4.degrade(),
+                $peripherals.GPIO5.degrade(),
+                $peripherals.GPIO6.degrade(),
+                $peripherals.GPIO7.degrade(),
+                $peripherals.GPIO10.degrade(),
+                $peripherals.GPIO20.degrade(),
+                $peripherals.GPIO21.degrade(),
+            ],
+            // The `StatusLedPin`.
+            status_led: $peripherals.GPIO8.degrade(),
+            // The BOOT button on most boards.
+            channel_button: $peripherals.GPIO9.degrade(),
+            // A strapping pin, fine for a button to ground as long as it's not held at reset.
+            panic_button: $peripherals.GPIO2.degrade(),
+            #[cfg(feature = "sensor-power")]
+            sensor_power: $peripherals.GPIO18.degrade(),
+            #[cfg(feature = "preset-button")]
+            preset_button: $peripherals.GPIO19.degrade(),
+        }
+    };
+}
+
+/// Move the board's pins out of the `esp_hal::peripherals::Peripherals`, into [`BoardPins`]. A
+/// macro so that the rest of the peripherals stay usable.
+///
+/// Leaves out the USB pins (GPIO12 and GPIO13), so `debug-console` goes with any feature, and the
+/// UART0 pins (GPIO16 and GPIO17) of the board's USB to UART bridge.
+#[cfg(all(feature = "esp32c6", not(test)))]
+macro_rules! board_pins {
+    ($peripherals:expr) => {
+        $crate::board::BoardPins {
+            pads: [
+                $peripherals.GPIO0.degrade(),
+                $peripherals.GPIO1.degrade(),
+                $peripherals.GPIO2.degrade(),
+                $peripherals.GPIO3.degrade(),
+                $peripherals.GPIO6.degrade(),
+                $peripherals.GPIO7.degrade(),
+                $peripherals.GPIO10.degrade(),
+                $peripherals.GPIO11.degrade(),
+                $peripherals.GPIO18.degrade(),
+                $peripherals.GPIO19.degrade(),
+            ],
+            // The WS2812 of the board, the `StatusLedPin`.
+            status_led: $peripherals.GPIO8.degrade(),
+            // The BOOT button.
+            channel_button: $peripherals.GPIO9.degrade(),
+            // A strapping pin, fine for a button to ground as long as it's not held at reset.
+            panic_button: $peripherals.GPIO15.degrade(),
+            #[cfg(feature = "sensor-power")]
+            sensor_power: $peripherals.GPIO20.degrade(),
+            #[cfg(feature = "preset-button")]
+            preset_button: $peripherals.GPIO21.degrade(),
+            #[cfg(feature = "adc-velocity")]
+            velocity_adc: ($peripherals.GPIO4, $peripherals.GPIO5),
+        }
+    };
+}
+
+#[cfg(not(test))]
+pub(crate) use board_pins;
+
+/// The [`BoardPins::status_led`] pin, for [`steal_status_led`]. GPIO8 on all the supported boards,
+/// which each [`board_pins!`] takes as the status LED. A board with it elsewhere gives this its own
+/// version behind the board's feature, like [`board_pins!`].
+#[cfg(not(test))]
+pub type StatusLedPin = esp_hal::peripherals::GPIO8<'static>;
+
+/// The drum each pad plays by default, in the order of [`BoardPins::pads`].
+pub const DEFAULT_PAD_NOTES: [DrumNote; PAD_COUNT] = [
+    DrumNote::HighTom,
+    DrumNote::PedalHiHat,
+    DrumNote::OpenHiHat,
+    DrumNote::CrashCymbal1,
+    DrumNote::CrashCymbal2,
+    DrumNote::RideCymbal,
+    DrumNote::FloorTom,
+    DrumNote::LowTom,
+    DrumNote::BassDrum,
+    DrumNote::Snare,
+];
+
+/// The status LED pin, for the panic handler to take over.
+///
+/// # Safety
+///
+/// Only once nothing else is going to drive the pin anymore, i.e. when panicking.
+#[cfg(not(test))]
+pub unsafe fn steal_status_led() -> AnyPin<'static> {
+    use esp_hal::gpio::Pin;
+
+    // SAFETY: up to the caller.
+    unsafe { StatusLedPin::steal() }.degrade()
+}
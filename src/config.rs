@@ -0,0 +1,1159 @@
+use core::{cell::RefCell, ops::RangeInclusive};
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::NoopRawMutex},
+    signal::Signal,
+};
+use embassy_time::Duration;
+use midi_types::{Channel, Note};
+
+use crate::board::DEFAULT_PAD_NOTES;
+use crate::tasks::gpio::DrumNote;
+
+pub mod blob;
+pub mod nvs;
+
+pub const PAD_COUNT: usize = 10;
+pub const DEFAULT_MIDI_CHANNEL: u8 = 9; // GM percussion channel 10.
+/// [`Config::velocity_gains`] leaving the velocity as is.
+pub const UNITY_VELOCITY_GAIN: u8 = 100;
+/// Range of [`Config::tx_power`] in dBm.
+pub const TX_POWER_RANGE: RangeInclusive<i8> = -20..=20;
+/// [`Config::tx_power`] by default, the ESP32-C3 BLE controller's own default (rounded down).
+pub const DEFAULT_TX_POWER: i8 = 8;
+/// Range of [`Config::timestamp_offset`] in milliseconds.
+pub const TIMESTAMP_OFFSET_RANGE: RangeInclusive<i16> = -500..=500;
+
+/// Version of the config schema. Bump whenever the fields of [`Config`] change in a way that
+/// existing clients (or stored configs) must know about.
+pub const CONFIG_VERSION: u8 = 38;
+
+/// Device-wide settings that can be changed at runtime.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Settings of each pad, in the order the pad pins are given to
+    /// [`watch_gpios_task`](crate::tasks::gpio::watch_gpios_task).
+    pub pads: [PadConfig; PAD_COUNT],
+    /// Program selected on the host once a client subscribes, so that e.g. a soft sampler loads
+    /// the right drum kit by itself. Nothing is sent if `None`.
+    pub program_select: Option<ProgramSelect>,
+    /// Maximum random deviation (±) applied to the velocity of each hit. 0 disables it.
+    pub humanize_velocity: u8,
+    /// MIDI channel (0..=15) of the notes without an override in `note_channels`, and of the
+    /// non-note messages (e.g. program select).
+    pub midi_channel: u8,
+    /// Per-note MIDI channel (0..=15) overrides, indexed by [`DrumNote::index`]. A note with an
+    /// override always uses it, regardless of `midi_channel`, so e.g. the cymbals can stay on
+    /// their own channel while the drums follow the global one.
+    pub note_channels: [Option<u8>; DrumNote::COUNT],
+    /// Per-note velocity gain in percent, indexed by [`DrumNote::index`], to even out pads with
+    /// different output levels. Applied first, before any other velocity processing.
+    pub velocity_gains: [u8; DrumNote::COUNT],
+    /// Send Active Sensing after this long without any other message, for hosts that drop a
+    /// silent BLE MIDI source. Zero disables it.
+    ///
+    /// A receiver honoring Active Sensing expects a message at least every 300 ms once it has
+    /// seen one, and may shut its notes off otherwise, so keep this under that. Like any system
+    /// message, it makes the next message carry its status byte again with `running-status`.
+    pub active_sensing_interval: Duration,
+    /// BLE transmit power in dBm, within [`TX_POWER_RANGE`], applied when advertising starts.
+    /// More reaches further across a large room, less saves battery. Rounded down to a level the
+    /// radio supports.
+    ///
+    /// It's the power of the advertising, the most the controller may use for it: HCI has no
+    /// command setting the power of a connection, which is up to the controller.
+    ///
+    /// Regulations cap the radiated power (e.g. 20 dBm EIRP in the EU), antenna gain included,
+    /// so a board with a high-gain antenna may have to stay below the maximum.
+    pub tx_power: i8,
+    /// MIDI thru: echo the MIDI written to the MIDI characteristic back out as notifications,
+    /// along with the hits. See [`ThruLoopGuard`](crate::tasks::ble::ThruLoopGuard) for how a
+    /// client echoing them in turn is kept from looping.
+    pub midi_thru: bool,
+    /// Value of the MIDI characteristic until the first hit, which is what a host reading it
+    /// right after connecting gets. A change applies from the next connection.
+    pub initial_midi_event: InitialMidiEvent,
+    /// MPE-style channel rotation: the `(first, last)` MIDI channels (0..=15) the notes are spread
+    /// across, so that the host can apply expression (pitch bend, pressure, ...) to each
+    /// sounding note on its own. Each hit takes the next channel in turn not used by a note still
+    /// within its gate, and its Note Off goes out on the same channel. With all of them in use,
+    /// the next one in turn is shared anyway. Overrides `midi_channel` and `note_channels` for the
+    /// notes, `None` disables it.
+    ///
+    /// For an MPE lower zone, leave its master channel (channel 1, i.e. 0) out of the range.
+    pub mpe_channels: Option<(u8, u8)>,
+    /// Foot splash: the hi-hat pedal pressed and released again within this plays an open hi-hat
+    /// on the release, on top of the pedal note of the press. A press held longer is just the
+    /// pedal closing. Zero disables it. Changes apply once the pads are re-armed.
+    ///
+    /// The splash is played once the pedal is open again, so it's never turned into a closed
+    /// hi-hat like a hit of the open hi-hat pad with the pedal held is.
+    pub hi_hat_splash_window: Duration,
+    /// Hysteresis of the [`hi_hat_splash_window`](Self::hi_hat_splash_window), so that presses
+    /// about as long as the window don't flip between splash and chick from one to the next:
+    /// after a splash, the next press is still one when held up to the window plus this, and
+    /// after a chick, it takes a press shorter than the window minus this. E.g. with a 100 ms
+    /// window and 20 ms of hysteresis, a foot going 110 ms, 95 ms, 110 ms from chicks plays chicks
+    /// throughout, until one under 80 ms. Zero (the default) makes it a plain threshold. Like the
+    /// window, changes apply once the pads are re-armed.
+    pub hi_hat_splash_hysteresis: Duration,
+    /// MIDI note numbers the drums are sent as, for samplers not following the General MIDI map.
+    pub note_map: NoteMap,
+    /// Shift of the timestamps of the hits in the MIDI packets, in milliseconds within
+    /// [`TIMESTAMP_OFFSET_RANGE`], to line the hits up with the host when its BLE MIDI latency
+    /// compensation is off: negative to have them played as if hit earlier, positive later. Only
+    /// matters to hosts honoring the BLE MIDI timestamps, the others play each packet as it comes.
+    ///
+    /// The packets still go out when they would without it, it's only what they tell the host.
+    /// The timestamps count milliseconds modulo 8192, so a shifted one wraps around like any.
+    pub timestamp_offset: i16,
+    /// Number of hits of each pad ignored after the sensors are switched on, for sensors whose
+    /// supply settles with a dip (or a few pulses) longer than the settling time the hits are
+    /// ignored for anyway. Zero by default, as any more swallows as many real hits of every pad if
+    /// the sensors turn out to settle cleanly: only worth it if the phantom notes show up after
+    /// switching on, and no higher than their count. Doesn't apply to the pads re-armed by a
+    /// reload or calibration, as the sensors stay on through those.
+    pub warmup_hits: u8,
+    /// Response of the played velocity to the sensed one, after the
+    /// [`velocity_gains`](Self::velocity_gains) and before the humanizing. Read on each hit, so a
+    /// change applies from the next one.
+    pub velocity_curve: VelocityCurve,
+    /// MIDI channel (0..=15) the MIDI written to the MIDI characteristic is read for control
+    /// messages on, see
+    /// [`ControlCommand::from_midi`](crate::tasks::ble::control::ControlCommand::from_midi). The
+    /// control messages are acted on and not echoed, while the rest, on this channel or any other,
+    /// only goes to MIDI thru if enabled. `None` (the default) reads no control messages at all.
+    ///
+    /// Independent of `midi_channel`: it may be the same channel, but one apart from it keeps a
+    /// host sending on the drums' channel from triggering anything by accident.
+    pub control_channel: Option<u8>,
+    /// Hits played up to this long before a connection is established are kept, and sent once
+    /// the client subscribes, rather than dropped. Zero (the default) drops them all, as before.
+    ///
+    /// Saves the first few hits of a player starting right after switching the kit on, but those
+    /// come out all at once and late, as late as the connection took, which sounds worse than
+    /// silence when playing along. So keep it to a few seconds, and to when a lost hit matters
+    /// more, e.g. while recording. Only the latest
+    /// [`HIT_EVENTS_DEPTH`](crate::tasks::gpio::HIT_EVENTS_DEPTH) hits are kept, and they go out
+    /// with their own timestamps.
+    pub pre_connection_window: Duration,
+    /// What happens to the BLE connection when the sensors switch off, i.e. the kit is powered
+    /// off. With this set, the notes are silenced (All Sound Off and All Notes Off on every
+    /// channel the notes go out on) and the link is actively disconnected, so the host sees the
+    /// kit go away right away. Off (the default) keeps the connection open as before, idle until
+    /// the host drops it.
+    ///
+    /// Either way, advertising starts again once the sensors switch back on, for the host to
+    /// reconnect: by itself if it reconnects to the devices it knows, otherwise from its BLE MIDI
+    /// settings as on the first connection.
+    pub disconnect_on_sensors_off: bool,
+    /// A MIDI packet byte-identical to the previous one sent less than this long before is
+    /// dropped, for hosts that take such a packet for a duplicate of the previous notification
+    /// and misbehave. Zero (the default) sends them all.
+    ///
+    /// At the BLE level, unlike the per-pad debouncing: the packets carry their timestamp, so the
+    /// ones dropped are those of the same messages within the same millisecond (or a multiple of
+    /// the 8192 ms the timestamps wrap around at). That's what a genuine quick repeat looks like
+    /// too, e.g. two pads playing the same note at the same velocity at once, which then only
+    /// plays once: keep it to a few milliseconds.
+    pub duplicate_packet_window: Duration,
+    /// Most notes sounding at once, i.e. between their Note On and Note Off, for hosts or
+    /// samplers that choke on more. A hit going over it first ends the note picked by the
+    /// [`voice_stealing`](Self::voice_stealing) policy, sending its Note Off right before the new
+    /// Note On. Zero (the default) leaves it unlimited, up to the 16 Note Offs kept pending
+    /// anyway.
+    ///
+    /// A note layered on a hit takes a voice of its own. Notes without a gate don't take one, as
+    /// their Note Off goes out right away, and neither do the ones sent with their Note Off in
+    /// the same packet by the `batched-note-off` feature. Read on each hit, so a change applies
+    /// from the next one.
+    pub max_polyphony: u8,
+    /// Which sounding note a hit going over the [`max_polyphony`](Self::max_polyphony) ends.
+    pub voice_stealing: VoiceStealing,
+    /// Identifier of the kit of the user's choosing, advertised in the service data for a
+    /// companion app to tell several kits apart before connecting, see
+    /// [`advertised_service_data`](crate::tasks::ble::control::advertised_service_data). Zero by
+    /// default. A change applies from the next time the kit advertises.
+    pub advertised_id: u16,
+    /// A pad staying high (i.e. released) this long after all the other pads went low, as they
+    /// do when the sensors switch off, is taken for stuck or latched high: it's logged and left
+    /// out of detecting the sensors off, which would otherwise never happen, keeping the pads
+    /// armed with the sensors off. Zero disables it, leaving such a pad to keep the sensors
+    /// detected on. Changes apply once the pads are re-armed.
+    ///
+    /// A stuck pad is left out of detecting the sensors on too, as a pad high is what tells it.
+    /// It recovers by itself once it goes low: that plays as a hit like any, and the pad is
+    /// counted again from its next release. The pads found high at boot while the others are
+    /// low are handled the same way.
+    pub stuck_pad_timeout: Duration,
+}
+
+impl Config {
+    /// MIDI note number the `note` is sent as.
+    pub fn note_number(&self, note: DrumNote) -> Note {
+        Note::new(self.note_map.number_of(note))
+    }
+
+    /// MIDI channel the `note` is sent on.
+    pub fn channel_of(&self, note: DrumNote) -> Channel {
+        Channel::new(self.note_channels[note.index()].unwrap_or(self.midi_channel))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pads: DEFAULT_PAD_NOTES.map(PadConfig::new),
+            program_select: None,
+            humanize_velocity: 0,
+            midi_channel: DEFAULT_MIDI_CHANNEL,
+            note_channels: [None; DrumNote::COUNT],
+            velocity_gains: [UNITY_VELOCITY_GAIN; DrumNote::COUNT],
+            active_sensing_interval: Duration::from_ticks(0),
+            tx_power: DEFAULT_TX_POWER,
+            midi_thru: false,
+            initial_midi_event: InitialMidiEvent::Reset,
+            mpe_channels: None,
+            hi_hat_splash_window: Duration::from_ticks(0),
+            hi_hat_splash_hysteresis: Duration::from_ticks(0),
+            note_map: NoteMap::GeneralMidi,
+            timestamp_offset: 0,
+            warmup_hits: 0,
+            velocity_curve: VelocityCurve::Linear,
+            control_channel: None,
+            pre_connection_window: Duration::from_ticks(0),
+            disconnect_on_sensors_off: false,
+            duplicate_packet_window: Duration::from_ticks(0),
+            max_polyphony: 0,
+            voice_stealing: VoiceStealing::Oldest,
+            advertised_id: 0,
+            stuck_pad_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// [`Config`] shared between the tasks. Updates are persisted by
+/// [`persist_config_task`](crate::tasks::nvs::persist_config_task), once they stop coming or when
+/// asked to with [`request_save`](Self::request_save).
+pub struct SharedConfig {
+    config: Mutex<NoopRawMutex, RefCell<Config>>,
+    updated: Signal<NoopRawMutex, ()>,
+    save_requested: Signal<NoopRawMutex, ()>,
+}
+
+impl SharedConfig {
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config: Mutex::new(RefCell::new(config)),
+            updated: Signal::new(),
+            save_requested: Signal::new(),
+        }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&Config) -> R) -> R {
+        self.config.lock(|c| f(&c.borrow()))
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut Config) -> R) -> R {
+        let result = self.config.lock(|c| f(&mut c.borrow_mut()));
+        self.updated.signal(());
+        result
+    }
+
+    /// Wait until the config has been updated since the last call.
+    pub async fn wait_updated(&self) {
+        self.updated.wait().await
+    }
+
+    /// Have the config saved right away, without waiting for the updates to stop.
+    pub fn request_save(&self) {
+        self.save_requested.signal(());
+    }
+
+    /// Wait until [`request_save`](Self::request_save) has been called since the last call.
+    pub async fn wait_save_requested(&self) {
+        self.save_requested.wait().await
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct ProgramSelect {
+    pub program: u8,
+    /// Bank select `(MSB, LSB)`, sent as CC 0 and CC 32 before the program change.
+    pub bank: Option<(u8, u8)>,
+}
+
+impl ProgramSelect {
+    /// Encode as `[program, bank MSB, bank LSB]`. A program above 127 means no program select,
+    /// and a bank MSB above 127 means no bank select.
+    pub fn encode(program_select: Option<Self>) -> [u8; 3] {
+        match program_select {
+            None => [0xFF; 3],
+            Some(Self { program, bank }) => {
+                let (msb, lsb) = bank.unwrap_or((0xFF, 0xFF));
+                [program, msb, lsb]
+            }
+        }
+    }
+
+    /// Decode what's encoded by [`encode`](Self::encode), or `None` if it's malformed.
+    pub fn decode([program, bank_msb, bank_lsb]: [u8; 3]) -> Option<Option<Self>> {
+        if program > 0x7F {
+            return Some(None);
+        }
+        let bank = match (bank_msb, bank_lsb) {
+            (0x80.., _) => None,
+            (_, 0x80..) => return None,
+            bank => Some(bank),
+        };
+        Some(Some(Self { program, bank }))
+    }
+}
+
+/// Settings of a single drum pad.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct PadConfig {
+    pub note: DrumNote,
+    pub polarity: SensorPolarity,
+    /// Internal pull resistor of the pad's pin, for sensors that leave it floating at times,
+    /// which picks up noise as false triggers. `None` (the default) goes by the
+    /// [`polarity`](Self::polarity): no pull for [`Normal`](SensorPolarity::Normal) pads, a
+    /// pull-down for [`Inverted`](SensorPolarity::Inverted) ones. See [`SensorPull`] for which
+    /// wiring needs which.
+    pub pull: Option<SensorPull>,
+    pub trigger_edge: TriggerEdge,
+    pub debounce: DebounceProfile,
+    /// Double trigger rejection: further humps of the sensor within this after a hit, as a single
+    /// stroke can produce, are merged into the hit, which takes the highest velocity of them all.
+    /// Zero disables it.
+    ///
+    /// Unlike the [`debounce`](Self::debounce), which ignores what follows a hit, this keeps the
+    /// velocity of a later and stronger hump, at the cost of the hit only being sent once the
+    /// window is over. So keep it to a few milliseconds.
+    pub double_trigger_window: Duration,
+    /// Minimum duration the sensor level must stay unchanged to be considered stable. Noisier or
+    /// slower-settling sensors (e.g. cymbal piezos) may need longer than fast bass pedals.
+    pub stable_duration: Duration,
+    /// Time between a hit's Note On and its Note Off. With zero, the Note Off is sent right away.
+    pub gate: Duration,
+    /// Holds each hit's Note Off until the sensor is released instead of sending it after the
+    /// [`gate`](Self::gate), which is then unused, for sensors that tell how long they're held
+    /// (e.g. a hold-type switch). The [`min_gate`](Self::min_gate) still applies.
+    ///
+    /// The release is debounced like the hit, by the [`stable_duration`](Self::stable_duration),
+    /// and isn't looked for before the pad's debounce interval is over, so that's the shortest
+    /// note it plays. A hit rejected by the debounce has no Note Off of its own.
+    pub gate_follows_sensor: bool,
+    /// Makes the gate follow the velocity, e.g. for cymbals to ring longer the harder they're hit:
+    /// the gate of a hit at velocity 127, [`gate`](Self::gate) being the one at velocity 1, and
+    /// linear in between. See [`gate_for`](Self::gate_for). `None` (the default) keeps the gate
+    /// the same for all velocities.
+    pub max_velocity_gate: Option<Duration>,
+    /// Minimum time between a hit's Note On and its Note Off, for samplers that retrigger oddly
+    /// when the two arrive too close together. Extends [`gate`](Self::gate) when it's shorter.
+    ///
+    /// A new hit of the same note sends the Note Off still held back by this right before its own
+    /// Note On, like [`TriggerMode::Mono`] does, so the hold can't outlast the pad's debounce
+    /// interval on fast repeated hits.
+    pub min_gate: Duration,
+    pub trigger_mode: TriggerMode,
+    /// Makes this a control pad (e.g. a sustain pedal with CC 64), sending this instead of notes
+    /// when pressed (hit) and released, on the global MIDI channel.
+    pub control: Option<PadControl>,
+    /// Lowest and highest velocity (1..=127) the pad's notes are clamped to, after any other
+    /// velocity processing, compressing its dynamics, e.g. `(90, 120)` for an even kick.
+    pub velocity_range: (u8, u8),
+    /// Velocity (1..=127) every hit of the pad plays at regardless of how hard it's hit, e.g. 127
+    /// for an accent pad or a one-shot trigger. Bypasses the velocity sensing and all the velocity
+    /// processing, the [`velocity_range`](Self::velocity_range) clamp included. `None` (the
+    /// default) keeps the velocity dynamic.
+    pub fixed_velocity: Option<u8>,
+    /// A second note layered on the pad's own, e.g. a splash along with a crash, played by each
+    /// hit in the same packet and with the same gate. `None` (the default) plays the pad's note
+    /// alone.
+    pub layer: Option<NoteLayer>,
+    /// Note repeat: holding the pad after a hit replays the hit's note every so often until it's
+    /// released, like a roll machine. `None` (the default) plays each hit once. Not for the
+    /// control pads nor the hi-hat pedal, nor for pads triggered on the release, which are never
+    /// held past the hit.
+    pub repeat: Option<NoteRepeat>,
+    /// Which pads have their edges handled first when several are hit together, highest first,
+    /// e.g. for the kick and snare to go out ahead of the cymbals in a crash on the downbeat.
+    /// Pads of the same priority go in pad order. All 0 by default, i.e. pad order.
+    ///
+    /// The pads are all watched by one task, their futures gathered in a `select_slice`, which
+    /// has no fairness of its own: each time the task is woken, it polls every future in slice
+    /// order. So of the pads whose edges came in since the last poll, the first in the slice
+    /// timestamps its hit and queues it first, and the hit events are sent in the order queued,
+    /// one notification each. The priority only orders the slice, so there's no cost per hit.
+    ///
+    /// It only matters for edges that land in the same poll (within tens of microseconds of each
+    /// other), or hits queued behind others waiting for a slow link. Then a pad moved first
+    /// saves one queued notification per pad it's moved ahead of, which is up to 9 with all the
+    /// pads hit together. How long a notification takes depends on the connection interval and
+    /// how many the central takes per connection event, so time it on the actual setup, e.g.
+    /// with the `trace-edges` feature and the host's MIDI monitor.
+    pub scan_priority: u8,
+}
+
+impl PadConfig {
+    pub const DEFAULT_STABLE_DURATION: Duration = Duration::from_micros(150);
+
+    /// Gate of a hit played at `velocity` (1..=127), before [`min_gate`](Self::min_gate): from
+    /// [`gate`](Self::gate) at 1 to [`max_velocity_gate`](Self::max_velocity_gate) at 127, so e.g.
+    /// 50 ms and 550 ms give 300 ms at velocity 64. Shorter at 127 than at 1 works too.
+    pub fn gate_for(&self, velocity: u8) -> Duration {
+        let Some(max_velocity_gate) = self.max_velocity_gate else {
+            return self.gate;
+        };
+        let (low, high) = (
+            self.gate.as_ticks() as i64,
+            max_velocity_gate.as_ticks() as i64,
+        );
+        let step = i64::from(velocity.clamp(1, 127) - 1);
+        Duration::from_ticks((low + (high - low) * step / 126) as u64)
+    }
+
+    pub const fn new(note: DrumNote) -> Self {
+        Self {
+            note,
+            polarity: SensorPolarity::Normal,
+            pull: None,
+            trigger_edge: TriggerEdge::Hit,
+            debounce: DebounceProfile::Standard,
+            double_trigger_window: Duration::from_ticks(0),
+            stable_duration: Self::DEFAULT_STABLE_DURATION,
+            gate: Duration::from_ticks(0),
+            gate_follows_sensor: false,
+            max_velocity_gate: None,
+            min_gate: Duration::from_ticks(0),
+            trigger_mode: TriggerMode::Poly,
+            control: None,
+            velocity_range: (1, 127),
+            fixed_velocity: None,
+            layer: None,
+            repeat: None,
+            scan_priority: 0,
+        }
+    }
+
+    pub const fn with_polarity(self, polarity: SensorPolarity) -> Self {
+        Self { polarity, ..self }
+    }
+
+    pub const fn with_pull(self, pull: SensorPull) -> Self {
+        Self {
+            pull: Some(pull),
+            ..self
+        }
+    }
+
+    pub const fn with_trigger_edge(self, trigger_edge: TriggerEdge) -> Self {
+        Self {
+            trigger_edge,
+            ..self
+        }
+    }
+
+    pub const fn with_debounce(self, debounce: DebounceProfile) -> Self {
+        Self { debounce, ..self }
+    }
+
+    pub const fn with_double_trigger_window(self, double_trigger_window: Duration) -> Self {
+        Self {
+            double_trigger_window,
+            ..self
+        }
+    }
+
+    pub const fn with_stable_duration(self, stable_duration: Duration) -> Self {
+        Self {
+            stable_duration,
+            ..self
+        }
+    }
+
+    pub const fn with_gate(self, gate: Duration) -> Self {
+        Self { gate, ..self }
+    }
+
+    pub const fn with_gate_follows_sensor(self) -> Self {
+        Self {
+            gate_follows_sensor: true,
+            ..self
+        }
+    }
+
+    pub const fn with_max_velocity_gate(self, max_velocity_gate: Duration) -> Self {
+        Self {
+            max_velocity_gate: Some(max_velocity_gate),
+            ..self
+        }
+    }
+
+    pub const fn with_min_gate(self, min_gate: Duration) -> Self {
+        Self { min_gate, ..self }
+    }
+
+    pub const fn with_velocity_range(self, min: u8, max: u8) -> Self {
+        Self {
+            velocity_range: (min, max),
+            ..self
+        }
+    }
+
+    pub const fn with_fixed_velocity(self, velocity: u8) -> Self {
+        Self {
+            fixed_velocity: Some(velocity),
+            ..self
+        }
+    }
+
+    pub const fn with_layer(self, note: DrumNote, velocity_scale: u8) -> Self {
+        Self {
+            layer: Some(NoteLayer {
+                note,
+                velocity_scale,
+            }),
+            ..self
+        }
+    }
+
+    pub const fn with_repeat(self, interval: Duration, velocity_scale: u8) -> Self {
+        Self {
+            repeat: Some(NoteRepeat {
+                interval,
+                velocity_scale,
+            }),
+            ..self
+        }
+    }
+
+    pub const fn with_scan_priority(self, scan_priority: u8) -> Self {
+        Self {
+            scan_priority,
+            ..self
+        }
+    }
+
+    pub const fn with_trigger_mode(self, trigger_mode: TriggerMode) -> Self {
+        Self {
+            trigger_mode,
+            ..self
+        }
+    }
+
+    pub const fn with_control(self, control: u8) -> Self {
+        Self {
+            control: Some(PadControl::ControlChange(control)),
+            ..self
+        }
+    }
+
+    pub const fn with_parameter_control(self, parameter: ParameterNumber) -> Self {
+        Self {
+            control: Some(PadControl::Parameter(parameter)),
+            ..self
+        }
+    }
+}
+
+/// See [`PadConfig::layer`]. A single one, as the Note Ons of both notes just fit one MIDI packet.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct NoteLayer {
+    pub note: DrumNote,
+    /// Velocity of the layer, in percent of the hit's sensed velocity, before the velocity
+    /// processing of the layer's note (its own gain, humanizing and the pad's velocity range or
+    /// fixed velocity) applies to it like to any hit. E.g. 60 to keep a layered splash under the
+    /// crash.
+    pub velocity_scale: u8,
+}
+
+/// See [`PadConfig::repeat`].
+///
+/// The repeats come on top of the pad's [`debounce`](PadConfig::debounce), which only ever
+/// applies to the hits: the first repeat waits for the hit's debounce interval to be over if the
+/// `interval` is shorter, and a release ends the repeats right away, leaving the next hit subject
+/// to the debounce as usual. The repeats don't count towards the hit rate guard either. Each
+/// repeat is a hit of the pad of its own for the rest, with the pad's gate, trigger mode and
+/// layer, and goes through the velocity processing.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct NoteRepeat {
+    /// Time between two Note Ons, from the hit's. Zero disables the repeats. Keep it well over
+    /// the pad's [`stable_duration`](PadConfig::stable_duration), or the release is never seen
+    /// stable in between.
+    pub interval: Duration,
+    /// Velocity of each repeat, in percent of the previous one's (sensed, starting from the
+    /// hit's), to fade the roll out: e.g. 90 drops to about a third after 10 repeats. 100 keeps it
+    /// even. Lost on pads with a [`fixed_velocity`](PadConfig::fixed_velocity).
+    pub velocity_scale: u8,
+}
+
+/// What a [control pad](PadConfig::control) sends.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum PadControl {
+    /// This Control Change (0..=127), 127 when pressed and 0 when released.
+    ControlChange(u8),
+    /// This parameter, set to its highest value (`0x3FFF`) when pressed and 0 when released, for
+    /// samplers exposing their finer settings as NRPNs. See
+    /// [`parameter_change_messages`](crate::midi::parameter_change_messages) for the messages.
+    Parameter(ParameterNumber),
+}
+
+/// Address of a parameter set through the data entry Control Changes, a 14-bit number
+/// (0..=0x3FFF) whose meaning is up to the receiver for an NRPN, and set by the MIDI spec for an
+/// RPN (e.g. 0 for the pitch bend sensitivity).
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ParameterNumber {
+    /// Non-registered parameter number, selected by CC 99 (MSB) and 98 (LSB).
+    Nrpn(u16),
+    /// Registered parameter number, selected by CC 101 (MSB) and 100 (LSB).
+    Rpn(u16),
+}
+
+/// Assignment of MIDI note numbers to the drums, which keep their meaning (what's hit) whatever
+/// number they're sent as.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum NoteMap {
+    /// The General MIDI (and GM2) percussion map, the numbers of [`DrumNote`].
+    GeneralMidi,
+    /// General MIDI with the toms spread over the whole GM tom range (high tom 50, low-mid tom 47,
+    /// low floor tom 41), for samplers mapping only those.
+    GeneralMidiWideToms,
+    /// Per-note numbers (0..=127), indexed by [`DrumNote::index`].
+    Custom([u8; DrumNote::COUNT]),
+}
+
+impl NoteMap {
+    pub fn number_of(&self, note: DrumNote) -> u8 {
+        match (self, note) {
+            (Self::Custom(numbers), _) => numbers[note.index()],
+            (Self::GeneralMidiWideToms, DrumNote::HighTom) => 50,
+            (Self::GeneralMidiWideToms, DrumNote::LowTom) => 47,
+            (Self::GeneralMidiWideToms, DrumNote::FloorTom) => 41,
+            _ => note as u8,
+        }
+    }
+
+    /// Encode as the preset byte (0 General MIDI, 1 wide toms, 2 custom) followed by the number of
+    /// each note in [`DrumNote::ALL`] order.
+    pub fn encode(&self) -> [u8; 1 + DrumNote::COUNT] {
+        let mut value = [0; 1 + DrumNote::COUNT];
+        value[0] = match self {
+            Self::GeneralMidi => 0,
+            Self::GeneralMidiWideToms => 1,
+            Self::Custom(_) => 2,
+        };
+        for (v, note) in value[1..].iter_mut().zip(DrumNote::ALL) {
+            *v = self.number_of(note);
+        }
+        value
+    }
+
+    /// Decode what's encoded by [`encode`](Self::encode), or `None` if it's malformed. The note
+    /// numbers only matter for a custom map.
+    pub fn decode(value: [u8; 1 + DrumNote::COUNT]) -> Option<Self> {
+        let (&preset, numbers) = value.split_first()?;
+        match preset {
+            0 => Some(Self::GeneralMidi),
+            1 => Some(Self::GeneralMidiWideToms),
+            2 if numbers.iter().all(|&number| number <= 0x7F) => {
+                Some(Self::Custom(numbers.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Number of points of a [`VelocityCurve::Table`].
+pub const VELOCITY_CURVE_POINTS: usize = 16;
+
+/// See [`Config::velocity_curve`].
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum VelocityCurve {
+    /// The velocity as sensed.
+    Linear,
+    /// Soft hits come out louder, for a light touch: `127 - (127 - v)² / 127`.
+    Soft,
+    /// Soft hits come out quieter, leaving more room for the dynamics of the hard ones:
+    /// `v² / 127`.
+    Hard,
+    /// A custom curve: the velocities (1..=127, non-decreasing) played for sensed velocities
+    /// evenly spread from 0 to 127 (point `i` at `i * 127 / 15`), and linear in between.
+    Table([u8; VELOCITY_CURVE_POINTS]),
+}
+
+impl VelocityCurve {
+    /// The velocity played for the sensed `velocity`, within `1..=127`.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        let v = u32::from(velocity.min(127));
+        let out = match self {
+            Self::Linear => v,
+            Self::Soft => 127 - (127 - v) * (127 - v) / 127,
+            Self::Hard => v * v / 127,
+            Self::Table(points) => {
+                // Position along the points, in 1/127th of a point.
+                let position = v * (VELOCITY_CURVE_POINTS as u32 - 1);
+                let i = (position / 127) as usize;
+                let fraction = position % 127;
+                let low = u32::from(points[i]);
+                let high = u32::from(points[(i + 1).min(VELOCITY_CURVE_POINTS - 1)]);
+                low + (high - low) * fraction / 127
+            }
+        };
+        out.clamp(1, 127) as u8
+    }
+
+    /// Encode as the preset byte (0 linear, 1 soft, 2 hard, 3 table) followed by the curve at
+    /// each of the [`VELOCITY_CURVE_POINTS`] points of a table. Also the value of the
+    /// `velocity_curve` control characteristic.
+    pub fn encode(&self) -> [u8; 1 + VELOCITY_CURVE_POINTS] {
+        let mut value = [0; 1 + VELOCITY_CURVE_POINTS];
+        value[0] = match self {
+            Self::Linear => 0,
+            Self::Soft => 1,
+            Self::Hard => 2,
+            Self::Table(_) => 3,
+        };
+        for (i, point) in value[1..].iter_mut().enumerate() {
+            *point = match self {
+                Self::Table(points) => points[i],
+                _ => self.apply((i * 127 / (VELOCITY_CURVE_POINTS - 1)) as u8),
+            };
+        }
+        value
+    }
+
+    /// Decode what's encoded by [`encode`](Self::encode), or `None` if it's malformed, including a
+    /// table with velocities out of `1..=127` or going down. The points only matter for a table.
+    pub fn decode(value: [u8; 1 + VELOCITY_CURVE_POINTS]) -> Option<Self> {
+        let (&preset, points) = value.split_first()?;
+        match preset {
+            0 => Some(Self::Linear),
+            1 => Some(Self::Soft),
+            2 => Some(Self::Hard),
+            3 if points.iter().all(|point| (1..=127).contains(point)) && points.is_sorted() => {
+                Some(Self::Table(points.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// See [`Config::initial_midi_event`]. Hosts differ in what they make of a read value: most
+/// ignore it, but some play it like any received MIDI, or show it in their MIDI monitor.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum InitialMidiEvent {
+    /// No MIDI at all, as the BLE MIDI spec asks for. Nothing for a host to act on, though a few
+    /// strict ones reject a value without a packet header.
+    Empty,
+    /// System Reset. A host playing it may reset its whole synth, e.g. unloading a drum kit.
+    Reset,
+    /// All Notes Off on the global MIDI channel. Harmless to play, but shows up as a stray CC 123.
+    AllNotesOff,
+}
+
+impl InitialMidiEvent {
+    /// Encode as a byte: 0 for `Empty`, 1 for `Reset`, 2 for `AllNotesOff`.
+    pub fn encode(self) -> u8 {
+        match self {
+            Self::Empty => 0,
+            Self::Reset => 1,
+            Self::AllNotesOff => 2,
+        }
+    }
+
+    /// Decode what's encoded by [`encode`](Self::encode), or `None` if it's unknown.
+    pub fn decode(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Empty),
+            1 => Some(Self::Reset),
+            2 => Some(Self::AllNotesOff),
+            _ => None,
+        }
+    }
+}
+
+/// How a new hit treats a previous hit of the same note that's still sounding (within its gate).
+/// Only matters for pads with a non-zero gate.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum TriggerMode {
+    /// Hits overlap, each hit's Note Off is sent after its own gate. Suits samplers that let
+    /// repeated hits ring out over each other, which most multi-voice drum samplers do.
+    Poly,
+    /// Strictly one note at a time. A new hit first sends the Note Off of the previous one.
+    /// Helps samplers that track a single voice per note (many GM synths and simpler
+    /// SoundFont/SFZ players), which can stack or hang voices when a second Note On arrives
+    /// before the first Note Off.
+    Mono,
+}
+
+/// See [`Config::voice_stealing`].
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum VoiceStealing {
+    /// The note played first, i.e. the one that has rung the longest and is the most likely to
+    /// have faded out already. The default.
+    Oldest,
+    /// The note played at the lowest velocity, the oldest of them on a tie, for the accents to
+    /// ring through a busy passage. Can cut a soft note just played short, e.g. a ghost note.
+    Quietest,
+}
+
+impl VoiceStealing {
+    /// Encode as a byte: 0 for `Oldest`, 1 for `Quietest`.
+    pub fn encode(self) -> u8 {
+        match self {
+            Self::Oldest => 0,
+            Self::Quietest => 1,
+        }
+    }
+
+    /// Decode what's encoded by [`encode`](Self::encode), or `None` if it's unknown.
+    pub fn decode(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Oldest),
+            1 => Some(Self::Quietest),
+            _ => None,
+        }
+    }
+}
+
+/// Which level the pad's sensor is at when hit.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum SensorPolarity {
+    /// Idle high while the sensors are on, and low when hit. All the normal pads being low means
+    /// the sensors are off.
+    Normal,
+    /// Idle low and high when hit, with the pin pulled down. Such pads look the same whether the
+    /// sensors are on or off, so they don't take part in detecting it. At least one pad must be
+    /// [`Normal`](Self::Normal) for the sensors to ever be detected as on.
+    Inverted,
+}
+
+/// Which edge of the sensor's pulse a pad's hits are triggered at, i.e. when they're timestamped
+/// and sent.
+///
+/// Only for note pads other than the hi-hat pedal: the [control pads](PadConfig::control) and the
+/// pedal follow the sensor's level either way.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum TriggerEdge {
+    /// When the sensor reaches its hit level (low for [`Normal`](SensorPolarity::Normal) pads),
+    /// once stable for the [`stable_duration`](PadConfig::stable_duration): the start of the
+    /// pulse. The default, matching the piezo front-ends, whose pulse starts with the strike.
+    Hit,
+    /// When the sensor is back at its idle level, once stable: the end of the pulse, for sensors
+    /// whose pulse ends at the moment that matters (e.g. a switch let go by the stroke). Each hit
+    /// is then sent the length of its pulse later than with [`Hit`](Self::Hit), and:
+    /// - the [`debounce`](PadConfig::debounce) intervals count from the release, and a pulse
+    ///   shorter than the retrigger hold is rejected, rather than one released within it;
+    /// - the velocity is read at the release, past the peak an analog source would catch at the
+    ///   start of the pulse;
+    /// - there's no [double trigger](PadConfig::double_trigger_window) merging, as the following
+    ///   pulses are hits of their own, nor [gate following](PadConfig::gate_follows_sensor), as
+    ///   the sensor is already released.
+    Release,
+}
+
+/// Internal pull resistor of a pad's pin, see [`PadConfig::pull`].
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum SensorPull {
+    /// For sensors driving the pin both ways, e.g. a comparator or Schmitt trigger output, which
+    /// need no pull. The pin floats while the sensors are off, which is fine for
+    /// [`Normal`](SensorPolarity::Normal) pads, as those only look for hits once the pin has gone
+    /// high.
+    None,
+    /// For [`Normal`](SensorPolarity::Normal) pads whose sensor only pulls the pin low when hit,
+    /// e.g. an open-collector output or a switch to ground. The pin then idles high even with the
+    /// sensors off, so such a pad can't tell the sensors being off: keep at least one normal pad
+    /// driven by its sensor for that to be detected.
+    Up,
+    /// For [`Inverted`](SensorPolarity::Inverted) pads whose sensor only pulls the pin high when
+    /// hit, e.g. a switch to the supply. A normal pad pulled down reads as hit while its sensor
+    /// isn't driving the pin.
+    Down,
+}
+
+/// How a pad rejects the retriggers (e.g. from the pad ringing) following a hit.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum DebounceProfile {
+    /// Ignore the pad for a fixed time after each hit.
+    Standard,
+    /// For press/buzz rolls, where strokes come faster than the [`Standard`](Self::Standard)
+    /// window allows. Hits are accepted after a much shorter interval, but those coming earlier
+    /// than the standard window must keep the sensor triggered for a while to tell a real stroke
+    /// apart from ringing, which only produces short blips. The sensors being digital, how long
+    /// they stay triggered is all there is to gate on: there's no velocity threshold, so a ring
+    /// loud enough to hold the sensor that long plays as a stroke.
+    Roll,
+    /// For a kick played with a double pedal, two beaters on one trigger (or two triggers wired to
+    /// one pad), where the strokes of a fast double come closer than the
+    /// [`Standard`](Self::Standard) window or even the [`Roll`](Self::Roll) interval. Accepts hits
+    /// after the shortest interval, but those coming earlier than the standard window must keep
+    /// the sensor triggered longer than for a roll: a beater stays against the head for a few
+    /// milliseconds, much longer than the blips of the shell and head ringing after a hard kick.
+    DoubleKick,
+}
+
+impl DebounceProfile {
+    const STANDARD_INTERVAL: Duration = Duration::from_millis(30);
+    const ROLL_INTERVAL: Duration = Duration::from_millis(12);
+    const ROLL_RETRIGGER_HOLD: Duration = Duration::from_millis(2);
+    const DOUBLE_KICK_INTERVAL: Duration = Duration::from_millis(8);
+    const DOUBLE_KICK_RETRIGGER_HOLD: Duration = Duration::from_millis(4);
+
+    /// Time after a hit during which the pad is ignored.
+    pub const fn min_interval(self) -> Duration {
+        match self {
+            Self::Standard => Self::STANDARD_INTERVAL,
+            Self::Roll => Self::ROLL_INTERVAL,
+            Self::DoubleKick => Self::DOUBLE_KICK_INTERVAL,
+        }
+    }
+
+    /// How long the sensor must stay triggered for a hit coming `since_last_hit` after the
+    /// previous one to be accepted, or `None` if it's accepted right away.
+    pub fn retrigger_hold(self, since_last_hit: Duration) -> Option<Duration> {
+        match self {
+            Self::Standard => None,
+            Self::Roll => {
+                (since_last_hit < Self::STANDARD_INTERVAL).then_some(Self::ROLL_RETRIGGER_HOLD)
+            }
+            Self::DoubleKick => (since_last_hit < Self::STANDARD_INTERVAL)
+                .then_some(Self::DOUBLE_KICK_RETRIGGER_HOLD),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// A sensor pulse, starting `at` ms into the replay and keeping the sensor triggered for
+    /// `held` µs.
+    #[derive(Clone, Copy)]
+    struct Pulse {
+        at: u64,
+        held: u64,
+    }
+
+    const fn stroke(at: u64) -> Pulse {
+        Pulse { at, held: 3_000 }
+    }
+
+    const fn ring(at: u64) -> Pulse {
+        Pulse { at, held: 500 }
+    }
+
+    /// The onsets (in ms) of the `pulses` accepted as hits, debounced the way `watch_pin_for_hits`
+    /// does: pulses within the minimum interval of the last hit go unseen, later ones are accepted
+    /// if they're held for the retrigger hold.
+    fn replay(debounce: DebounceProfile, pulses: &[Pulse]) -> Vec<u64> {
+        let mut last_hit: Option<Duration> = None;
+        let mut hits = Vec::new();
+        for pulse in pulses {
+            let at = Duration::from_millis(pulse.at);
+            if let Some(last_hit) = last_hit {
+                if at < last_hit + debounce.min_interval() {
+                    continue;
+                }
+                if let Some(hold) = debounce.retrigger_hold(at - last_hit)
+                    && Duration::from_micros(pulse.held) < hold
+                {
+                    continue;
+                }
+            }
+            last_hit = Some(at);
+            hits.push(pulse.at);
+        }
+        hits
+    }
+
+    /// A press roll at 15 ms a stroke, each ringing at 5 ms and again at 13 ms, the latter past
+    /// the roll interval.
+    fn press_roll() -> Vec<Pulse> {
+        (0..8)
+            .map(|i| i * 15)
+            .flat_map(|at| [stroke(at), ring(at + 5), ring(at + 13)])
+            .collect()
+    }
+
+    #[test]
+    fn roll_keeps_the_strokes_of_a_press_roll() {
+        let strokes: Vec<u64> = (0..8).map(|i| i * 15).collect();
+        assert_eq!(replay(DebounceProfile::Roll, &press_roll()), strokes);
+    }
+
+    #[test]
+    fn standard_clips_a_press_roll() {
+        assert_eq!(
+            replay(DebounceProfile::Standard, &press_roll()),
+            [0, 30, 60, 90]
+        );
+    }
+
+    #[test]
+    fn roll_rejects_ringing_after_a_single_hit() {
+        let pulses = [stroke(0), ring(12), ring(16), ring(25), stroke(200)];
+        assert_eq!(replay(DebounceProfile::Roll, &pulses), [0, 200]);
+    }
+
+    #[test]
+    fn roll_accepts_short_pulses_past_the_standard_window() {
+        // Played softly, with nothing to tell them from ringing but the time since the last hit.
+        let pulses = [stroke(0), ring(30), ring(60)];
+        assert_eq!(replay(DebounceProfile::Roll, &pulses), [0, 30, 60]);
+    }
+
+    #[test]
+    fn double_kick_keeps_a_fast_double_and_rejects_ringing() {
+        let pulses = [
+            Pulse { at: 0, held: 5_000 },
+            ring(9),
+            Pulse {
+                at: 10,
+                held: 5_000,
+            },
+            Pulse {
+                at: 20,
+                held: 3_000,
+            },
+        ];
+        assert_eq!(replay(DebounceProfile::DoubleKick, &pulses), [0, 10]);
+    }
+
+    /// Doubles of both feet, the second 16 ms after the first, every 32nd note at 220 BPM
+    /// (68 ms), each beater pressed for 4 ms and the head ringing 10 ms after, past the shortest
+    /// interval.
+    fn double_pedal_doubles() -> Vec<Pulse> {
+        (0..4)
+            .map(|i| i * 68)
+            .flat_map(|at| [at, at + 16])
+            .flat_map(|at| [Pulse { at, held: 4_000 }, ring(at + 10)])
+            .collect()
+    }
+
+    #[test]
+    fn double_kick_keeps_the_doubles_of_a_double_pedal() {
+        let pulses = double_pedal_doubles();
+        assert_eq!(
+            replay(DebounceProfile::DoubleKick, &pulses),
+            [0, 16, 68, 84, 136, 152, 204, 220]
+        );
+    }
+
+    #[test]
+    fn standard_drops_the_second_kicks_of_a_double_pedal() {
+        let pulses = double_pedal_doubles();
+        assert_eq!(
+            replay(DebounceProfile::Standard, &pulses),
+            [0, 68, 136, 204]
+        );
+    }
+
+    #[test]
+    fn default_pads_debounce_standard() {
+        assert!(
+            Config::default()
+                .pads
+                .iter()
+                .all(|pad| pad.debounce == DebounceProfile::Standard)
+        );
+    }
+
+    #[test]
+    fn gate_follows_the_velocity_linearly() {
+        let pad = PadConfig {
+            gate: Duration::from_millis(50),
+            max_velocity_gate: Some(Duration::from_millis(550)),
+            ..PadConfig::new(DrumNote::CrashCymbal1)
+        };
+        let gates = [1, 32, 64, 96, 127].map(|velocity| pad.gate_for(velocity).as_millis());
+        assert_eq!(gates, [50, 173, 300, 426, 550]);
+        // Out of range velocities count as the nearest valid one.
+        assert_eq!(pad.gate_for(0), pad.gate_for(1));
+        assert_eq!(pad.gate_for(200), pad.gate_for(127));
+    }
+
+    #[test]
+    fn gate_can_shorten_with_the_velocity() {
+        let pad = PadConfig {
+            gate: Duration::from_millis(200),
+            max_velocity_gate: Some(Duration::from_millis(100)),
+            ..PadConfig::new(DrumNote::CrashCymbal1)
+        };
+        assert_eq!(pad.gate_for(1), Duration::from_millis(200));
+        assert_eq!(pad.gate_for(64), Duration::from_millis(150));
+        assert_eq!(pad.gate_for(127), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn gate_without_a_max_velocity_gate_is_fixed() {
+        let pad = PadConfig {
+            gate: Duration::from_millis(80),
+            ..PadConfig::new(DrumNote::CrashCymbal1)
+        };
+        assert!((1..=127).all(|velocity| pad.gate_for(velocity) == Duration::from_millis(80)));
+    }
+
+    #[test]
+    fn preset_curves_keep_the_ends_and_bend_the_middle() {
+        let curves = [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+        ];
+        let middles = curves.map(|curve| curve.apply(64));
+        assert_eq!(middles, [64, 96, 32]);
+        assert!(curves.iter().all(|curve| curve.apply(127) == 127));
+        // Up to the lowest velocity a Note On can have.
+        assert!(curves.iter().all(|curve| curve.apply(0) == 1));
+        assert_eq!(VelocityCurve::Hard.apply(1), 1);
+    }
+
+    #[test]
+    fn table_curve_interpolates_between_its_points() {
+        let curve = VelocityCurve::Table(core::array::from_fn(|i| 1 + 8 * i as u8));
+        assert_eq!(curve.apply(0), 1);
+        assert_eq!(curve.apply(127), 121);
+        // Between the points 7 (at 59.3, 57) and 8 (at 67.7, 65).
+        assert_eq!(curve.apply(63), 60);
+        let velocities: std::vec::Vec<_> = (0..=127).map(|v| curve.apply(v)).collect();
+        assert!(velocities.is_sorted());
+    }
+
+    #[test]
+    fn velocity_curves_round_trip_through_their_encoding() {
+        let table = VelocityCurve::Table(core::array::from_fn(|i| 1 + 8 * i as u8));
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+            table,
+        ] {
+            assert!(VelocityCurve::decode(curve.encode()) == Some(curve));
+        }
+        // A preset encodes its points, for a client to start a table from.
+        assert_eq!(VelocityCurve::Linear.encode()[1..][15], 127);
+    }
+
+    #[test]
+    fn malformed_velocity_curves_are_refused() {
+        let mut going_down = VelocityCurve::Linear.encode();
+        going_down[0] = 3;
+        going_down[9] = 1;
+        assert!(VelocityCurve::decode(going_down).is_none());
+        let mut silent = VelocityCurve::Linear.encode();
+        silent[0] = 3;
+        silent[1] = 0;
+        assert!(VelocityCurve::decode(silent).is_none());
+        let mut past_127 = VelocityCurve::Linear.encode();
+        past_127[0] = 3;
+        past_127[16] = 128;
+        assert!(VelocityCurve::decode(past_127).is_none());
+        let mut unknown = VelocityCurve::Linear.encode();
+        unknown[0] = 4;
+        assert!(VelocityCurve::decode(unknown).is_none());
+    }
+}
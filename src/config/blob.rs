@@ -0,0 +1,527 @@
+//! Versioned binary encoding of the [`Config`].
+//!
+//! | Bytes         | Content                                        |
+//! |---------------|------------------------------------------------|
+//! | 0..4          | Magic `b"EDMC"`                                |
+//! | 4             | [`CONFIG_VERSION`]                             |
+//! | 5..7          | Payload length (`u16` little-endian)           |
+//! | 7..7+len      | Payload                                        |
+//! | 7+len..11+len | FNV-1a hash of the payload (`u32` little-endian) |
+//!
+//! The payload is the config fields in declaration order, see [`encode_payload`]. A blob of
+//! another config version is rejected as a whole rather than migrated. It's [`PAYLOAD_LEN`] long:
+//! the per-pad durations are kept to 2 bytes each (milliseconds, or microseconds for the
+//! `stable_duration`) so that all the pads fit the blob in [`MAX_BLOB_LEN`].
+//!
+//! The same blob is what's stored in flash and what companion apps back up and restore over GATT
+//! (the `config_export` and `config_import` characteristics), so a backup only restores on
+//! firmware of the same [`CONFIG_VERSION`], which the `capabilities` characteristic tells. The
+//! version is bumped with any change to the payload layout.
+
+use embassy_time::Duration;
+
+use super::{
+    CONFIG_VERSION, Config, DebounceProfile, InitialMidiEvent, NoteLayer, NoteMap, NoteRepeat,
+    PAD_COUNT, PadConfig, PadControl, ParameterNumber, ProgramSelect, SensorPolarity, SensorPull,
+    TIMESTAMP_OFFSET_RANGE, TX_POWER_RANGE, TriggerEdge, TriggerMode, VELOCITY_CURVE_POINTS,
+    VelocityCurve, VoiceStealing,
+};
+use crate::tasks::gpio::DrumNote;
+
+const MAGIC: [u8; 4] = *b"EDMC";
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + 2;
+const CHECKSUM_LEN: usize = 4;
+
+/// Maximum length of an encoded config. Also the most a GATT characteristic value can hold, for
+/// the `config_export` one.
+pub const MAX_BLOB_LEN: usize = 512;
+
+/// Length of a pad in the payload.
+const PAD_LEN: usize = 30;
+/// Length of the payload: the pads, then the rest of the config.
+const PAYLOAD_LEN: usize = PAD_COUNT * PAD_LEN + 45 + 3 * DrumNote::COUNT + VELOCITY_CURVE_POINTS;
+
+/// The blob didn't fit in [`MAX_BLOB_LEN`]. Never returned as long as [`PAYLOAD_LEN`] matches
+/// [`encode_payload`], as [`encode`] checks the length fits at compile time.
+#[derive(Debug, defmt::Format)]
+pub struct BlobOverflow;
+
+/// Encode the config into `buf`, and return the length of the blob.
+pub fn encode(config: &Config, buf: &mut [u8; MAX_BLOB_LEN]) -> Result<usize, BlobOverflow> {
+    const { assert!(HEADER_LEN + PAYLOAD_LEN + CHECKSUM_LEN <= MAX_BLOB_LEN) };
+
+    let (header, rest) = buf.split_at_mut(HEADER_LEN);
+    let (payload_buf, _) = rest.split_at_mut(MAX_BLOB_LEN - HEADER_LEN - CHECKSUM_LEN);
+    let mut writer = Writer {
+        buf: payload_buf,
+        len: 0,
+    };
+    encode_payload(config, &mut writer)?;
+    let payload_len = writer.len;
+
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()] = CONFIG_VERSION;
+    header[MAGIC.len() + 1..].copy_from_slice(&(payload_len as u16).to_le_bytes());
+
+    let (payload, rest) = rest.split_at_mut(payload_len);
+    rest[..CHECKSUM_LEN].copy_from_slice(&fnv1a(payload).to_le_bytes());
+
+    Ok(HEADER_LEN + payload_len + CHECKSUM_LEN)
+}
+
+/// The config version and the whole length of the blob starting with `header`, as told by its
+/// first [`HEADER_LEN`] bytes, or `None` if those aren't the header of a config blob.
+pub fn header(header: &[u8]) -> Option<(u8, usize)> {
+    let header = header.first_chunk::<HEADER_LEN>()?;
+    if header[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let payload_len = u16::from_le_bytes([header[MAGIC.len() + 1], header[MAGIC.len() + 2]]);
+    Some((
+        header[MAGIC.len()],
+        HEADER_LEN + payload_len as usize + CHECKSUM_LEN,
+    ))
+}
+
+/// Decode a blob made by [`encode`] (possibly followed by garbage), or `None` if it's not a valid
+/// config of the current version.
+pub fn decode(blob: &[u8]) -> Option<Config> {
+    let (header, rest) = blob.split_at_checked(HEADER_LEN)?;
+    if header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != CONFIG_VERSION {
+        return None;
+    }
+    let payload_len = u16::from_le_bytes([header[MAGIC.len() + 1], header[MAGIC.len() + 2]]);
+
+    let (payload, rest) = rest.split_at_checked(payload_len as usize)?;
+    let checksum = rest.first_chunk::<CHECKSUM_LEN>()?;
+    if u32::from_le_bytes(*checksum) != fnv1a(payload) {
+        return None;
+    }
+
+    let mut reader = Reader(payload);
+    let config = decode_payload(&mut reader)?;
+    reader.0.is_empty().then_some(config)
+}
+
+fn encode_payload(config: &Config, w: &mut Writer<'_>) -> Result<(), BlobOverflow> {
+    let Config {
+        pads,
+        program_select,
+        humanize_velocity,
+        midi_channel,
+        note_channels,
+        velocity_gains,
+        active_sensing_interval,
+        tx_power,
+        midi_thru,
+        initial_midi_event,
+        mpe_channels,
+        hi_hat_splash_window,
+        hi_hat_splash_hysteresis,
+        note_map,
+        timestamp_offset,
+        warmup_hits,
+        velocity_curve,
+        control_channel,
+        pre_connection_window,
+        disconnect_on_sensors_off,
+        duplicate_packet_window,
+        max_polyphony,
+        voice_stealing,
+        advertised_id,
+        stuck_pad_timeout,
+    } = config;
+
+    for pad in pads {
+        w.u8(pad.note as u8)?;
+        w.u8(match pad.polarity {
+            SensorPolarity::Normal => 0,
+            SensorPolarity::Inverted => 1,
+        })?;
+        w.u8(match pad.pull {
+            None => 0xFF,
+            Some(SensorPull::None) => 0,
+            Some(SensorPull::Up) => 1,
+            Some(SensorPull::Down) => 2,
+        })?;
+        w.u8(match pad.trigger_edge {
+            TriggerEdge::Hit => 0,
+            TriggerEdge::Release => 1,
+        })?;
+        w.u8(match pad.debounce {
+            DebounceProfile::Standard => 0,
+            DebounceProfile::Roll => 1,
+            DebounceProfile::DoubleKick => 2,
+        })?;
+        w.millis(pad.double_trigger_window)?;
+        w.short_micros(pad.stable_duration)?;
+        w.millis(pad.gate)?;
+        w.u8(pad.gate_follows_sensor as u8)?;
+        w.u8(pad.max_velocity_gate.is_some() as u8)?;
+        w.millis(pad.max_velocity_gate.unwrap_or(Duration::from_ticks(0)))?;
+        w.millis(pad.min_gate)?;
+        w.u8(match pad.trigger_mode {
+            TriggerMode::Poly => 0,
+            TriggerMode::Mono => 1,
+        })?;
+        let (kind, number) = match pad.control {
+            None => (0xFF, 0),
+            Some(PadControl::ControlChange(control)) => (0, u16::from(control)),
+            Some(PadControl::Parameter(ParameterNumber::Nrpn(number))) => (1, number),
+            Some(PadControl::Parameter(ParameterNumber::Rpn(number))) => (2, number),
+        };
+        w.u8(kind)?;
+        w.bytes(&number.to_le_bytes())?;
+        w.bytes(&[pad.velocity_range.0, pad.velocity_range.1])?;
+        w.u8(pad.fixed_velocity.unwrap_or(0))?;
+        match pad.layer {
+            Some(layer) => w.bytes(&[layer.note as u8, layer.velocity_scale])?,
+            None => w.bytes(&[0xFF, 0])?,
+        }
+        // A zero interval for no repeats, so a shorter one than a millisecond is kept at one.
+        let repeat = pad
+            .repeat
+            .map(|r| (r.interval.max(Duration::from_millis(1)), r.velocity_scale));
+        let (interval, velocity_scale) = repeat.unwrap_or((Duration::from_ticks(0), 0));
+        w.millis(interval)?;
+        w.u8(velocity_scale)?;
+        w.u8(pad.scan_priority)?;
+    }
+    w.bytes(&ProgramSelect::encode(*program_select))?;
+    w.u8(*humanize_velocity)?;
+    w.u8(*midi_channel)?;
+    for channel in note_channels {
+        w.u8(channel.unwrap_or(0xFF))?;
+    }
+    w.bytes(velocity_gains)?;
+    w.duration(*active_sensing_interval)?;
+    w.u8(*tx_power as u8)?;
+    w.u8(*midi_thru as u8)?;
+    w.u8(initial_midi_event.encode())?;
+    w.bytes(&encode_mpe_channels(*mpe_channels))?;
+    w.duration(*hi_hat_splash_window)?;
+    w.duration(*hi_hat_splash_hysteresis)?;
+    w.bytes(&note_map.encode())?;
+    w.bytes(&timestamp_offset.to_le_bytes())?;
+    w.u8(*warmup_hits)?;
+    w.bytes(&velocity_curve.encode())?;
+    w.u8(control_channel.unwrap_or(0xFF))?;
+    w.duration(*pre_connection_window)?;
+    w.u8(*disconnect_on_sensors_off as u8)?;
+    w.duration(*duplicate_packet_window)?;
+    w.u8(*max_polyphony)?;
+    w.u8(voice_stealing.encode())?;
+    w.bytes(&advertised_id.to_le_bytes())?;
+    w.duration(*stuck_pad_timeout)?;
+    Ok(())
+}
+
+fn decode_payload(r: &mut Reader<'_>) -> Option<Config> {
+    let mut pads = [PadConfig::new(DrumNote::BassDrum); PAD_COUNT];
+    for pad in &mut pads {
+        *pad = PadConfig {
+            note: DrumNote::try_from(r.u8()?).ok()?,
+            polarity: match r.u8()? {
+                0 => SensorPolarity::Normal,
+                1 => SensorPolarity::Inverted,
+                _ => return None,
+            },
+            pull: match r.u8()? {
+                0xFF => None,
+                0 => Some(SensorPull::None),
+                1 => Some(SensorPull::Up),
+                2 => Some(SensorPull::Down),
+                _ => return None,
+            },
+            trigger_edge: match r.u8()? {
+                0 => TriggerEdge::Hit,
+                1 => TriggerEdge::Release,
+                _ => return None,
+            },
+            debounce: match r.u8()? {
+                0 => DebounceProfile::Standard,
+                1 => DebounceProfile::Roll,
+                2 => DebounceProfile::DoubleKick,
+                _ => return None,
+            },
+            double_trigger_window: r.millis()?,
+            stable_duration: r.short_micros()?,
+            gate: r.millis()?,
+            gate_follows_sensor: match r.u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+            max_velocity_gate: match (r.u8()?, r.millis()?) {
+                (0, _) => None,
+                (1, gate) => Some(gate),
+                _ => return None,
+            },
+            min_gate: r.millis()?,
+            trigger_mode: match r.u8()? {
+                0 => TriggerMode::Poly,
+                1 => TriggerMode::Mono,
+                _ => return None,
+            },
+            control: match (r.u8()?, u16::from_le_bytes(r.array()?)) {
+                (0xFF, _) => None,
+                (0, control @ 0..=0x7F) => Some(PadControl::ControlChange(control as u8)),
+                (1, number @ 0..=0x3FFF) => {
+                    Some(PadControl::Parameter(ParameterNumber::Nrpn(number)))
+                }
+                (2, number @ 0..=0x3FFF) => {
+                    Some(PadControl::Parameter(ParameterNumber::Rpn(number)))
+                }
+                _ => return None,
+            },
+            velocity_range: decode_velocity_range(r.array()?)?,
+            fixed_velocity: match r.u8()? {
+                0 => None,
+                velocity @ 1..=0x7F => Some(velocity),
+                _ => return None,
+            },
+            layer: match r.array()? {
+                [0xFF, _] => None,
+                [note, velocity_scale] => Some(NoteLayer {
+                    note: DrumNote::try_from(note).ok()?,
+                    velocity_scale,
+                }),
+            },
+            repeat: match (r.millis()?, r.u8()?) {
+                (interval, _) if interval == Duration::from_ticks(0) => None,
+                (interval, velocity_scale) => Some(NoteRepeat {
+                    interval,
+                    velocity_scale,
+                }),
+            },
+            scan_priority: r.u8()?,
+        };
+    }
+    let program_select = ProgramSelect::decode(r.array()?)?;
+    let humanize_velocity = r.u8()?;
+    let midi_channel = r.channel()?;
+    let mut note_channels = [None; DrumNote::COUNT];
+    for channel in &mut note_channels {
+        *channel = match r.u8()? {
+            0xFF => None,
+            channel @ 0..=15 => Some(channel),
+            _ => return None,
+        };
+    }
+
+    let velocity_gains = r.array()?;
+    let active_sensing_interval = r.duration()?;
+    let tx_power = r.u8()? as i8;
+    if !TX_POWER_RANGE.contains(&tx_power) {
+        return None;
+    }
+    let midi_thru = match r.u8()? {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    let initial_midi_event = InitialMidiEvent::decode(r.u8()?)?;
+    let mpe_channels = decode_mpe_channels(r.array()?)?;
+    let hi_hat_splash_window = r.duration()?;
+    let hi_hat_splash_hysteresis = r.duration()?;
+    let note_map = NoteMap::decode(r.array()?)?;
+    let timestamp_offset = i16::from_le_bytes(r.array()?);
+    if !TIMESTAMP_OFFSET_RANGE.contains(&timestamp_offset) {
+        return None;
+    }
+    let warmup_hits = r.u8()?;
+    let velocity_curve = VelocityCurve::decode(r.array()?)?;
+    let control_channel = match r.u8()? {
+        0xFF => None,
+        channel @ 0..=15 => Some(channel),
+        _ => return None,
+    };
+    let pre_connection_window = r.duration()?;
+    let disconnect_on_sensors_off = match r.u8()? {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    let duplicate_packet_window = r.duration()?;
+    let max_polyphony = r.u8()?;
+    let voice_stealing = VoiceStealing::decode(r.u8()?)?;
+    let advertised_id = u16::from_le_bytes(r.array()?);
+    let stuck_pad_timeout = r.duration()?;
+
+    Some(Config {
+        pads,
+        program_select,
+        humanize_velocity,
+        midi_channel,
+        note_channels,
+        velocity_gains,
+        active_sensing_interval,
+        tx_power,
+        midi_thru,
+        initial_midi_event,
+        mpe_channels,
+        hi_hat_splash_window,
+        hi_hat_splash_hysteresis,
+        note_map,
+        timestamp_offset,
+        warmup_hits,
+        velocity_curve,
+        control_channel,
+        pre_connection_window,
+        disconnect_on_sensors_off,
+        duplicate_packet_window,
+        max_polyphony,
+        voice_stealing,
+        advertised_id,
+        stuck_pad_timeout,
+    })
+}
+
+/// Decode a [`PadConfig::velocity_range`] encoded as `[min, max]`, or `None` if it's malformed.
+pub fn decode_velocity_range([min, max]: [u8; 2]) -> Option<(u8, u8)> {
+    (1 <= min && min <= max && max <= 127).then_some((min, max))
+}
+
+/// Encode [`Config::mpe_channels`] as `[first, last]`, both 0xFF if `None`. Also the value of the
+/// `mpe_channels` control characteristic.
+pub fn encode_mpe_channels(mpe_channels: Option<(u8, u8)>) -> [u8; 2] {
+    mpe_channels.map_or([0xFF; 2], |(first, last)| [first, last])
+}
+
+/// Decode what's encoded by [`encode_mpe_channels`], or `None` if it's malformed.
+pub fn decode_mpe_channels(value: [u8; 2]) -> Option<Option<(u8, u8)>> {
+    match value {
+        [0xFF, 0xFF] => Some(None),
+        [first, last] if first <= last && last <= 15 => Some(Some((first, last))),
+        _ => None,
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), BlobOverflow> {
+        let end = self.len + bytes.len();
+        let dest = self.buf.get_mut(self.len..end).ok_or(BlobOverflow)?;
+        dest.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), BlobOverflow> {
+        self.bytes(&[value])
+    }
+
+    /// In microseconds, saturating at `u32::MAX` (a bit over an hour).
+    fn duration(&mut self, value: Duration) -> Result<(), BlobOverflow> {
+        let micros = u32::try_from(value.as_micros()).unwrap_or(u32::MAX);
+        self.bytes(&micros.to_le_bytes())
+    }
+
+    /// In milliseconds, rounded down and saturating at `u16::MAX` (a bit over a minute).
+    fn millis(&mut self, value: Duration) -> Result<(), BlobOverflow> {
+        let millis = u16::try_from(value.as_millis()).unwrap_or(u16::MAX);
+        self.bytes(&millis.to_le_bytes())
+    }
+
+    /// In microseconds, saturating at `u16::MAX` (about 65 ms).
+    fn short_micros(&mut self, value: Duration) -> Result<(), BlobOverflow> {
+        let micros = u16::try_from(value.as_micros()).unwrap_or(u16::MAX);
+        self.bytes(&micros.to_le_bytes())
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (array, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*array)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.array::<1>().map(|[value]| value)
+    }
+
+    fn duration(&mut self) -> Option<Duration> {
+        self.array()
+            .map(|bytes| Duration::from_micros(u32::from_le_bytes(bytes).into()))
+    }
+
+    fn millis(&mut self) -> Option<Duration> {
+        self.array()
+            .map(|bytes| Duration::from_millis(u16::from_le_bytes(bytes).into()))
+    }
+
+    fn short_micros(&mut self) -> Option<Duration> {
+        self.array()
+            .map(|bytes| Duration::from_micros(u16::from_le_bytes(bytes).into()))
+    }
+
+    /// A MIDI channel, 0..=15.
+    fn channel(&mut self) -> Option<u8> {
+        self.u8().filter(|&channel| channel <= 15)
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The blob of `config`, checking it's of the length it's sized for.
+    fn encoded(config: &Config) -> [u8; MAX_BLOB_LEN] {
+        let mut blob = [0; MAX_BLOB_LEN];
+        let len = encode(config, &mut blob).unwrap();
+        assert_eq!(len, HEADER_LEN + PAYLOAD_LEN + CHECKSUM_LEN);
+        assert_eq!(header(&blob), Some((CONFIG_VERSION, len)));
+        blob
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let blob = encoded(&Config::default());
+        let decoded = decode(&blob).expect("blob not decoded");
+        assert_eq!(encoded(&decoded), blob);
+    }
+
+    #[test]
+    fn changed_pads_round_trip() {
+        let mut config = Config::default();
+        config.pads[0] = config.pads[0]
+            .with_repeat(Duration::from_millis(60), 90)
+            .with_velocity_range(20, 110)
+            .with_layer(DrumNote::CrashCymbal1, 50)
+            .with_stable_duration(Duration::from_micros(400));
+        config.pads[1] = config.pads[1]
+            .with_debounce(DebounceProfile::DoubleKick)
+            .with_gate_follows_sensor();
+        let blob = encoded(&config);
+        assert_ne!(blob, encoded(&Config::default()));
+        let decoded = decode(&blob).expect("blob not decoded");
+        assert_eq!(encoded(&decoded), blob);
+        assert!(decoded.pads[0].repeat == config.pads[0].repeat);
+    }
+
+    #[test]
+    fn corrupted_blob_is_refused() {
+        let blob = encoded(&Config::default());
+        let mut corrupted = blob;
+        corrupted[HEADER_LEN + 3] ^= 0x01;
+        assert!(decode(&corrupted).is_none());
+        let mut other_version = blob;
+        other_version[MAGIC.len()] = CONFIG_VERSION.wrapping_add(1);
+        assert!(decode(&other_version).is_none());
+        let len = HEADER_LEN + PAYLOAD_LEN + CHECKSUM_LEN;
+        assert!(decode(&blob[..len - 1]).is_none());
+    }
+}
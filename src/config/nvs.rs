@@ -0,0 +1,123 @@
+//! Persistent storage of the [`Config`] in flash, along with up to [`MAX_PRESETS`] named presets
+//! of it.
+
+use embedded_storage::{ReadStorage, Storage};
+#[cfg(not(test))]
+use esp_storage::{FlashStorage, FlashStorageError};
+
+use super::{
+    Config,
+    blob::{self, BlobOverflow},
+};
+
+/// Flash offset the config is stored at: the `nvs` partition of the default ESP-IDF partition
+/// table. The partition only holds our own config blob, not the ESP-IDF NVS format.
+const CONFIG_OFFSET: u32 = 0x9000;
+/// What a write erases at once.
+const SECTOR_SIZE: u32 = 0x1000;
+/// Flash offset of the first preset. Each preset is in a sector of its own following the config's,
+/// so storing one doesn't wear the others.
+const PRESETS_OFFSET: u32 = CONFIG_OFFSET + SECTOR_SIZE;
+
+/// Number of presets that can be stored. The `nvs` partition is 24 KiB, i.e. 6 sectors: the
+/// config's, one per preset, and one spare.
+pub const MAX_PRESETS: u8 = 4;
+/// Longest preset name, in bytes. Shorter names are zero-padded.
+pub const PRESET_NAME_LEN: usize = 16;
+
+pub type PresetName = [u8; PRESET_NAME_LEN];
+
+/// A preset as stored: its name, then its config blob.
+const PRESET_LEN: usize = PRESET_NAME_LEN + blob::MAX_BLOB_LEN;
+
+/// Why a config or preset wasn't stored.
+#[cfg(not(test))]
+#[derive(Debug)]
+pub enum SaveError {
+    Flash(FlashStorageError),
+    Encode(BlobOverflow),
+}
+
+#[cfg(not(test))]
+impl From<FlashStorageError> for SaveError {
+    fn from(e: FlashStorageError) -> Self {
+        Self::Flash(e)
+    }
+}
+
+#[cfg(not(test))]
+impl From<BlobOverflow> for SaveError {
+    fn from(e: BlobOverflow) -> Self {
+        Self::Encode(e)
+    }
+}
+
+#[cfg(not(test))]
+pub struct Nvs {
+    flash: FlashStorage<'static>,
+}
+
+#[cfg(not(test))]
+impl Nvs {
+    pub fn new(flash: FlashStorage<'static>) -> Self {
+        Self { flash }
+    }
+
+    /// The stored config, or `None` if there's none or it's not valid for this firmware (e.g.
+    /// stored by a firmware with another config version).
+    pub fn load(&mut self) -> Option<Config> {
+        let mut buf = [0; blob::MAX_BLOB_LEN];
+        self.flash.read(CONFIG_OFFSET, &mut buf).ok()?;
+        blob::decode(&buf)
+    }
+
+    /// Store `config`, unless it's already what's stored, and return whether it was written.
+    ///
+    /// Each write erases the whole 4 KiB flash sector the config is in, and a sector only lasts
+    /// that many erases (about 100k), so an unchanged config is left alone. Once this returns, the
+    /// config is in flash and survives a reset of any kind.
+    pub fn save(&mut self, config: &Config) -> Result<bool, SaveError> {
+        let mut buf = [0; blob::MAX_BLOB_LEN];
+        let len = blob::encode(config, &mut buf)?;
+        let mut stored = [0; blob::MAX_BLOB_LEN];
+        if self.flash.read(CONFIG_OFFSET, &mut stored).is_ok() && stored[..len] == buf[..len] {
+            return Ok(false);
+        }
+        // The whole buffer, to keep the write aligned.
+        self.flash.write(CONFIG_OFFSET, &buf)?;
+        Ok(true)
+    }
+
+    /// The preset stored in `slot` (below [`MAX_PRESETS`]) and its name, or `None` if there's
+    /// none or it's not valid for this firmware. Like the stored config, the presets don't survive
+    /// a firmware update with another config version.
+    pub fn load_preset(&mut self, slot: u8) -> Option<(PresetName, Config)> {
+        let mut buf = [0; PRESET_LEN];
+        self.flash.read(preset_offset(slot), &mut buf).ok()?;
+        let (name, blob) = buf.split_first_chunk::<PRESET_NAME_LEN>()?;
+        Some((*name, blob::decode(blob)?))
+    }
+
+    /// Store `config` as the preset in `slot` (below [`MAX_PRESETS`]) named `name`, replacing the
+    /// one there if any. Only written when asked to, so unlike [`save`](Self::save) it doesn't
+    /// check for an unchanged preset.
+    pub fn save_preset(
+        &mut self,
+        slot: u8,
+        name: &PresetName,
+        config: &Config,
+    ) -> Result<(), SaveError> {
+        let mut blob = [0; blob::MAX_BLOB_LEN];
+        blob::encode(config, &mut blob)?;
+        let mut buf = [0; PRESET_LEN];
+        buf[..PRESET_NAME_LEN].copy_from_slice(name);
+        buf[PRESET_NAME_LEN..].copy_from_slice(&blob);
+        self.flash.write(preset_offset(slot), &buf)?;
+        Ok(())
+    }
+}
+
+fn preset_offset(slot: u8) -> u32 {
+    assert!(slot < MAX_PRESETS, "no preset slot {}", slot);
+    PRESETS_OFFSET + u32::from(slot) * SECTOR_SIZE
+}
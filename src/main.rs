@@ -1,57 +1,353 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// The tests run on the host (`cargo test --target x86_64-unknown-linux-gnu`), without the
+// hardware setup below nor the chip support crates, leaving some of the firmware unused there.
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 #![deny(
     clippy::mem_forget,
     reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
     holding buffers for the duration of a data transfer."
 )]
 
-use defmt::{timestamp, unwrap};
-use embassy_executor::Spawner;
+use core::{future::pending, ptr};
+#[cfg(feature = "ws2812")]
+use defmt::unwrap;
+use defmt::{Debug2Format, Display2Format, error, info, timestamp, warn};
+use embassy_executor::{SpawnError, Spawner};
 use embassy_sync::{channel::Channel, signal::Signal};
 use embassy_time::Instant;
+#[cfg(feature = "sensor-power")]
+use embassy_time::{Duration, Timer};
+#[cfg(not(test))]
 use esp_alloc as _;
+#[cfg(all(feature = "debug-console", not(test)))]
+use esp_hal::usb_serial_jtag::UsbSerialJtag;
+#[cfg(not(test))]
 use esp_hal::{
     clock::CpuClock,
+    delay::Delay,
     gpio::{Level, Output, OutputConfig, Pin},
     interrupt::software::SoftwareInterruptControl,
-    peripherals,
+    rng::Rng,
+    rtc_cntl::{SocResetReason, reset_reason},
+    system::Cpu,
     timer::timg::TimerGroup,
 };
+#[cfg(all(feature = "ws2812", not(test)))]
+use esp_hal::{rmt::Rmt, time::Rate};
+#[cfg(not(test))]
 use esp_println as _;
+#[cfg(not(test))]
 use esp_radio::ble::controller::BleConnector;
+#[cfg(not(test))]
+use esp_storage::FlashStorage;
 use static_cell::StaticCell;
 use trouble_host::prelude::*;
 
-use crate::tasks::gpio::{DrumNote, HitEventsChannel, SensorsStatusSignal};
-use crate::tasks::{ble, gpio};
+use crate::config::SharedConfig;
+#[cfg(not(test))]
+use crate::config::nvs::Nvs;
+use crate::tasks::ble::{PriorityMessagesChannel, control::ForceDisconnectSignal};
+#[cfg(not(test))]
+use crate::tasks::button;
+#[cfg(all(feature = "adc-velocity", not(test)))]
+use crate::tasks::gpio::velocity::AdcVelocity;
+#[cfg(not(feature = "adc-velocity"))]
+use crate::tasks::gpio::velocity::FixedVelocity;
+use crate::tasks::gpio::{
+    HitEventsBackpressure, HitEventsChannel, ReloadPadsSignal, SensorsStatusSignal,
+    calibration::Calibration,
+};
+#[cfg(all(feature = "ws2812", not(test)))]
+use crate::tasks::led::ws2812::Ws2812;
+use crate::tasks::led::{LedPattern, LedPatternChannel, LedPatternSender};
+use crate::tasks::nvs::PresetRequestsChannel;
+use crate::tasks::{ble, gpio, led, nvs, telemetry};
 
+mod board;
+mod config;
+mod midi;
 mod tasks;
+#[cfg(test)]
+mod test_support;
 mod trouble_midi;
 
-type BluetoothController = ExternalController<BleConnector<'static>, 20>;
+// Sizing of the BLE stack. The defaults fit the single client served at a time. The packet pool
+// itself (number of packets and their MTU) is sized by the `default-packet-pool*` features of
+// `trouble-host` in Cargo.toml: more packets let more notifications be in flight, bigger ones
+// allow a bigger negotiated MTU, both at the cost of RAM.
+
+/// Simultaneous connections the host keeps state for. Each costs a few hundred bytes of RAM, and
+/// only one is served at a time, so more just let a second central connect without being served.
+const BLE_CONNECTIONS: usize = 1;
+/// L2CAP connection-oriented channels. BLE MIDI runs over GATT on the fixed ATT channel, which
+/// doesn't take one.
+const BLE_L2CAP_CHANNELS: usize = 0;
+/// HCI commands the controller can have queued. Fewer save a little RAM but make the host wait
+/// on the controller more.
+const BLE_CONTROLLER_SLOTS: usize = 20;
+
+#[cfg(not(test))]
+type BluetoothController = ExternalController<BleConnector<'static>, BLE_CONTROLLER_SLOTS>;
+
+/// Size of the heap. The firmware's own state all lives in statics, so the heap is only for
+/// `esp-radio`, which allocates the BLE controller's buffers and queues from it.
+///
+/// 72 KiB is what the esp-hal BLE examples run with, and leaves most of the ESP32-C3's ~320 KiB
+/// of data RAM to the stacks and statics. Running out shows as the out-of-memory blinking of the
+/// panic handler. The peak usage logged by the telemetry (see
+/// [`peak_heap_used`](tasks::telemetry::peak_heap_used)) tells how far it can be reduced.
+const HEAP_SIZE: usize = 72 * 1024;
+
+/// With the `sensor-power` feature, how long the sensor front-end gets to power up before the pads
+/// are first read, ahead of the settling time of the sensors switching on.
+#[cfg(feature = "sensor-power")]
+const SENSOR_POWER_STABILIZE_TIME: Duration = Duration::from_millis(50);
+
+#[cfg(not(any(feature = "esp32c3", feature = "esp32c6")))]
+compile_error!("no chip to build for, enable one of the `esp32c3` or `esp32c6` features");
+#[cfg(all(feature = "esp32c3", feature = "esp32c6"))]
+compile_error!("`esp32c3` and `esp32c6` are exclusive, build with `--no-default-features`");
+#[cfg(all(
+    feature = "esp32c3",
+    feature = "sensor-power",
+    feature = "debug-console"
+))]
+compile_error!("`sensor-power` drives GPIO18, which the USB Serial/JTAG of `debug-console` uses");
+#[cfg(all(
+    feature = "esp32c3",
+    feature = "preset-button",
+    feature = "debug-console"
+))]
+compile_error!("`preset-button` reads GPIO19, which the USB Serial/JTAG of `debug-console` uses");
+#[cfg(all(feature = "esp32c3", feature = "adc-velocity"))]
+compile_error!("`adc-velocity` takes ADC1 pins, which the ESP32-C3 has none of left");
+#[cfg(all(feature = "raw-hits", not(debug_assertions)))]
+compile_error!("`raw-hits` is for bench testing, and never for a release build to play with");
+
+/// Left in RTC fast memory by the panic handler, which keeps it across a reset, for the next boot
+/// to tell it followed a panic. Not initialized at boot, so it holds garbage after power-on, other
+/// than [`PANIC_MARKER`] but for an unlucky power-on.
+#[cfg(not(test))]
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut LAST_BOOT_MARKER: u32 = 0;
+const PANIC_MARKER: u32 = 0x5041_4E43; // "PANC"
 
+/// Times the status LED flashes at boot when the previous boot ended in a panic.
+const PANIC_RECOVERY_FLASHES: u8 = 5;
+
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_: &core::panic::PanicInfo) -> ! {
-    // Turn on the on-board LED when panicking to signal something went wrong.
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // A single volatile word write: no lock, allocation or peripheral involved that the panic
+    // could have left in a broken state.
+    // SAFETY: only read at boot before any task runs, and we're the last and only code running.
+    unsafe { ptr::write_volatile(&raw mut LAST_BOOT_MARKER, PANIC_MARKER) };
+
+    // Turn on the on-board LED when panicking to signal something went wrong, or blink it fast if
+    // we ran out of memory. A WS2812 on the pin (the `ws2812` feature) just keeps showing its last
+    // color.
 
     // SAFETY: we're panicking so we should be safe as the last and only one to use the pin.
-    let led_pin = unsafe { peripherals::GPIO8::steal() };
-    let _ = Output::new(led_pin, Level::Low, OutputConfig::default());
+    let led_pin = unsafe { board::steal_status_led() };
+    let mut led = Output::new(led_pin, Level::Low, OutputConfig::default());
 
+    if is_out_of_memory(info) {
+        error!(
+            "Out of memory ({} of {} bytes used): {}",
+            esp_alloc::HEAP.used(),
+            HEAP_SIZE,
+            Display2Format(info)
+        );
+        let delay = Delay::new();
+        loop {
+            led.toggle();
+            delay.delay_millis(50);
+        }
+    }
+
+    error!("{}", Display2Format(info));
     loop {}
 }
 
+/// Log why the chip was reset, and whether the previous boot ended in a panic, clearing the marker
+/// for the next boot. Must run before any task is spawned.
+#[cfg(not(test))]
+fn check_last_boot() -> bool {
+    let reason = reset_reason(Cpu::ProCpu);
+    info!("Reset reason: {}", Debug2Format(&reason));
+
+    // SAFETY: the tasks haven't been spawned yet, and the panic handler can't run concurrently.
+    let marker = unsafe { ptr::replace(&raw mut LAST_BOOT_MARKER, 0) };
+    // Left over from before a power cycle otherwise.
+    let panicked = marker == PANIC_MARKER && !matches!(reason, Some(SocResetReason::ChipPowerOn));
+    if panicked {
+        warn!("The previous boot ended in a panic");
+    }
+    panicked
+}
+
+/// Whether the panic comes from the default alloc error handler, which panics with "memory
+/// allocation of {size} bytes failed" (`#[alloc_error_handler]` being unstable).
+fn is_out_of_memory(info: &core::panic::PanicInfo) -> bool {
+    struct StartsWith {
+        expected: &'static [u8],
+        matches: bool,
+    }
+
+    impl core::fmt::Write for StartsWith {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let len = s.len().min(self.expected.len());
+            self.matches &= s.as_bytes()[..len] == self.expected[..len];
+            self.expected = &self.expected[len..];
+            Ok(())
+        }
+    }
+
+    let mut message = StartsWith {
+        expected: b"memory allocation of ",
+        matches: true,
+    };
+    let _ = core::fmt::write(&mut message, format_args!("{}", info.message()));
+    message.matches && message.expected.is_empty()
+}
+
+/// A startup step that failed. Reported by flashing the status LED [`InitError::led_code`] times
+/// in a row, repeated forever.
+#[cfg(not(test))]
+#[derive(defmt::Format)]
+enum InitError {
+    Radio(esp_radio::InitializationError),
+    Spawn {
+        task: &'static str,
+        error: SpawnError,
+    },
+}
+
+#[cfg(not(test))]
+impl InitError {
+    /// Number of LED flashes per repetition.
+    const fn led_code(&self) -> u8 {
+        match self {
+            Self::Radio(_) => 2,
+            Self::Spawn { .. } => 3,
+        }
+    }
+}
+
+/// Log `error` and flash its LED code forever instead of panicking, so a failed startup can be
+/// told apart in the field without a debugger.
+#[cfg(not(test))]
+async fn halt_with_init_error(status_led: LedPatternSender<'_>, error: InitError) -> ! {
+    error!("Initialization failed: {}", error);
+    status_led
+        .send(LedPattern::ErrorCode(error.led_code()))
+        .await;
+    loop {
+        pending::<()>().await;
+    }
+}
+
+/// Evaluate a fallible init step, or halt with its [`InitError`] on the status LED.
+#[cfg(not(test))]
+macro_rules! try_init {
+    ($status_led:expr, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => halt_with_init_error($status_led, error).await,
+        }
+    };
+}
+
+/// Spawn `$task`, mapping a failure to [`InitError::Spawn`] named after the task.
+#[cfg(not(test))]
+macro_rules! try_spawn {
+    ($status_led:expr, $spawner:expr, $($task:ident)::+($($arg:expr),* $(,)?)) => {
+        try_init!(
+            $status_led,
+            $spawner.spawn($($task)::+($($arg),*)).map_err(|error| InitError::Spawn {
+                task: stringify!($($task)::+),
+                error,
+            })
+        )
+    };
+}
+
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+#[cfg(not(test))]
 esp_bootloader_esp_idf::esp_app_desc!();
 
 timestamp!("[{=u64:us}]", Instant::now().as_micros());
 
+#[cfg(not(test))]
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
+    let pins = board::board_pins!(peripherals);
+
+    // The heap, the time driver and the status LED come first, as the LED error codes below need
+    // them. Failing to spawn the LED task itself is left to the panic handler.
+    esp_alloc::heap_allocator!(size: HEAP_SIZE);
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let sw_int = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+    esp_rtos::start(timg0.timer0, sw_int.software_interrupt0);
+
+    static LED_PATTERN_CHANNEL: StaticCell<LedPatternChannel> = StaticCell::new();
+    let led_pattern_channel = LED_PATTERN_CHANNEL.init(Channel::new());
+    let status_led = led_pattern_channel.sender();
+    #[cfg(not(feature = "ws2812"))]
+    let led = Output::new(pins.status_led, Level::High, OutputConfig::default());
+    #[cfg(feature = "ws2812")]
+    let led = {
+        let rmt = unwrap!(Rmt::new(peripherals.RMT, Rate::from_mhz(80)));
+        unwrap!(Ws2812::new(rmt.channel0, pins.status_led))
+    };
+    spawner.must_spawn(led::status_led_task(led, led_pattern_channel.receiver()));
+
+    if check_last_boot() {
+        status_led
+            .send(LedPattern::Flash(PANIC_RECOVERY_FLASHES))
+            .await;
+    }
+
+    let mut storage = Nvs::new(FlashStorage::new(peripherals.FLASH));
+    let stored_config = storage.load();
+    if stored_config.is_none() {
+        info!("No valid stored config. Using defaults.");
+    }
+
+    static CONFIG: StaticCell<SharedConfig> = StaticCell::new();
+    let config = CONFIG.init(SharedConfig::new(stored_config.unwrap_or_default()));
+
+    static RELOAD_PADS_SIGNAL: StaticCell<ReloadPadsSignal> = StaticCell::new();
+    let reload_pads_signal = RELOAD_PADS_SIGNAL.init(Signal::new());
+
+    static PRESET_REQUESTS_CHANNEL: StaticCell<PresetRequestsChannel> = StaticCell::new();
+    let preset_requests_channel = PRESET_REQUESTS_CHANNEL.init(Channel::new());
+
+    try_spawn!(
+        status_led,
+        spawner,
+        nvs::persist_config_task(
+            storage,
+            config,
+            preset_requests_channel.receiver(),
+            reload_pads_signal,
+            status_led,
+        )
+    );
+
+    // Powered before the pads are watched, which starts with the stuck pins check at boot. Held
+    // high for as long as the firmware runs, i.e. until a reset drops the pin back to its
+    // unpowered state. Sleeping would have to drive it low first.
+    #[cfg(feature = "sensor-power")]
+    let _sensor_power = {
+        let sensor_power = Output::new(pins.sensor_power, Level::High, OutputConfig::default());
+        Timer::after(SENSOR_POWER_STABILIZE_TIME).await;
+        sensor_power
+    };
 
     static SENSORS_STATUS_SIGNAL: StaticCell<SensorsStatusSignal> = StaticCell::new();
     let sensors_status_signal = SENSORS_STATUS_SIGNAL.init(Signal::new());
@@ -59,40 +355,106 @@ async fn main(spawner: Spawner) {
     static HIT_EVENTS_CHANNEL: StaticCell<HitEventsChannel> = StaticCell::new();
     let hit_events_channel = HIT_EVENTS_CHANNEL.init(Channel::new());
 
-    spawner.must_spawn(gpio::watch_gpios_task(
-        [
-            (peripherals.GPIO0.degrade(), DrumNote::HighTom),
-            (peripherals.GPIO1.degrade(), DrumNote::PedalHiHat),
-            (peripherals.GPIO3.degrade(), DrumNote::OpenHiHat),
-            (peripherals.GPIO4.degrade(), DrumNote::CrashCymbal1),
-            (peripherals.GPIO5.degrade(), DrumNote::CrashCymbal2),
-            (peripherals.GPIO6.degrade(), DrumNote::RideCymbal),
-            (peripherals.GPIO7.degrade(), DrumNote::FloorTom),
-            (peripherals.GPIO10.degrade(), DrumNote::LowTom),
-            (peripherals.GPIO20.degrade(), DrumNote::BassDrum),
-            (peripherals.GPIO21.degrade(), DrumNote::Snare),
-        ],
-        sensors_status_signal,
-        hit_events_channel,
-    ));
+    static HIT_EVENTS_BACKPRESSURE: StaticCell<HitEventsBackpressure> = StaticCell::new();
+    let hit_events_backpressure = HIT_EVENTS_BACKPRESSURE.init(HitEventsBackpressure::new());
 
-    esp_alloc::heap_allocator!(size: 72 * 1024);
-    let timg0 = TimerGroup::new(peripherals.TIMG0);
-    let sw_int = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
-    esp_rtos::start(timg0.timer0, sw_int.software_interrupt0);
+    static CALIBRATION: StaticCell<Calibration> = StaticCell::new();
+    let calibration = CALIBRATION.init(Calibration::new());
+
+    #[cfg(not(feature = "adc-velocity"))]
+    let velocity_source = FixedVelocity;
+    #[cfg(feature = "adc-velocity")]
+    let velocity_source = {
+        let (gpio4, gpio5) = pins.velocity_adc;
+        AdcVelocity::new(peripherals.ADC1, gpio4, gpio5)
+    };
+
+    try_spawn!(
+        status_led,
+        spawner,
+        gpio::watch_gpios_task(
+            pins.pads,
+            config,
+            sensors_status_signal,
+            hit_events_channel,
+            hit_events_backpressure,
+            reload_pads_signal,
+            calibration,
+            velocity_source,
+            status_led,
+        )
+    );
+
+    try_spawn!(
+        status_led,
+        spawner,
+        button::channel_button_task(pins.channel_button, config, status_led)
+    );
+
+    #[cfg(feature = "preset-button")]
+    try_spawn!(
+        status_led,
+        spawner,
+        button::preset_button_task(pins.preset_button, preset_requests_channel.sender())
+    );
+
+    try_spawn!(status_led, spawner, telemetry::telemetry_task());
 
     static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
-    let radio = RADIO.init(unwrap!(esp_radio::init()));
+    let radio = RADIO.init(try_init!(
+        status_led,
+        esp_radio::init().map_err(InitError::Radio)
+    ));
 
     let bluetooth = peripherals.BT;
     let connector = BleConnector::new(radio, bluetooth, Default::default());
     let controller = BluetoothController::new(connector);
 
+    static PRIORITY_MESSAGES_CHANNEL: StaticCell<PriorityMessagesChannel> = StaticCell::new();
+    let priority_messages_channel = PRIORITY_MESSAGES_CHANNEL.init(Channel::new());
+
+    try_spawn!(
+        status_led,
+        spawner,
+        button::panic_button_task(
+            pins.panic_button,
+            config,
+            priority_messages_channel.sender()
+        )
+    );
+
+    static FORCE_DISCONNECT_SIGNAL: StaticCell<ForceDisconnectSignal> = StaticCell::new();
+    let force_disconnect_signal = FORCE_DISCONNECT_SIGNAL.init(Signal::new());
+
+    #[cfg(feature = "debug-console")]
+    try_spawn!(
+        status_led,
+        spawner,
+        tasks::console::debug_console_task(
+            UsbSerialJtag::new(peripherals.USB_DEVICE).into_async(),
+            config,
+            force_disconnect_signal,
+            reload_pads_signal,
+            calibration,
+        )
+    );
+
     ble::peripheral_run(
         controller,
-        sensors_status_signal,
-        peripherals.GPIO8.degrade(),
-        hit_events_channel.receiver(),
+        Rng::new().random(),
+        ble::Shared {
+            status_signal: sensors_status_signal,
+            status_led,
+            hit_events: hit_events_channel.receiver(),
+            hit_events_backpressure,
+            priority_messages: priority_messages_channel.receiver(),
+            thru_messages: priority_messages_channel.sender(),
+            config,
+            force_disconnect: force_disconnect_signal,
+            reload_pads: reload_pads_signal,
+            calibration,
+            preset_requests: preset_requests_channel.sender(),
+        },
     )
     .await;
 }
@@ -0,0 +1,424 @@
+use heapless::Vec;
+use midi_types::{Channel, Control, MidiMessage};
+
+use crate::{
+    config::{Config, PadControl, ParameterNumber, ProgramSelect},
+    tasks::gpio::DrumNote,
+};
+
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+/// Note On for a hit of `note` on the `pad` with the given velocity, after applying the config.
+pub fn build_note_on(
+    pad: usize,
+    note: DrumNote,
+    velocity: u8,
+    config: &Config,
+    rng: &mut XorShift32,
+) -> MidiMessage {
+    let pad = &config.pads[pad];
+    let velocity = match pad.fixed_velocity {
+        // As sensed, see `raw-hits` in `tasks::gpio`.
+        _ if cfg!(feature = "raw-hits") => velocity,
+        Some(velocity) => velocity,
+        None => {
+            let velocity = scale_velocity(velocity, config.velocity_gains[note.index()]);
+            let velocity = config.velocity_curve.apply(velocity);
+            let velocity = humanize(velocity, config.humanize_velocity, rng);
+            let (min, max) = pad.velocity_range;
+            velocity.clamp(min, max)
+        }
+    };
+    MidiMessage::NoteOn(
+        config.channel_of(note),
+        config.note_number(note),
+        velocity.into(),
+    )
+}
+
+/// Note Off matching the Note On of `note` built with the same config.
+pub fn build_note_off(note: DrumNote, config: &Config) -> MidiMessage {
+    MidiMessage::NoteOff(config.channel_of(note), config.note_number(note), 0.into())
+}
+
+/// `msg` moved to `channel` if it's a note message, as is otherwise.
+pub fn with_channel(msg: MidiMessage, channel: Channel) -> MidiMessage {
+    match msg {
+        MidiMessage::NoteOn(_, note, velocity) => MidiMessage::NoteOn(channel, note, velocity),
+        MidiMessage::NoteOff(_, note, velocity) => MidiMessage::NoteOff(channel, note, velocity),
+        msg => msg,
+    }
+}
+
+/// Round-robin over the channels of [`Config::mpe_channels`].
+#[derive(Default)]
+pub struct ChannelRotation {
+    next: u8,
+}
+
+impl ChannelRotation {
+    /// The next channel of `first..=last` in turn that's not `in_use`, or just the next one in
+    /// turn if they all are.
+    pub fn assign(&mut self, (first, last): (u8, u8), in_use: impl Fn(Channel) -> bool) -> Channel {
+        let count = last - first + 1;
+        // The range may have changed since the last one.
+        let start = if (first..=last).contains(&self.next) {
+            self.next
+        } else {
+            first
+        };
+        let channel = (0..count)
+            .map(|i| first + (start - first + i) % count)
+            .find(|&channel| !in_use(Channel::new(channel)))
+            .unwrap_or(start);
+        self.next = if channel == last { first } else { channel + 1 };
+        Channel::new(channel)
+    }
+}
+
+/// Channels the notes can currently go out on: the global one, the per-note overrides and the MPE
+/// rotation, as a bit mask of channels 0..=15.
+pub fn note_channels_mask(config: &Config) -> u16 {
+    let mut mask = 1 << config.midi_channel;
+    for channel in config.note_channels.into_iter().flatten() {
+        mask |= 1 << channel;
+    }
+    if let Some((first, last)) = config.mpe_channels {
+        for channel in first..=last {
+            mask |= 1 << channel;
+        }
+    }
+    mask
+}
+
+/// All Sound Off (CC 120) then All Notes Off (CC 123) on `channel`, to silence hanging notes.
+pub fn panic_messages(channel: Channel) -> [MidiMessage; 2] {
+    [120, 123].map(|control| MidiMessage::ControlChange(channel, Control::new(control), 0.into()))
+}
+
+/// Most messages a control pad sends at once, those of a [`parameter_change_messages`].
+pub const MAX_CONTROL_MESSAGES: usize = 4;
+
+/// What a control pad `pressed` or released sends.
+pub fn build_control_messages(
+    control: PadControl,
+    pressed: bool,
+    config: &Config,
+) -> Vec<MidiMessage, MAX_CONTROL_MESSAGES> {
+    let channel = Channel::new(config.midi_channel);
+    match control {
+        PadControl::ControlChange(control) => {
+            let value = if pressed { 127 } else { 0 };
+            Vec::from_iter([MidiMessage::ControlChange(
+                channel,
+                Control::new(control),
+                value.into(),
+            )])
+        }
+        PadControl::Parameter(parameter) => {
+            let value = if pressed { 0x3FFF } else { 0 };
+            Vec::from_iter(parameter_change_messages(parameter, value, channel))
+        }
+    }
+}
+
+/// The Control Changes setting `parameter` to the 14-bit `value` (0..=0x3FFF) on `channel`:
+/// the parameter number MSB and LSB (CC 99 and 98 for an NRPN, 101 and 100 for an RPN), then
+/// the data entry MSB and LSB (CC 6 and 38). E.g. NRPN `0x0102` set to `0x3FFF` on channel 0 is
+/// `B0 63 02`, `B0 62 02`, `B0 06 7F`, `B0 26 7F`.
+///
+/// Meant to go out together, in as few packets as they fit (see `MidiEventPacket::pack`): a
+/// receiver applies the data entry to whichever parameter was selected last on the channel, so
+/// nothing else should be sent on it in between. The parameter stays selected afterwards, which
+/// is harmless as long as nothing else on the channel sends data entries.
+pub fn parameter_change_messages(
+    parameter: ParameterNumber,
+    value: u16,
+    channel: Channel,
+) -> [MidiMessage; 4] {
+    let (msb_control, lsb_control, number) = match parameter {
+        ParameterNumber::Nrpn(number) => (99, 98, number),
+        ParameterNumber::Rpn(number) => (101, 100, number),
+    };
+    let cc = |control: u8, value: u16| {
+        MidiMessage::ControlChange(
+            channel,
+            Control::new(control),
+            ((value & 0x7F) as u8).into(),
+        )
+    };
+    [
+        cc(msb_control, number >> 7),
+        cc(lsb_control, number),
+        cc(6, value >> 7),
+        cc(38, value),
+    ]
+}
+
+/// Bank select MSB and LSB (only if the bank is set) followed by the program change. E.g. bank
+/// `(0, 1)` and program 5 on channel 9 pack into one packet as `80 80 B9 00 00 80 20 01 80 C9 05`
+/// at timestamp 0, the LSB taking the running status of the MSB.
+pub fn program_select_messages(
+    program_select: ProgramSelect,
+    channel: Channel,
+) -> [Option<MidiMessage>; 3] {
+    let ProgramSelect { program, bank } = program_select;
+    let [bank_msb, bank_lsb] = bank.map_or([None, None], |(msb, lsb)| {
+        [
+            Some(MidiMessage::ControlChange(
+                channel,
+                Control::new(0),
+                msb.into(),
+            )),
+            Some(MidiMessage::ControlChange(
+                channel,
+                Control::new(32),
+                lsb.into(),
+            )),
+        ]
+    });
+    [
+        bank_msb,
+        bank_lsb,
+        Some(MidiMessage::ProgramChange(channel, program.into())),
+    ]
+}
+
+/// Scale `velocity` by `gain` percent, keeping the result within `1..=127`.
+pub fn scale_velocity(velocity: u8, gain: u8) -> u8 {
+    (velocity as u32 * gain as u32 / 100).clamp(1, 127) as u8
+}
+
+/// Randomly perturb `velocity` by up to `±amount` to avoid the machine-gun effect on repeated
+/// identical hits. The result always stays within `1..=127`.
+pub fn humanize(velocity: u8, amount: u8, rng: &mut XorShift32) -> u8 {
+    if amount == 0 {
+        return velocity;
+    }
+
+    let span = 2 * amount as u32 + 1;
+    let offset = (rng.next_u32() % span) as i16 - amount as i16;
+    (velocity as i16 + offset).clamp(1, 127) as u8
+}
+
+/// Cheap PRNG. Plenty for humanizing, not for anything that needs real randomness.
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        // An all-zero state would get stuck at zero.
+        Self(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::render_slice::MidiRenderSlice;
+
+    use super::*;
+    use crate::{
+        config::{UNITY_VELOCITY_GAIN, VelocityCurve},
+        trouble_midi::MidiEventPacket,
+    };
+
+    #[test]
+    fn humanize_stays_within_amount_and_velocity_range() {
+        let mut rng = XorShift32::new(1);
+        for amount in [1, 5, 20, 127, 255] {
+            for velocity in [1, 2, 64, 126, 127] {
+                for _ in 0..1000 {
+                    let humanized = humanize(velocity, amount, &mut rng);
+                    assert!((1..=127).contains(&humanized));
+                    assert!(humanized.abs_diff(velocity) <= amount);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn humanize_spreads_over_the_whole_amount() {
+        let mut rng = XorShift32::new(1);
+        let mut seen = [false; 7];
+        for _ in 0..1000 {
+            seen[(humanize(64, 3, &mut rng) - 61) as usize] = true;
+        }
+        assert_eq!(seen, [true; 7]);
+    }
+
+    #[test]
+    fn humanize_off_keeps_the_velocity() {
+        let mut rng = XorShift32::new(1);
+        assert!((1..=127).all(|velocity| humanize(velocity, 0, &mut rng) == velocity));
+    }
+
+    #[test]
+    fn humanize_is_off_by_default() {
+        assert_eq!(Config::default().humanize_velocity, 0);
+    }
+
+    #[test]
+    fn scale_velocity_by_percent() {
+        assert_eq!(scale_velocity(80, UNITY_VELOCITY_GAIN), 80);
+        assert_eq!(scale_velocity(80, 150), 120);
+        assert_eq!(scale_velocity(80, 50), 40);
+        assert_eq!(scale_velocity(1, 100), 1);
+    }
+
+    #[test]
+    fn scale_velocity_clamps_to_velocity_range() {
+        assert_eq!(scale_velocity(100, 200), 127);
+        assert_eq!(scale_velocity(127, 255), 127);
+        assert_eq!(scale_velocity(1, 50), 1);
+        assert_eq!(scale_velocity(64, 0), 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn build_note_on_scales_before_the_curve() {
+        let mut config = Config::default();
+        config.velocity_gains[DrumNote::Snare.index()] = 200;
+        config.velocity_curve = VelocityCurve::Hard;
+        let pad = config
+            .pads
+            .iter()
+            .position(|pad| pad.note == DrumNote::Snare)
+            .unwrap();
+        let mut rng = XorShift32::new(1);
+        // 40 doubled to 80, then 80² / 127.
+        assert_eq!(
+            build_note_on(pad, DrumNote::Snare, 40, &config, &mut rng),
+            MidiMessage::NoteOn(
+                config.channel_of(DrumNote::Snare),
+                config.note_number(DrumNote::Snare),
+                50.into()
+            )
+        );
+    }
+
+    /// The velocity of the Note On of a hit of the snare pad sensed at `velocity`.
+    fn snare_velocity(config: &Config, velocity: u8) -> u8 {
+        let pad = config
+            .pads
+            .iter()
+            .position(|pad| pad.note == DrumNote::Snare)
+            .unwrap();
+        match build_note_on(
+            pad,
+            DrumNote::Snare,
+            velocity,
+            config,
+            &mut XorShift32::new(1),
+        ) {
+            MidiMessage::NoteOn(_, _, velocity) => u8::from(velocity),
+            msg => panic!("{msg:?} built for a hit"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn velocity_range_clamps_at_its_bounds() {
+        let mut config = Config::default();
+        for pad in &mut config.pads {
+            pad.velocity_range = (90, 120);
+        }
+        assert_eq!(snare_velocity(&config, 1), 90);
+        assert_eq!(snare_velocity(&config, 89), 90);
+        assert_eq!(snare_velocity(&config, 90), 90);
+        assert_eq!(snare_velocity(&config, 100), 100);
+        assert_eq!(snare_velocity(&config, 120), 120);
+        assert_eq!(snare_velocity(&config, 121), 120);
+        assert_eq!(snare_velocity(&config, 127), 120);
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn velocity_range_clamps_after_the_gain_and_curve() {
+        let mut config = Config::default();
+        config.velocity_gains[DrumNote::Snare.index()] = 200;
+        config.velocity_curve = VelocityCurve::Hard;
+        for pad in &mut config.pads {
+            pad.velocity_range = (60, 120);
+        }
+        // 40 doubled to 80, through the curve to 50, then up to the floor.
+        assert_eq!(snare_velocity(&config, 40), 60);
+        // 127 through the curve, down to the ceiling.
+        assert_eq!(snare_velocity(&config, 100), 120);
+    }
+
+    #[test]
+    fn default_velocity_range_keeps_the_velocity() {
+        let config = Config::default();
+        assert!(config.pads.iter().all(|pad| pad.velocity_range == (1, 127)));
+        assert!((1..=127).all(|velocity| snare_velocity(&config, velocity) == velocity));
+    }
+
+    /// The bytes of `messages`, each with its status byte.
+    fn rendered(messages: impl IntoIterator<Item = MidiMessage>) -> std::vec::Vec<u8> {
+        let mut bytes = std::vec::Vec::new();
+        for message in messages {
+            let mut rendered = [0; 3];
+            let len = message.render_slice(&mut rendered);
+            bytes.extend_from_slice(&rendered[..len]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn nrpn_selects_the_parameter_then_sets_the_value() {
+        let messages =
+            parameter_change_messages(ParameterNumber::Nrpn(0x0102), 0x3FFF, Channel::C1);
+        assert_eq!(
+            rendered(messages),
+            [
+                0xB0, 0x63, 0x02, 0xB0, 0x62, 0x02, 0xB0, 0x06, 0x7F, 0xB0, 0x26, 0x7F
+            ]
+        );
+    }
+
+    #[test]
+    fn rpn_selects_through_its_own_controls() {
+        // The pitch bend sensitivity, to 2 semitones.
+        let messages = parameter_change_messages(ParameterNumber::Rpn(0), 2 << 7, Channel::C10);
+        assert_eq!(
+            rendered(messages),
+            [
+                0xB9, 0x65, 0x00, 0xB9, 0x64, 0x00, 0xB9, 0x06, 0x02, 0xB9, 0x26, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn bank_select_and_program_change_pack_into_one_packet() {
+        let program_select = ProgramSelect {
+            program: 5,
+            bank: Some((0, 1)),
+        };
+        let messages = program_select_messages(program_select, Channel::C10);
+        let packets = MidiEventPacket::pack::<1>(0, messages.into_iter().flatten());
+        assert_eq!(
+            packets[0].as_bytes(),
+            [
+                0x80, 0x80, 0xB9, 0x00, 0x00, 0x80, 0x20, 0x01, 0x80, 0xC9, 0x05
+            ]
+        );
+    }
+
+    #[test]
+    fn program_change_alone_without_a_bank() {
+        let program_select = ProgramSelect {
+            program: 5,
+            bank: None,
+        };
+        let messages = program_select_messages(program_select, Channel::C10);
+        assert_eq!(rendered(messages.into_iter().flatten()), [0xC9, 0x05]);
+    }
+}
@@ -1,2 +1,9 @@
 pub mod ble;
+#[cfg(not(test))]
+pub mod button;
+#[cfg(all(feature = "debug-console", not(test)))]
+pub mod console;
 pub mod gpio;
+pub mod led;
+pub mod nvs;
+pub mod telemetry;
@@ -1,33 +1,143 @@
-use defmt::{error, info, unwrap, warn};
+use bt_hci::{
+    cmd::le::{
+        LeClearAdvSets, LeReadNumberOfSupportedAdvSets, LeSetAdvSetRandomAddr, LeSetExtAdvData,
+        LeSetExtAdvParams, LeSetExtScanResponseData,
+    },
+    controller::ControllerCmdSync,
+};
+use core::{cell::Cell, future::pending};
+use defmt::{Debug2Format, debug, error, info, unwrap, warn};
 use embassy_futures::{
     join::join,
-    select::{Either, select},
+    select::{Either, Either4, select, select4},
+};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{self, Receiver, Sender},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_timeout};
+use heapless::Vec;
+use midi_types::{Channel, Control, MidiMessage};
+use trouble_host::{
+    att::{AttClient, AttReq, AttUns},
+    prelude::*,
 };
-use embassy_time::{Duration, with_timeout};
-use esp_hal::gpio::{AnyPin, Level, Output, OutputConfig};
-use midi_types::{Channel, MidiMessage, Value7};
-use trouble_host::prelude::*;
 
 use crate::{
-    BluetoothController,
-    tasks::gpio::{HitEventsReceiver, SensorsStatus, SensorsStatusSignal, blink},
-    trouble_midi::{MIDI_SERVICE_UUID, MidiService},
+    BLE_CONNECTIONS, BLE_L2CAP_CHANNELS,
+    config::{
+        Config, InitialMidiEvent, NoteMap, ProgramSelect, SharedConfig, TIMESTAMP_OFFSET_RANGE,
+        TX_POWER_RANGE, TriggerMode, VelocityCurve, VoiceStealing,
+        blob::{self, MAX_BLOB_LEN, decode_mpe_channels, encode_mpe_channels},
+    },
+    midi::{
+        ChannelRotation, MAX_CONTROL_MESSAGES, XorShift32, build_control_messages, build_note_off,
+        build_note_on, note_channels_mask, panic_messages, program_select_messages, scale_velocity,
+        with_channel,
+    },
+    tasks::ble::control::{
+        CONTROL_SERVICE_UUID, ConfigWrites, ControlCommand, ControlService, ForceDisconnectSignal,
+        LAST_HIT_INTERVAL, SERVICE_DATA_LEN, advertised_service_data, decode_channels,
+        decode_preset_request, decode_program_select, decode_velocity_ranges, encode_calibration,
+        encode_channels, encode_hit_log, encode_last_hit, encode_velocity_ranges,
+    },
+    tasks::gpio::{
+        DrumNote, HIT_EVENTS_DEPTH, HitEvent, HitEventsBackpressure, HitEventsReceiver, HitKind,
+        ReloadPadsSignal, SensorsStatus, SensorsStatusSignal, calibration::Calibration,
+    },
+    tasks::led::{LedPattern, LedPatternSender},
+    tasks::nvs::{PresetRequest, PresetRequestsSender},
+    tasks::telemetry::{COUNTERS, HIT_LOG},
+    trouble_midi::{
+        AsTimestamp, MIDI_SERVICE_UUID, MidiEventPacket, MidiService, RunningStatus, Shifted,
+    },
 };
 
+pub mod control;
+#[cfg(test)]
+mod mock_controller;
+
 const BLE_SERVICE_NAME: &str = "ESP MIDI";
 
+/// How long the connection gets to silence the notes and disconnect once the sensors switch off,
+/// with [`Config::disconnect_on_sensors_off`], before being dropped anyway.
+const SENSORS_OFF_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Real-time and panic messages (e.g. `TimingClock`, `Start`/`Stop`, All Notes Off) which must
+/// not wait behind buffered hits, and the messages echoed by MIDI thru.
+///
+/// Whenever both are pending, these are notified before the hit events. Each stream stays in
+/// order on its own, but there's no ordering between streams: a priority message sent after a
+/// hit event can be notified before it, and a hit whose notification is already in-flight will
+/// always complete first.
+pub type PriorityMessagesChannel = channel::Channel<NoopRawMutex, MidiMessage, 4>;
+pub type PriorityMessagesReceiver<'ch> = Receiver<'ch, NoopRawMutex, MidiMessage, 4>;
+pub type PriorityMessagesSender<'ch> = Sender<'ch, NoopRawMutex, MidiMessage, 4>;
+
 #[gatt_server]
 struct GattServer {
     midi_service: MidiService,
+    control_service: ControlService,
 }
 
-pub async fn peripheral_run(
-    controller: BluetoothController,
-    status_signal: &SensorsStatusSignal,
-    status_led: AnyPin<'_>,
-    hit_events: HitEventsReceiver<'_>,
-) {
-    let mut resources: HostResources<DefaultPacketPool, 1, 0> = HostResources::new();
+/// What the BLE side shares with the rest of the firmware.
+#[derive(Clone, Copy)]
+pub struct Shared<'a> {
+    pub status_signal: &'a SensorsStatusSignal,
+    pub status_led: LedPatternSender<'a>,
+    pub hit_events: HitEventsReceiver<'a>,
+    pub hit_events_backpressure: &'a HitEventsBackpressure,
+    pub priority_messages: PriorityMessagesReceiver<'a>,
+    /// Into `priority_messages`, for MIDI thru.
+    pub thru_messages: PriorityMessagesSender<'a>,
+    pub config: &'a SharedConfig,
+    pub force_disconnect: &'a ForceDisconnectSignal,
+    pub reload_pads: &'a ReloadPadsSignal,
+    pub calibration: &'a Calibration,
+    pub preset_requests: PresetRequestsSender<'a>,
+}
+
+/// A [`Controller`] taking the extended advertising commands too, which the advertising goes
+/// through for its TX power, see [`advertise_and_connect`].
+pub trait ExtAdvController:
+    Controller
+    + for<'t> ControllerCmdSync<LeSetExtAdvData<'t>>
+    + ControllerCmdSync<LeClearAdvSets>
+    + ControllerCmdSync<LeSetExtAdvParams>
+    + ControllerCmdSync<LeSetAdvSetRandomAddr>
+    + ControllerCmdSync<LeReadNumberOfSupportedAdvSets>
+    + for<'t> ControllerCmdSync<LeSetExtScanResponseData<'t>>
+{
+}
+
+impl<C> ExtAdvController for C where
+    C: Controller
+        + for<'t> ControllerCmdSync<LeSetExtAdvData<'t>>
+        + ControllerCmdSync<LeClearAdvSets>
+        + ControllerCmdSync<LeSetExtAdvParams>
+        + ControllerCmdSync<LeSetAdvSetRandomAddr>
+        + ControllerCmdSync<LeReadNumberOfSupportedAdvSets>
+        + for<'t> ControllerCmdSync<LeSetExtScanResponseData<'t>>
+{
+}
+
+/// Serve BLE MIDI over `controller` for as long as the firmware runs, with the randomness (e.g.
+/// of the humanization) seeded from `rng_seed`.
+pub async fn peripheral_run<C>(controller: C, rng_seed: u32, shared: Shared<'_>)
+where
+    C: ExtAdvController,
+    C::Error: defmt::Format,
+{
+    let Shared {
+        status_signal,
+        status_led,
+        config,
+        ..
+    } = shared;
+
+    let mut resources: HostResources<DefaultPacketPool, BLE_CONNECTIONS, BLE_L2CAP_CHANNELS> =
+        HostResources::new();
     let stack = trouble_host::new(controller, &mut resources);
     let Host {
         mut peripheral,
@@ -41,157 +151,1898 @@ pub async fn peripheral_run(
             appearance: &appearance::MEDIA_PLAYER,
         }
     )));
+    config.read(|c| set_config_values(&server, c));
 
-    let mut status_led = Output::new(status_led, Level::High, OutputConfig::default());
+    let mut rng = XorShift32::new(rng_seed);
 
     let wait_for_status = async |status: SensorsStatus| {
         while status_signal.wait().await != status {}
         info!("Sensors switched {}", status);
     };
 
+    let sensors_off = SensorsOffSignal::new();
+
     join(host_runner_task(runner), async {
         loop {
             wait_for_status(SensorsStatus::On).await;
+            sensors_off.reset();
 
             select(
                 midi_service_task(
                     BLE_SERVICE_NAME,
                     &mut peripheral,
                     &server,
-                    &mut status_led,
-                    hit_events,
+                    shared,
+                    &sensors_off,
+                    &mut rng,
                 ),
-                wait_for_status(SensorsStatus::Off),
+                async {
+                    wait_for_status(SensorsStatus::Off).await;
+                    if config.read(|c| c.disconnect_on_sensors_off) {
+                        // Ends the service task once disconnected, or right away if not connected.
+                        sensors_off.signal(());
+                        Timer::after(SENSORS_OFF_DISCONNECT_TIMEOUT).await;
+                    }
+                },
             )
             .await;
+            status_led.send(LedPattern::Off).await;
         }
     })
     .await;
 }
 
-async fn host_runner_task<'a>(mut runner: Runner<'a, BluetoothController, DefaultPacketPool>) -> ! {
+async fn host_runner_task<C>(mut runner: Runner<'_, C, DefaultPacketPool>) -> !
+where
+    C: Controller,
+    C::Error: defmt::Format,
+{
     loop {
         unwrap!(runner.run().await);
     }
 }
 
-async fn midi_service_task<'a>(
+/// Set the values of the config characteristics to the config `c`.
+fn set_config_values(server: &GattServer<'_>, c: &Config) {
+    let control = &server.control_service;
+    unwrap!(server.set(
+        &control.program_select,
+        &ProgramSelect::encode(c.program_select)
+    ));
+    unwrap!(server.set(&control.channels, &encode_channels(c)));
+    unwrap!(server.set(&control.velocity_ranges, &encode_velocity_ranges(c)));
+    unwrap!(server.set(&control.velocity_gains, &c.velocity_gains));
+    unwrap!(server.set(&control.note_map, &c.note_map.encode()));
+    unwrap!(server.set(&control.timestamp_offset, &c.timestamp_offset));
+    unwrap!(server.set(&control.velocity_curve, &c.velocity_curve.encode()));
+    unwrap!(server.set(&control.tx_power, &c.tx_power));
+    unwrap!(server.set(&control.midi_thru, &c.midi_thru));
+    unwrap!(server.set(&control.control_channel, &c.control_channel.unwrap_or(0xFF)));
+    unwrap!(server.set(&control.initial_midi_event, &c.initial_midi_event.encode()));
+    unwrap!(server.set(&control.mpe_channels, &encode_mpe_channels(c.mpe_channels)));
+    unwrap!(server.set(
+        &control.hi_hat_splash_window,
+        &u16::try_from(c.hi_hat_splash_window.as_millis()).unwrap_or(u16::MAX)
+    ));
+    unwrap!(server.set(
+        &control.hi_hat_splash_hysteresis,
+        &u16::try_from(c.hi_hat_splash_hysteresis.as_millis()).unwrap_or(u16::MAX)
+    ));
+    unwrap!(server.set(
+        &control.pre_connection_window,
+        &u16::try_from(c.pre_connection_window.as_millis()).unwrap_or(u16::MAX)
+    ));
+    unwrap!(server.set(
+        &control.duplicate_packet_window,
+        &u16::try_from(c.duplicate_packet_window.as_millis()).unwrap_or(u16::MAX)
+    ));
+    unwrap!(server.set(
+        &control.polyphony,
+        &[c.max_polyphony, c.voice_stealing.encode()]
+    ));
+    unwrap!(server.set(&control.advertised_id, &c.advertised_id));
+    unwrap!(server.set(
+        &control.stuck_pad_timeout,
+        &u16::try_from(c.stuck_pad_timeout.as_millis()).unwrap_or(u16::MAX)
+    ));
+}
+
+async fn midi_service_task<'a, C: ExtAdvController>(
     service_name: &str,
-    peripheral: &mut Peripheral<'a, BluetoothController, DefaultPacketPool>,
+    peripheral: &mut Peripheral<'a, C, DefaultPacketPool>,
     server: &GattServer<'a>,
-    status_led: &mut Output<'_>,
-    hit_events: HitEventsReceiver<'_>,
+    shared: Shared<'_>,
+    sensors_off: &SensorsOffSignal,
+    rng: &mut XorShift32,
 ) {
+    let Shared {
+        status_led,
+        config,
+        force_disconnect,
+        ..
+    } = shared;
+
     info!("Starting advertising and GATT service");
 
-    while let Ok(Either::First(res)) = with_timeout(
-        Duration::from_secs(60),
-        select(
-            advertise_and_connect(service_name, peripheral, server),
-            blink(status_led, Duration::from_millis(1000)),
-        ),
-    )
-    .await
-    {
-        let conn = unwrap!(res);
-
-        let connected_led_blink_task = with_timeout(
-            Duration::from_secs(1),
-            blink(status_led, Duration::from_millis(100)),
+    // Per sensors switch-on, so a kit powered back on advertises right away.
+    let mut backoff = ReconnectBackoff::default();
+    let mut attempt: u32 = 0;
+    let mut advertise_failures: u32 = 0;
+
+    loop {
+        attempt += 1;
+        info!("[adv] attempt {}", attempt);
+        let (tx_power, initial_midi_event, service_data) = config.read(|c| {
+            (
+                tx_power_level(c.tx_power),
+                initial_midi_event_packet(c),
+                advertised_service_data(c.advertised_id),
+            )
+        });
+        // Otherwise the last hit of the previous connection.
+        unwrap!(server.set(&server.midi_service.midi_event, &initial_midi_event));
+        status_led
+            .send(LedPattern::Blink(Duration::from_millis(1000)))
+            .await;
+        let advertising = with_timeout(
+            Duration::from_secs(60),
+            advertise_and_connect(service_name, &service_data, peripheral, server, tx_power),
         );
+        let Ok(res) = (match select(advertising, sensors_off.wait()).await {
+            Either::First(res) => res,
+            Either::Second(()) => return,
+        }) else {
+            break;
+        };
+        let conn = match res {
+            Ok(conn) => {
+                advertise_failures = 0;
+                conn
+            }
+            Err(e) => {
+                advertise_failures += 1;
+                log_advertise_error(&e, advertise_failures);
+                if advertise_failures >= MAX_ADVERTISE_FAILURES {
+                    error!(
+                        "[adv] giving up after {} failures in a row",
+                        advertise_failures
+                    );
+                    break;
+                }
+                Timer::after(ADVERTISE_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        COUNTERS.connections.increment();
+        let connected_at = Instant::now();
 
-        let connection_service_tasks = select(
-            gatt_events_task(&conn),
-            notify_midi_events_task(server, &conn, hit_events),
-        ); // Either task finishes means we're disconnected.
+        status_led
+            .send(LedPattern::Burst {
+                interval: Duration::from_millis(100),
+                duration: Duration::from_secs(1),
+            })
+            .await;
 
-        let _ = join(connected_led_blink_task, connection_service_tasks).await;
+        let subscription = SubscriptionSignal::new();
+        let subscribed = Cell::new(None);
+        let confirmation = ConfirmationSignal::new();
+        let last_hit = LastHitSignal::new();
+        force_disconnect.reset();
+        let connection_service_tasks = select4(
+            gatt_events_task(server, &conn, shared, &subscription, &confirmation),
+            notify_midi_events_task(
+                server,
+                &conn,
+                shared,
+                &subscription,
+                &subscribed,
+                &confirmation,
+                &last_hit,
+                rng,
+            ),
+            select(force_disconnect.wait(), sensors_off.wait()),
+            // Neither ever returns.
+            join(
+                report_att_mtu(&conn),
+                notify_last_hit_task(server, &conn, &last_hit),
+            ),
+        ); // Either service task finishes means we're disconnected.
+
+        let delay = match connection_service_tasks.await {
+            Either4::Third(Either::First(())) => {
+                // Any in-flight notification has been dropped along with the service tasks, so
+                // nothing is holding up the teardown.
+                info!("[adv] forced disconnect");
+                conn.raw().disconnect();
+                // Asked for, so not a sign of a flaky link.
+                backoff = ReconnectBackoff::default();
+                Duration::from_ticks(0)
+            }
+            Either4::Third(Either::Second(())) => {
+                info!("[adv] sensors off, disconnecting");
+                if let Some(delivery) = subscribed.get() {
+                    silence_notes(server, &conn, config, delivery, &confirmation).await;
+                }
+                conn.raw().disconnect();
+                // Advertising again is up to the sensors switching back on.
+                return;
+            }
+            _ => backoff.next_delay(Instant::now() - connected_at),
+        };
+        if delay > Duration::from_ticks(0) {
+            info!("[adv] backing off for {} before advertising again", delay);
+            Timer::after(delay).await;
+        }
     }
 
+    status_led.send(LedPattern::Off).await;
     warn!("[adv] Timeout. Not connected.");
 }
 
-async fn advertise_and_connect<'a, 's, C: Controller>(
+/// All Sound Off and All Notes Off on every channel the notes can go out on, for the notes still
+/// sounding (or waiting for their Note Off) not to hang once disconnected. Best effort, as the
+/// link is about to go anyway.
+async fn silence_notes(
+    server: &GattServer<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    config: &SharedConfig,
+    delivery: Delivery,
+    confirmation: &ConfirmationSignal,
+) {
+    let midi = &server.midi_service.midi_event;
+    let channels = config.read(note_channels_mask);
+    let msgs = (0..16)
+        .filter(|channel| channels & (1 << channel) != 0)
+        .flat_map(|channel| panic_messages(Channel::new(channel)));
+    let silence = async {
+        // Two messages to a packet, for up to 16 channels.
+        for packet in MidiEventPacket::pack::<16>(Instant::now().as_timestamp(), msgs) {
+            let sent = match delivery {
+                Delivery::Notification => {
+                    with_timeout(NOTIFY_TIMEOUT, midi.notify(conn, &packet)).await
+                }
+                Delivery::Indication => {
+                    with_timeout(
+                        NOTIFY_TIMEOUT,
+                        indicate_midi(server, conn, &packet, confirmation),
+                    )
+                    .await
+                }
+            };
+            if !matches!(sent, Ok(Ok(()))) {
+                warn!("[adv] failed to silence the notes before disconnecting");
+                return;
+            }
+        }
+    };
+    // `gatt_events_task` is gone by now, so the confirmations are picked up here, and the other
+    // events replied to as they're dropped.
+    let confirmations = async {
+        loop {
+            match conn.next().await {
+                GattConnectionEvent::Gatt { event } if is_confirmation(event.payload()) => {
+                    confirmation.signal(())
+                }
+                GattConnectionEvent::Disconnected { .. } => pending().await,
+                _ => {}
+            }
+        }
+    };
+    select(silence, confirmations).await;
+}
+
+/// Set the MIDI characteristic to `packet` and indicate it to `conn`, waiting for the client to
+/// confirm it: trouble-host's characteristics only have `notify`, so the Handle Value Indication
+/// is sent as is, and the confirmation comes back through whoever dispatches the connection's
+/// GATT events, which signals it to `confirmation`.
+async fn indicate_midi(
+    server: &GattServer<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    packet: &MidiEventPacket,
+    confirmation: &ConfirmationSignal,
+) -> Result<(), Error> {
+    let midi = &server.midi_service.midi_event;
+    server.set(midi, packet)?;
+    confirmation.reset();
+    let indication = AttUns::Indicate {
+        handle: midi.handle,
+        data: packet.as_gatt(),
+    };
+    GattData::send_unsolicited(conn.raw(), indication).await?;
+    confirmation.wait().await;
+    Ok(())
+}
+
+/// Delay between a disconnect and advertising again, growing while the connections keep dropping
+/// shortly after being established. This spares the radio and battery when the link is flaky, e.g.
+/// at the edge of the range.
+///
+/// Bounded by [`MAX_DELAY`](Self::MAX_DELAY), and reset by a connection that held for
+/// [`STABLE_CONNECTION`](Self::STABLE_CONNECTION). The attempts as a whole are still limited by
+/// the advertising timeout.
+struct ReconnectBackoff {
+    delay: Duration,
+}
+
+impl ReconnectBackoff {
+    const INITIAL_DELAY: Duration = Duration::from_millis(250);
+    const MAX_DELAY: Duration = Duration::from_secs(8);
+    const STABLE_CONNECTION: Duration = Duration::from_secs(10);
+
+    /// The delay before advertising again after a connection that lasted `connected_for`.
+    fn next_delay(&mut self, connected_for: Duration) -> Duration {
+        if connected_for >= Self::STABLE_CONNECTION {
+            *self = Self::default();
+            return Duration::from_ticks(0);
+        }
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Self::MAX_DELAY);
+        delay
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            delay: Self::INITIAL_DELAY,
+        }
+    }
+}
+
+/// Delay before advertising again after [`advertise_and_connect`] failed, for a transient radio
+/// error to clear.
+const ADVERTISE_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Failures of [`advertise_and_connect`] in a row after which advertising is given up on, like on
+/// the advertising timeout, rather than retrying an error that isn't going away (e.g. a device
+/// name too long for the advertising data).
+const MAX_ADVERTISE_FAILURES: u32 = 5;
+
+fn log_advertise_error<E: core::fmt::Debug>(e: &BleHostError<E>, failures: u32) {
+    match e {
+        BleHostError::Controller(e) => warn!(
+            "[adv] controller error ({} in a row): {}",
+            failures,
+            Debug2Format(e)
+        ),
+        BleHostError::BleHost(e) => {
+            warn!("[adv] host error ({} in a row): {:?}", failures, e)
+        }
+    }
+}
+
+/// Longest advertising data or scan response of legacy advertising.
+const MAX_ADV_DATA_LEN: usize = 31;
+
+/// AD type of the 128-bit UUID service data, which `AdStructure` has no variant of.
+const AD_SERVICE_DATA_128: u8 = 0x21;
+
+async fn advertise_and_connect<'a, 's, C: ExtAdvController>(
     name: &str,
+    service_data: &[u8; SERVICE_DATA_LEN],
     peripheral: &mut Peripheral<'a, C, DefaultPacketPool>,
     server: &'s GattServer<'a>,
+    tx_power: TxPower,
 ) -> Result<GattConnection<'a, 's, DefaultPacketPool>, BleHostError<C::Error>> {
     let mut midi_service_uuid = [0; 16];
     MIDI_SERVICE_UUID.bytes(&mut midi_service_uuid);
+    let mut uuid_and_service_data = [0; 16 + SERVICE_DATA_LEN];
+    CONTROL_SERVICE_UUID.bytes(&mut uuid_and_service_data[..16]);
+    uuid_and_service_data[16..].copy_from_slice(service_data);
 
-    let mut advertiser_data = [0; 31];
-    let len = AdStructure::encode_slice(
+    // Most important first: the BLE MIDI spec wants the MIDI service UUID in the advertising
+    // data, for hosts to list the kit as a MIDI device at all.
+    let mut advertiser_data = [0; MAX_ADV_DATA_LEN];
+    let mut scan_data = [0; MAX_ADV_DATA_LEN];
+    let (len, scan_len) = split_advertisement(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
             AdStructure::ServiceUuids128(&[midi_service_uuid]),
             AdStructure::CompleteLocalName(name.as_bytes()),
+            AdStructure::Unknown {
+                ty: AD_SERVICE_DATA_128,
+                data: &uuid_and_service_data,
+            },
         ],
-        &mut advertiser_data[..],
+        &mut advertiser_data,
+        &mut scan_data,
     )?;
-    let advertiser = peripheral
-        .advertise(
-            &AdvertisementParameters {
-                interval_min: Duration::from_millis(5),
-                interval_max: Duration::from_millis(15),
-                ..Default::default()
-            },
-            Advertisement::ConnectableScannableUndirected {
-                adv_data: &advertiser_data[..len],
-                scan_data: &[],
-            },
-        )
-        .await?;
+    // Through the extended advertising commands, as only `LeSetExtAdvParams` carries the TX
+    // power, the legacy `LeSetAdvParams` has none. Still legacy advertising PDUs, which every
+    // central scans for.
+    let sets = [AdvertisementSet {
+        params: AdvertisementParameters {
+            interval_min: Duration::from_millis(5),
+            interval_max: Duration::from_millis(15),
+            tx_power,
+            ..Default::default()
+        },
+        data: Advertisement::ConnectableScannableUndirected {
+            adv_data: &advertiser_data[..len],
+            scan_data: &scan_data[..scan_len],
+        },
+    }];
+    let mut handles = AdvertisementSet::handles(&sets);
+    let advertiser = peripheral.advertise_ext(&sets, &mut handles).await?;
     info!("[adv] advertising");
     let conn = advertiser.accept().await?.with_attribute_server(server)?;
     info!("[adv] connection established");
     Ok(conn)
 }
 
-async fn gatt_events_task<P: PacketPool>(conn: &GattConnection<'_, '_, P>) {
+/// Lay out the `fields` over the advertising data and the scan response, returning the length of
+/// each: in order, each field goes into the advertising data if it still fits, into the scan
+/// response otherwise. `Err` if a field fits in neither, e.g. with a device name too long.
+fn split_advertisement<E>(
+    fields: &[AdStructure<'_>],
+    advertiser_data: &mut [u8; MAX_ADV_DATA_LEN],
+    scan_data: &mut [u8; MAX_ADV_DATA_LEN],
+) -> Result<(usize, usize), BleHostError<E>> {
+    let (mut len, mut scan_len) = (0, 0);
+    for field in fields {
+        let field = core::slice::from_ref(field);
+        match AdStructure::encode_slice(field, &mut advertiser_data[len..]) {
+            Ok(field_len) => len += field_len,
+            Err(_) => scan_len += AdStructure::encode_slice(field, &mut scan_data[scan_len..])?,
+        }
+    }
+    Ok((len, scan_len))
+}
+
+/// Signaled when the client writes the CCCD of the MIDI characteristic, with how it asked for the
+/// packets to be delivered, or `None` when it unsubscribes.
+type SubscriptionSignal = Signal<NoopRawMutex, Option<Delivery>>;
+
+/// Signaled when the client confirms the indication in flight, see [`indicate_midi`].
+type ConfirmationSignal = Signal<NoopRawMutex, ()>;
+
+/// Signaled by [`peripheral_run`] when the sensors switch off with
+/// [`Config::disconnect_on_sensors_off`], for [`midi_service_task`] to disconnect and return.
+type SensorsOffSignal = Signal<NoopRawMutex, ()>;
+
+/// Signaled with the encoded `last_hit` characteristic value of each hit notified, see
+/// [`notify_last_hit_task`].
+type LastHitSignal = Signal<NoopRawMutex, [u8; control::LAST_HIT_LEN]>;
+
+/// How the MIDI packets go out, as chosen by the client in the characteristic's CCCD.
+///
+/// The BLE MIDI spec only has notifications, so that's what's used whenever the client enables
+/// them. A client enabling only indications gets each packet confirmed, at the cost of a round
+/// trip per packet: ATT allows a single indication in flight, so the next one waits for the
+/// confirmation of the previous one, about a connection interval later. That caps the throughput
+/// to around a packet per connection interval and adds that much latency behind every queued
+/// packet, but a packet that fails or is never confirmed (see `NOTIFY_TIMEOUT`) is known to be
+/// lost, and counts towards the stalled connection detection.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum Delivery {
+    Notification,
+    Indication,
+}
+
+/// What to do after replying to a GATT write.
+enum WriteAction {
+    Command(ControlCommand),
+    Subscription(Option<Delivery>),
+    /// MIDI written to the MIDI characteristic, for MIDI thru and the control channel.
+    Midi(MidiEventPacket),
+    Preset(PresetRequest),
+}
+
+/// Dispatch the events of `conn` until it's disconnected: GATT reads and writes are replied to,
+/// then the writes acted on (commands, subscription changes, MIDI in, presets), and the updates of
+/// the link itself are logged.
+async fn gatt_events_task<P: PacketPool>(
+    server: &GattServer<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    shared: Shared<'_>,
+    subscription: &SubscriptionSignal,
+    confirmation: &ConfirmationSignal,
+) {
+    let Shared {
+        thru_messages,
+        config,
+        force_disconnect,
+        reload_pads,
+        calibration,
+        preset_requests,
+        ..
+    } = shared;
+
+    let request_preset = |request| {
+        if preset_requests.try_send(request).is_err() {
+            warn!("[gatt] preset requests queue full, {} dropped", request);
+        }
+    };
+
+    let mut thru_loop_guard = ThruLoopGuard::default();
+    let mut config_writes = ConfigWrites::new(config);
+
     // FIXME: Fix connection with iOS not maintained.
     // TODO: Bonding? (Auto-reconnect?)
     let reason = loop {
-        if let GattConnectionEvent::Disconnected { reason } = conn.next().await {
-            break reason;
+        let action = match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => break reason,
+            GattConnectionEvent::Gatt { event } => {
+                if is_confirmation(event.payload()) {
+                    confirmation.signal(());
+                }
+                reply_to_gatt_event(server, &mut config_writes, calibration, event).await
+            }
+            GattConnectionEvent::ConnectionParamsUpdated {
+                conn_interval,
+                peripheral_latency,
+                supervision_timeout,
+            } => {
+                info!(
+                    "[gatt] connection parameters updated: interval {}, latency {}, supervision \
+                    timeout {}",
+                    conn_interval, peripheral_latency, supervision_timeout
+                );
+                None
+            }
+            GattConnectionEvent::PhyUpdated { tx_phy, rx_phy } => {
+                info!(
+                    "[gatt] PHY updated: TX {}, RX {}",
+                    Debug2Format(&tx_phy),
+                    Debug2Format(&rx_phy)
+                );
+                None
+            }
+            GattConnectionEvent::DataLengthUpdated {
+                max_tx_octets,
+                max_rx_octets,
+                ..
+            } => {
+                info!(
+                    "[gatt] data length updated: {} bytes TX, {} RX",
+                    max_tx_octets, max_rx_octets
+                );
+                None
+            }
+            // Left to the host, e.g. the central asking for other connection parameters.
+            _ => None,
+        };
+
+        let mut run_command = |command| match command {
+            ControlCommand::Disconnect => force_disconnect.signal(()),
+            ControlCommand::ReloadPads => reload_pads.signal(()),
+            ControlCommand::Calibrate => calibration.request(),
+            ControlCommand::BeginTransaction => {
+                if config_writes.begin() {
+                    warn!("[gatt] config transaction restarted, staged writes dropped");
+                } else {
+                    info!("[gatt] config transaction started");
+                }
+            }
+            ControlCommand::ApplyTransaction => match config_writes.apply() {
+                Some(rearm) => {
+                    info!("[gatt] config transaction applied");
+                    if rearm {
+                        reload_pads.signal(());
+                    }
+                }
+                None => warn!("[gatt] no config transaction to apply"),
+            },
+            ControlCommand::AbortTransaction => {
+                if config_writes.abort() {
+                    info!("[gatt] config transaction aborted");
+                    config.read(|c| set_config_values(server, c));
+                } else {
+                    warn!("[gatt] no config transaction to abort");
+                }
+            }
+            ControlCommand::NextPreset => request_preset(PresetRequest::Next),
+        };
+
+        // Only act on a write once it has been replied to.
+        match action {
+            Some(WriteAction::Command(command)) => run_command(command),
+            Some(WriteAction::Subscription(delivery)) => subscription.signal(delivery),
+            Some(WriteAction::Preset(request)) => request_preset(request),
+            Some(WriteAction::Midi(packet)) => {
+                let (midi_thru, control_channel) =
+                    config.read(|c| (c.midi_thru, c.control_channel));
+                for msg in packet.messages() {
+                    let command =
+                        control_channel.and_then(|channel| ControlCommand::from_midi(msg, channel));
+                    match command {
+                        Some(Ok(command)) => {
+                            info!("[gatt] MIDI control command {}", command);
+                            run_command(command);
+                            continue;
+                        }
+                        Some(Err(unknown)) => {
+                            warn!("[gatt] unknown MIDI control command {:#x}", unknown);
+                            continue;
+                        }
+                        None => {}
+                    }
+                    if !midi_thru || thru_loop_guard.is_echo(msg) {
+                        continue;
+                    }
+                    match thru_messages.try_send(msg) {
+                        Ok(()) => thru_loop_guard.sent(msg),
+                        Err(_) => warn!("[gatt] MIDI thru queue full, message dropped"),
+                    }
+                }
+            }
+            None => {}
         }
     };
-    info!("[gatt] disconnected: {:?}", reason);
+    if config_writes.abort() {
+        info!("[gatt] config transaction aborted by the disconnect");
+        config.read(|c| set_config_values(server, c));
+    }
+    let code = reason.into_inner();
+    info!(
+        "[gatt] disconnected: {} ({=u8:#04x})",
+        disconnect_reason(code),
+        code
+    );
 }
 
+/// Readable name of an HCI disconnect reason code (Bluetooth Core Spec, Vol 1, Part F).
+fn disconnect_reason(code: u8) -> &'static str {
+    match code {
+        0x05 => "authentication failure",
+        0x06 => "PIN or key missing",
+        0x08 => "connection timeout (supervision timeout, e.g. out of range)",
+        0x13 => "remote user terminated connection",
+        0x14 => "remote device terminated connection due to low resources",
+        0x15 => "remote device terminated connection due to power off",
+        0x16 => "connection terminated by local host",
+        0x1A => "unsupported remote feature",
+        0x1F => "unspecified error",
+        0x22 => "LL response timeout",
+        0x23 => "LL procedure collision",
+        0x28 => "instant passed",
+        0x2A => "different transaction collision",
+        0x3A => "controller busy",
+        0x3B => "unacceptable connection parameters",
+        0x3D => "connection terminated due to MIC failure",
+        0x3E => "connection failed to be established",
+        _ => "unknown reason",
+    }
+}
+
+/// Reply to a GATT read or write, accepting it unless it's rejected by [`on_read`] or
+/// [`on_write`], and return what to do about a write once it's been replied to. The ATT MTU
+/// exchange and the other requests are answered by the host itself.
+async fn reply_to_gatt_event<P: PacketPool>(
+    server: &GattServer<'_>,
+    config: &mut ConfigWrites<'_>,
+    calibration: &Calibration,
+    event: GattEvent<'_, '_, P>,
+) -> Option<WriteAction> {
+    let action = match &event {
+        GattEvent::Read(event) => {
+            // A value longer than the MTU is read in parts at increasing offsets. Only refreshed
+            // for the first, so that the parts all come from the same snapshot, and e.g. the
+            // config export isn't torn by a change happening in between (with a checksum that
+            // doesn't match).
+            if read_offset(event.payload()) == 0 {
+                on_read(server, config, calibration, event.handle());
+            }
+            Ok(None)
+        }
+        GattEvent::Write(event) => on_write(server, config, event.handle(), event.data()),
+        _ => Ok(None),
+    };
+
+    let reply = match &action {
+        Ok(_) => event.accept(),
+        Err(code) => event.reject(*code),
+    };
+    match reply {
+        Ok(reply) => reply.send().await,
+        Err(e) => warn!("[gatt] error sending response: {:?}", e),
+    };
+    action.ok().flatten()
+}
+
+/// Offset in the value a read starts at: 0 for a Read request, that of the part read for a Read
+/// Blob one.
+fn read_offset<P: PacketPool>(data: &GattData<'_, P>) -> u16 {
+    match data.incoming() {
+        AttClient::Request(AttReq::ReadBlob { offset, .. }) => offset,
+        _ => 0,
+    }
+}
+
+/// Whether `data` is the client's confirmation of an indication.
+fn is_confirmation<P: PacketPool>(data: &GattData<'_, P>) -> bool {
+    matches!(data.incoming(), AttClient::Confirmation(_))
+}
+
+/// Refresh the value about to be read, as the config can also change outside of GATT (e.g. the
+/// channel button).
+fn on_read(
+    server: &GattServer<'_>,
+    config: &ConfigWrites<'_>,
+    calibration: &Calibration,
+    handle: u16,
+) {
+    let control = &server.control_service;
+
+    let result = if handle == control.calibration.handle {
+        server.set(
+            &control.calibration,
+            &encode_calibration(calibration.report()),
+        )
+    } else if handle == control.hit_log.handle {
+        server.set(&control.hit_log, &encode_hit_log(&HIT_LOG))
+    } else if handle == control.config_export.handle {
+        let mut value = [0; MAX_BLOB_LEN];
+        match config.read(|c| blob::encode(c, &mut value)) {
+            Ok(_) => server.set(&control.config_export, &value),
+            Err(e) => {
+                warn!("[gatt] config not exported: {}", e);
+                Ok(())
+            }
+        }
+    } else {
+        // The config may have been changed by other means than GATT (e.g. the channel button or
+        // a preset loaded) since the values were set. Within a transaction, they're the staged
+        // ones written.
+        if !config.staging() {
+            config.read(|c| set_config_values(server, c));
+        }
+        Ok(())
+    };
+    if let Err(e) = result {
+        warn!("[gatt] error refreshing read value: {:?}", e);
+    }
+}
+
+fn on_write(
+    server: &GattServer<'_>,
+    config: &mut ConfigWrites<'_>,
+    handle: u16,
+    data: &[u8],
+) -> Result<Option<WriteAction>, AttErrorCode> {
+    let control = &server.control_service;
+
+    if Some(handle) == server.midi_service.midi_event.cccd_handle {
+        let flags = data.first().copied().unwrap_or(0);
+        let delivery = if flags & 0x01 != 0 {
+            Some(Delivery::Notification)
+        } else if flags & 0x02 != 0 {
+            Some(Delivery::Indication)
+        } else {
+            None
+        };
+        Ok(Some(WriteAction::Subscription(delivery)))
+    } else if handle == server.midi_service.midi_event.handle {
+        if !config.read(|c| c.midi_thru || c.control_channel.is_some()) {
+            return Ok(None);
+        }
+        match MidiEventPacket::from_gatt(data) {
+            Ok(packet) => Ok(Some(WriteAction::Midi(packet))),
+            Err(_) => {
+                warn!("[gatt] malformed MIDI packet of {} bytes", data.len());
+                Ok(None)
+            }
+        }
+    } else if handle == control.config_import.handle {
+        match config.import_chunk(data)? {
+            None => Ok(None),
+            Some(rearm) => {
+                info!("[gatt] config imported");
+                Ok(rearm.then_some(WriteAction::Command(ControlCommand::ReloadPads)))
+            }
+        }
+    } else if handle == control.preset.handle {
+        let request = decode_preset_request(data)?;
+        Ok(Some(WriteAction::Preset(request)))
+    } else if handle == control.command.handle {
+        match data.first().copied().map(ControlCommand::try_from) {
+            Some(Ok(command)) => Ok(Some(WriteAction::Command(command))),
+            Some(Err(unknown)) => {
+                warn!("[gatt] unknown control command {:#x}", unknown);
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            }
+            None => Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        }
+    } else if handle == control.program_select.handle {
+        let program_select = decode_program_select(data)?;
+        info!("[gatt] program select set to {}", program_select);
+        config.update(|c| c.program_select = program_select);
+        Ok(None)
+    } else if handle == control.channels.handle {
+        let (midi_channel, note_channels) = decode_channels(data)?;
+        info!(
+            "[gatt] MIDI channel set to {}, note overrides {}",
+            midi_channel, note_channels
+        );
+        config.update(|c| {
+            c.midi_channel = midi_channel;
+            c.note_channels = note_channels;
+        });
+        Ok(None)
+    } else if handle == control.tx_power.handle {
+        let tx_power = match data {
+            [dbm] => *dbm as i8,
+            _ => return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        };
+        if !TX_POWER_RANGE.contains(&tx_power) {
+            return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+        }
+        info!(
+            "[gatt] TX power set to {} dBm, from the next advertising",
+            tx_power
+        );
+        config.update(|c| c.tx_power = tx_power);
+        Ok(None)
+    } else if handle == control.midi_thru.handle {
+        let midi_thru = match data {
+            [0] => false,
+            [1] => true,
+            [_] => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+            _ => return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        };
+        info!("[gatt] MIDI thru set to {}", midi_thru);
+        config.update(|c| c.midi_thru = midi_thru);
+        Ok(None)
+    } else if handle == control.initial_midi_event.handle {
+        let initial_midi_event = match data {
+            [value] => InitialMidiEvent::decode(*value).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?,
+            _ => return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        };
+        info!("[gatt] initial MIDI event set to {}", initial_midi_event);
+        config.update(|c| c.initial_midi_event = initial_midi_event);
+        Ok(None)
+    } else if handle == control.mpe_channels.handle {
+        let value = data
+            .try_into()
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let mpe_channels = decode_mpe_channels(value).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+        info!("[gatt] MPE channels set to {}", mpe_channels);
+        config.update(|c| c.mpe_channels = mpe_channels);
+        Ok(None)
+    } else if handle == control.hi_hat_splash_window.handle {
+        let millis = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let window = Duration::from_millis(millis.into());
+        info!("[gatt] hi-hat splash window set to {}", window);
+        config.update(|c| c.hi_hat_splash_window = window);
+        Ok(None)
+    } else if handle == control.hi_hat_splash_hysteresis.handle {
+        let millis = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let hysteresis = Duration::from_millis(millis.into());
+        info!("[gatt] hi-hat splash hysteresis set to {}", hysteresis);
+        config.update(|c| c.hi_hat_splash_hysteresis = hysteresis);
+        Ok(None)
+    } else if handle == control.pre_connection_window.handle {
+        let millis = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let window = Duration::from_millis(millis.into());
+        info!("[gatt] pre-connection window set to {}", window);
+        config.update(|c| c.pre_connection_window = window);
+        Ok(None)
+    } else if handle == control.duplicate_packet_window.handle {
+        let millis = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let window = Duration::from_millis(millis.into());
+        info!("[gatt] duplicate packet window set to {}", window);
+        config.update(|c| c.duplicate_packet_window = window);
+        Ok(None)
+    } else if handle == control.polyphony.handle {
+        let [max_polyphony, voice_stealing] = data
+            .try_into()
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let voice_stealing =
+            VoiceStealing::decode(voice_stealing).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+        info!(
+            "[gatt] max polyphony set to {}, stealing the {}",
+            max_polyphony, voice_stealing
+        );
+        config.update(|c| {
+            c.max_polyphony = max_polyphony;
+            c.voice_stealing = voice_stealing;
+        });
+        Ok(None)
+    } else if handle == control.advertised_id.handle {
+        let id = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        info!("[gatt] advertised id set to {}", id);
+        config.update(|c| c.advertised_id = id);
+        Ok(None)
+    } else if handle == control.stuck_pad_timeout.handle {
+        let millis = data
+            .try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let timeout = Duration::from_millis(millis.into());
+        info!("[gatt] stuck pad timeout set to {}", timeout);
+        config.update(|c| c.stuck_pad_timeout = timeout);
+        Ok(None)
+    } else if handle == control.velocity_ranges.handle {
+        let velocity_ranges = decode_velocity_ranges(data)?;
+        info!("[gatt] velocity ranges set to {}", velocity_ranges);
+        config.update(|c| {
+            for (pad, range) in c.pads.iter_mut().zip(velocity_ranges) {
+                pad.velocity_range = range;
+            }
+        });
+        Ok(None)
+    } else if handle == control.note_map.handle {
+        let value = data
+            .try_into()
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let note_map = NoteMap::decode(value).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+        info!("[gatt] note map set to {}", note_map);
+        config.update(|c| c.note_map = note_map);
+        Ok(None)
+    } else if handle == control.control_channel.handle {
+        let control_channel = match data {
+            [0xFF] => None,
+            [channel @ 0..=15] => Some(*channel),
+            [_] => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+            _ => return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        };
+        info!("[gatt] control channel set to {}", control_channel);
+        config.update(|c| c.control_channel = control_channel);
+        Ok(None)
+    } else if handle == control.timestamp_offset.handle {
+        let timestamp_offset = data
+            .try_into()
+            .map(i16::from_le_bytes)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        if !TIMESTAMP_OFFSET_RANGE.contains(&timestamp_offset) {
+            return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+        }
+        info!("[gatt] timestamp offset set to {} ms", timestamp_offset);
+        config.update(|c| c.timestamp_offset = timestamp_offset);
+        Ok(None)
+    } else if handle == control.velocity_curve.handle {
+        let value = data
+            .try_into()
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let velocity_curve = VelocityCurve::decode(value).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+        info!("[gatt] velocity curve set to {}", velocity_curve);
+        config.update(|c| c.velocity_curve = velocity_curve);
+        Ok(None)
+    } else if handle == control.velocity_gains.handle {
+        let velocity_gains: [u8; DrumNote::COUNT] = data
+            .try_into()
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        info!("[gatt] velocity gains set to {}", velocity_gains);
+        config.update(|c| c.velocity_gains = velocity_gains);
+        Ok(None)
+    } else {
+        Ok(None)
+    }
+}
+
+#[allow(
+    clippy::too_many_arguments,
+    reason = "each is a per-connection state shared with another task, bundling them would just \
+    move the list"
+)]
 async fn notify_midi_events_task(
     server: &GattServer<'_>,
     conn: &GattConnection<'_, '_, DefaultPacketPool>,
-    hit_events: HitEventsReceiver<'_>,
+    shared: Shared<'_>,
+    subscription: &SubscriptionSignal,
+    subscribed: &Cell<Option<Delivery>>,
+    confirmation: &ConfirmationSignal,
+    last_hit: &LastHitSignal,
+    rng: &mut XorShift32,
 ) {
+    let Shared {
+        hit_events,
+        hit_events_backpressure,
+        priority_messages,
+        config,
+        ..
+    } = shared;
+
     let midi = &server.midi_service.midi_event;
+    // The hits from before the connection, sent once the client subscribes.
+    let mut buffered: Vec<HitEvent, HIT_EVENTS_DEPTH> = Vec::new();
+    let pre_connection_window = config.read(|c| c.pre_connection_window);
+    if pre_connection_window > Duration::from_ticks(0) {
+        let kept_since = Instant::now().checked_sub(pre_connection_window);
+        while let Ok(hit) = hit_events.try_receive() {
+            if kept_since.is_none_or(|since| hit.timestamp >= since) {
+                let _ = buffered.push(hit);
+            }
+        }
+        if !buffered.is_empty() {
+            info!(
+                "[notify_midi_events_task] {} hits from before the connection kept",
+                buffered.len()
+            );
+        }
+    }
     hit_events.clear();
+    hit_events_backpressure.update(0);
+
+    let mut program_selected = false;
+
+    // A central that silently walks out of range leaves us waiting for the supervision timeout
+    // before `Disconnected` arrives, while notifications pile up in the meantime. Count the
+    // notifications that fail or don't go through in time, and tear the link down ourselves if
+    // too many of them fail back-to-back.
+    const MAX_CONSECUTIVE_NOTIFY_FAILURES: u8 = 3;
+    let mut consecutive_failures = 0;
+
+    // Nothing goes out until the client subscribes (`subscribed`, kept by the caller for the
+    // connection), as the notifications would just be dropped (or fail) meanwhile. The hits in the
+    // meantime are dropped too, they'd be stale by then.
+
+    // Per connection, so that the first packet after connecting always carries a status byte.
+    let mut running_status = RunningStatus::default();
+
+    // Notifications carry at most the ATT MTU minus their 3-byte header. The MTU can be
+    // renegotiated at any time, so it's checked on every notification.
+    let mut last_mtu = 0;
+
+    // For the Active Sensing keep-alive.
+    let last_notified = Cell::new(Instant::now());
+
+    let mut duplicate_guard = DuplicateGuard::default();
+
+    // Returns `false` once the connection is considered stalled.
+    let mut notify = async |packet: MidiEventPacket| {
+        last_notified.set(Instant::now());
+        let Some(delivery) = subscribed.get() else {
+            return true;
+        };
+        let mtu = conn.raw().att_mtu().max(DEFAULT_ATT_MTU);
+        if mtu != last_mtu {
+            info!("[notify_midi_events_task] ATT MTU is {}", mtu);
+            last_mtu = mtu;
+        }
+        let max_len = usize::from(mtu - ATT_NOTIFICATION_HEADER_LEN);
+        if packet.as_gatt().len() > max_len {
+            error!(
+                "[notify_midi_events_task] dropping packet of {} bytes, over the {} allowed by the MTU",
+                packet.as_gatt().len(),
+                max_len
+            );
+            return true;
+        }
+
+        let window = config.read(|c| c.duplicate_packet_window);
+        if duplicate_guard.is_duplicate(&packet, Instant::now(), window) {
+            debug!("[notify_midi_events_task] dropping packet identical to the previous one");
+            return true;
+        }
+
+        let packet = if cfg!(feature = "running-status") {
+            packet.with_running_status(&mut running_status)
+        } else {
+            packet
+        };
+        let sent = match delivery {
+            Delivery::Notification => {
+                with_timeout(NOTIFY_TIMEOUT, midi.notify(conn, &packet)).await
+            }
+            Delivery::Indication => {
+                with_timeout(
+                    NOTIFY_TIMEOUT,
+                    indicate_midi(server, conn, &packet, confirmation),
+                )
+                .await
+            }
+        };
+        match sent {
+            Ok(Ok(())) => consecutive_failures = 0,
+            Ok(Err(_)) => {
+                error!("[notify_midi_events_task] error notifying connection");
+                COUNTERS.notify_failures.increment();
+                consecutive_failures += 1;
+                running_status.reset();
+            }
+            Err(TimeoutError) => {
+                error!("[notify_midi_events_task] timed out notifying connection");
+                COUNTERS.notify_failures.increment();
+                consecutive_failures += 1;
+                running_status.reset();
+            }
+        }
+
+        if consecutive_failures >= MAX_CONSECUTIVE_NOTIFY_FAILURES {
+            warn!(
+                "[notify_midi_events_task] connection stalled after {} failed notifications. Disconnecting.",
+                consecutive_failures
+            );
+            conn.raw().disconnect();
+            false
+        } else {
+            true
+        }
+    };
+
+    // Note Offs of the hits still within their pad's gate, in no particular order.
+    let mut pending_note_offs: Vec<PendingNoteOff, MAX_PENDING_NOTE_OFFS> = Vec::new();
+    let mut channel_rotation = ChannelRotation::default();
 
     loop {
-        let (timestamp, note) = hit_events.receive().await;
+        let (active_sensing, timestamp_offset) =
+            config.read(|c| (c.active_sensing_interval, c.timestamp_offset));
+        // Of the hits and their Note Offs, with `Config::timestamp_offset`.
+        let stamp = |at: Instant| Shifted(at, timestamp_offset);
+        let active_sensing_due = (active_sensing != Duration::from_ticks(0))
+            .then(|| last_notified.get() + active_sensing);
 
-        const MIDI_CHANNEL: Channel = Channel::new(9);
-        const MIDI_VELOCITY: Value7 = Value7::new(100);
+        let next_timer = async {
+            let next_note_off = pending_note_offs
+                .iter()
+                .filter(|n| n.until_release.is_none())
+                .map(|n| n.due)
+                .min();
+            match next_note_off.into_iter().chain(active_sensing_due).min() {
+                Some(at) => Timer::at(at).await,
+                None => pending().await,
+            }
+        };
 
-        let packet = (
-            timestamp,
-            MidiMessage::NoteOn(MIDI_CHANNEL, note.into(), MIDI_VELOCITY),
-        )
-            .into();
+        // Polled in order, so priority messages jump ahead of buffered hits.
+        let event = if subscribed.get().is_some() && !buffered.is_empty() {
+            Either4::Third(buffered.remove(0))
+        } else {
+            select4(
+                priority_messages.receive(),
+                next_timer,
+                hit_events.receive(),
+                subscription.wait(),
+            )
+            .await
+        };
 
-        if midi.notify(conn, &packet).await.is_err() {
-            error!("[notify_midi_events_task] error notifying connection");
-            break;
+        let hit = match event {
+            Either4::First(msg) => {
+                if !notify(msg.into()).await {
+                    return;
+                }
+                continue;
+            }
+            Either4::Second(()) => {
+                let now = Instant::now();
+                while let Some(i) = pending_note_offs
+                    .iter()
+                    .position(|n| n.until_release.is_none() && n.due <= now)
+                {
+                    let PendingNoteOff { due, msg, .. } = pending_note_offs.swap_remove(i);
+                    if !notify((stamp(due), msg).into()).await {
+                        return;
+                    }
+                }
+                if active_sensing_due.is_some_and(|due| due <= now)
+                    && !notify(MidiMessage::ActiveSensing.into()).await
+                {
+                    return;
+                }
+                continue;
+            }
+            Either4::Third(hit_event) => {
+                hit_events_backpressure.update(hit_events.len());
+                hit_event
+            }
+            Either4::Fourth(changed) => {
+                if changed != subscribed.get() {
+                    match changed {
+                        Some(delivery) => {
+                            info!("[notify_midi_events_task] subscribed, by {}", delivery)
+                        }
+                        None => info!("[notify_midi_events_task] unsubscribed, pausing"),
+                    }
+                    subscribed.set(changed);
+                }
+                if changed.is_none() {
+                    continue;
+                }
+                // Only once per connection, even if the client re-subscribes.
+                if !program_selected
+                    && let Some((program_select, channel)) =
+                        config.read(|c| c.program_select.map(|p| (p, Channel::new(c.midi_channel))))
+                {
+                    program_selected = true;
+                    // In one packet, or in order over several if the packet were too small.
+                    let msgs = program_select_messages(program_select, channel);
+                    for packet in MidiEventPacket::pack::<3>(
+                        Instant::now().as_timestamp(),
+                        msgs.into_iter().flatten(),
+                    ) {
+                        if !notify(packet).await {
+                            return;
+                        }
+                    }
+                }
+                continue;
+            }
         };
 
-        let packet = (
-            timestamp,
-            MidiMessage::NoteOff(MIDI_CHANNEL, note.into(), 0.into()),
+        let (note, velocity, until_release) = match hit.kind {
+            HitKind::Note {
+                note,
+                velocity,
+                until_release,
+            } => (note, velocity, until_release),
+            HitKind::Release => {
+                // Sent by the timer, once any `min_gate` is over.
+                for n in &mut pending_note_offs {
+                    if n.until_release == Some(hit.pad) {
+                        n.until_release = None;
+                        n.due = n.due.max(hit.timestamp);
+                    }
+                }
+                continue;
+            }
+            HitKind::Control { pressed } => {
+                // Dropped if the pad has stopped being a control pad since.
+                let msgs = config.read(|c| {
+                    c.pads[hit.pad]
+                        .control
+                        .map(|control| build_control_messages(control, pressed, c))
+                });
+                // Together, as the messages of a parameter change must be.
+                for packet in MidiEventPacket::pack::<MAX_CONTROL_MESSAGES>(
+                    stamp(hit.timestamp).as_timestamp(),
+                    msgs.into_iter().flatten(),
+                ) {
+                    if !notify(packet).await {
+                        return;
+                    }
+                }
+                continue;
+            }
+        };
+
+        // Built together so that the Note Offs match even if the config changes in between. The
+        // pad's note first, then its layer if any.
+        let (pad, notes, mpe_channels, polyphony) = config.read(|c| {
+            let pad = c.pads[hit.pad];
+            let layer = pad
+                .layer
+                .map(|layer| (layer.note, scale_velocity(velocity, layer.velocity_scale)));
+            let notes = [Some((note, velocity)), layer].map(|note| {
+                note.map(|(note, velocity)| {
+                    (
+                        note,
+                        build_note_on(hit.pad, note, velocity, c, rng),
+                        build_note_off(note, c),
+                    )
+                })
+            });
+            let polyphony = (c.max_polyphony, c.voice_stealing);
+            (pad, notes, c.mpe_channels, polyphony)
+        });
+
+        let mut layered: Vec<(DrumNote, MidiMessage, MidiMessage), 2> = Vec::new();
+        for (note, note_on, note_off) in notes.into_iter().flatten() {
+            if let Some(i) = pending_note_offs
+                .iter()
+                .position(|n| n.is_cut_by(note, pad.trigger_mode))
+            {
+                let PendingNoteOff { msg, .. } = pending_note_offs.swap_remove(i);
+                if !notify((stamp(hit.timestamp), msg).into()).await {
+                    return;
+                }
+            }
+
+            // After the flush above, so that the channel it frees can be taken.
+            let (note_on, note_off) = match mpe_channels {
+                Some(range) => {
+                    let channel = channel_rotation.assign(range, |channel| {
+                        pending_note_offs
+                            .iter()
+                            .any(|n| matches!(n.msg, MidiMessage::NoteOff(c, ..) if c == channel))
+                    });
+                    (
+                        with_channel(note_on, channel),
+                        with_channel(note_off, channel),
+                    )
+                }
+                None => (note_on, note_off),
+            };
+            let _ = layered.push((note, note_on, note_off));
+        }
+
+        // Of the pad's own note, for the layer too.
+        let pad_gate = match layered.first() {
+            Some((_, MidiMessage::NoteOn(_, _, velocity), _)) => pad.gate_for(u8::from(*velocity)),
+            _ => pad.gate,
+        };
+        let gate = pad_gate.max(pad.min_gate);
+        // Before the Note Ons, for the host to have the voices back by then.
+        let (max_polyphony, voice_stealing) = polyphony;
+        while (until_release || gate > Duration::from_ticks(0))
+            && max_polyphony > 0
+            && pending_note_offs.len() + layered.len() > usize::from(max_polyphony)
+            && let Some(i) = voice_to_steal(&pending_note_offs, voice_stealing)
+        {
+            let PendingNoteOff { note, msg, .. } = pending_note_offs.swap_remove(i);
+            debug!("[notify_midi_events_task] stealing the voice of {}", note);
+            if !notify((stamp(hit.timestamp), msg).into()).await {
+                return;
+            }
+        }
+
+        if let Some(&(note, MidiMessage::NoteOn(_, _, velocity), _)) = layered.first() {
+            last_hit.signal(encode_last_hit(
+                hit.pad,
+                note,
+                u8::from(velocity),
+                hit.timestamp,
+            ));
+        }
+        if let [(_, note_on, note_off)] = layered[..]
+            && cfg!(feature = "batched-note-off")
+            && !until_release
+            && pad_gate >= pad.min_gate
+        {
+            // Both in one packet, the Note Off timestamped at the end of the gate, if the gate is
+            // short enough for the timestamps to tell. Falls back to scheduling the Note Off.
+            let batched = MidiEventPacket::add_timestamped(stamp(hit.timestamp), note_on)
+                .add_timestamped(stamp(hit.timestamp + gate), note_off);
+            if let Ok(packet) = batched {
+                if !notify(packet.build()).await {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        // The Note Ons of the layers together in one packet, which fits two of them.
+        let note_ons = layered.iter().map(|&(_, note_on, _)| note_on);
+        for packet in MidiEventPacket::pack::<2>(stamp(hit.timestamp).as_timestamp(), note_ons) {
+            if !notify(packet).await {
+                return;
+            }
+        }
+
+        for (note, note_on, note_off) in layered {
+            let velocity = match note_on {
+                MidiMessage::NoteOn(_, _, velocity) => u8::from(velocity),
+                _ => 0,
+            };
+            let note_off = PendingNoteOff {
+                due: hit.timestamp + if until_release { pad.min_gate } else { gate },
+                on_at: hit.timestamp,
+                velocity,
+                note,
+                msg: note_off,
+                held: !until_release && pad_gate < pad.min_gate,
+                until_release: until_release.then_some(hit.pad),
+            };
+            if gate == Duration::from_ticks(0) && !until_release {
+                if !notify((stamp(note_off.due), note_off.msg).into()).await {
+                    return;
+                }
+            } else if let Err(note_off) = pending_note_offs.push(note_off) {
+                // Make room by cutting the gate of the one due soonest short.
+                let soonest = pending_note_offs
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, n)| n.due)
+                    .map(|(i, _)| i);
+                if let Some(i) = soonest {
+                    let PendingNoteOff { msg, .. } =
+                        core::mem::replace(&mut pending_note_offs[i], note_off);
+                    if !notify((stamp(hit.timestamp), msg).into()).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Index in `note_offs` of the sounding note the `policy` ends for a new one, `None` if there's
+/// none. See [`Config::max_polyphony`].
+fn voice_to_steal(note_offs: &[PendingNoteOff], policy: VoiceStealing) -> Option<usize> {
+    let voices = note_offs.iter().enumerate();
+    let stolen = match policy {
+        VoiceStealing::Oldest => voices.min_by_key(|(_, n)| n.on_at),
+        VoiceStealing::Quietest => voices.min_by_key(|(_, n)| (n.velocity, n.on_at)),
+    };
+    stolen.map(|(i, _)| i)
+}
+
+/// Drops the MIDI packets repeating the previous one sent, see
+/// [`Config::duplicate_packet_window`].
+#[derive(Default)]
+struct DuplicateGuard {
+    last_sent: Option<(Instant, MidiEventPacket)>,
+}
+
+impl DuplicateGuard {
+    /// Whether `packet`, about to be sent at `now`, is byte-identical to the last one sent less
+    /// than `window` before. If not, it's remembered as the last one sent. Compared as built, i.e.
+    /// before any running status is applied.
+    fn is_duplicate(&mut self, packet: &MidiEventPacket, now: Instant, window: Duration) -> bool {
+        if let Some((sent_at, last)) = &self.last_sent
+            && now - *sent_at < window
+            && last.as_bytes() == packet.as_bytes()
+        {
+            return true;
+        }
+        self.last_sent = Some((now, *packet));
+        false
+    }
+}
+
+/// Keeps MIDI thru from looping with a client that echoes the notifications back, e.g. one
+/// running MIDI thru itself, which would otherwise bounce every message between the two forever.
+///
+/// Remembers the messages echoed in the last [`ECHO_WINDOW`](Self::ECHO_WINDOW), and drops an
+/// incoming one matching them as the echo of the echo. A client legitimately repeating the exact
+/// same message within the window loses the repeat.
+#[derive(Default)]
+pub struct ThruLoopGuard {
+    echoed: Vec<(Instant, MidiMessage), 8>,
+}
+
+impl ThruLoopGuard {
+    /// Longer than a round trip through the client, a few connection intervals.
+    const ECHO_WINDOW: Duration = Duration::from_millis(250);
+
+    /// Whether `msg` is an echo still coming back, forgetting it if so.
+    fn is_echo(&mut self, msg: MidiMessage) -> bool {
+        let now = Instant::now();
+        self.echoed.retain(|(at, _)| now - *at < Self::ECHO_WINDOW);
+        match self.echoed.iter().position(|(_, echoed)| *echoed == msg) {
+            Some(i) => {
+                self.echoed.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sent(&mut self, msg: MidiMessage) {
+        if self.echoed.is_full() {
+            self.echoed.remove(0);
+        }
+        let _ = self.echoed.push((Instant::now(), msg));
+    }
+}
+
+fn initial_midi_event_packet(config: &Config) -> MidiEventPacket {
+    match config.initial_midi_event {
+        InitialMidiEvent::Empty => MidiEventPacket::empty(),
+        InitialMidiEvent::Reset => MidiMessage::Reset.into(),
+        InitialMidiEvent::AllNotesOff => MidiMessage::ControlChange(
+            Channel::new(config.midi_channel),
+            Control::new(123),
+            0.into(),
         )
-            .into();
+        .into(),
+    }
+}
 
-        if midi.notify(conn, &packet).await.is_err() {
-            error!("[notify_midi_events_task] error notifying connection");
-            break;
+/// The highest radio power level not above `dbm`.
+fn tx_power_level(dbm: i8) -> TxPower {
+    match dbm {
+        20.. => TxPower::Plus20dBm,
+        18.. => TxPower::Plus18dBm,
+        16.. => TxPower::Plus16dBm,
+        14.. => TxPower::Plus14dBm,
+        12.. => TxPower::Plus12dBm,
+        10.. => TxPower::Plus10dBm,
+        8.. => TxPower::Plus8dBm,
+        7 => TxPower::Plus7dBm,
+        6 => TxPower::Plus6dBm,
+        5 => TxPower::Plus5dBm,
+        4 => TxPower::Plus4dBm,
+        3 => TxPower::Plus3dBm,
+        2 => TxPower::Plus2dBm,
+        0.. => TxPower::ZerodBm,
+        -4.. => TxPower::Minus4dBm,
+        -8.. => TxPower::Minus8dBm,
+        -12.. => TxPower::Minus12dBm,
+        -16.. => TxPower::Minus16dBm,
+        -20.. => TxPower::Minus20dBm,
+        _ => TxPower::Minus40dBm,
+    }
+}
+
+/// Notify the `last_hit` characteristic with the latest of the hits signaled, at most every
+/// [`LAST_HIT_INTERVAL`]. The value is stored for reads either way, but only notified once the
+/// client has subscribed to it.
+async fn notify_last_hit_task(
+    server: &GattServer<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    last_hit: &LastHitSignal,
+) -> ! {
+    let characteristic = &server.control_service.last_hit;
+    loop {
+        let value = last_hit.wait().await;
+        if let Err(e) = characteristic.notify(conn, &value).await {
+            warn!("[gatt] error notifying the last hit: {:?}", e);
+        }
+        // The hits in the meantime overwrite each other in the signal.
+        Timer::after(LAST_HIT_INTERVAL).await;
+    }
+}
+
+/// Log the ATT MTU the central settles on after connecting.
+///
+/// Only the client can start the ATT MTU exchange, so a peripheral can't ask for a bigger MTU
+/// itself. It can only offer up to what its packet pool allows (see the BLE stack sizing in
+/// `main.rs`) once asked, which most phones and computers do right after connecting. Whatever
+/// comes out of it is respected by the notifications, which check it on every packet.
+async fn report_att_mtu(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> ! {
+    const MTU_EXCHANGE_GRACE: Duration = Duration::from_secs(2);
+
+    Timer::after(MTU_EXCHANGE_GRACE).await;
+    let mtu = conn.raw().att_mtu();
+    if mtu > DEFAULT_ATT_MTU {
+        info!("[gatt] ATT MTU negotiated to {}", mtu);
+    } else {
+        info!(
+            "[gatt] central kept the default ATT MTU of {}",
+            DEFAULT_ATT_MTU
+        );
+    }
+    pending().await
+}
+
+/// The ATT MTU every connection starts with, and the least it can be negotiated to.
+const DEFAULT_ATT_MTU: u16 = 23;
+/// Opcode and attribute handle of a notification.
+const ATT_NOTIFICATION_HEADER_LEN: u16 = 3;
+/// Longer than this and a notification (or indication) counts as failed.
+const NOTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_PENDING_NOTE_OFFS: usize = 16;
+
+struct PendingNoteOff {
+    due: Instant,
+    /// Of the Note On, with its velocity, for `voice_to_steal`.
+    on_at: Instant,
+    velocity: u8,
+    note: DrumNote,
+    msg: MidiMessage,
+    /// Due later than the pad's gate because of its `min_gate`.
+    held: bool,
+    /// The pad whose `HitKind::Release` this waits for, see `PadConfig::gate_follows_sensor`. Due
+    /// no earlier than at the end of the pad's `min_gate`.
+    until_release: Option<usize>,
+}
+
+impl PendingNoteOff {
+    /// Whether a new Note On of `note`, of a pad in `trigger_mode`, sends this right before it:
+    /// the note's previous Note Off in [`TriggerMode::Mono`], or one held back by a `min_gate`.
+    fn is_cut_by(&self, note: DrumNote, trigger_mode: TriggerMode) -> bool {
+        self.note == note && (self.held || trigger_mode == TriggerMode::Mono)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::select::{Either3, select3};
+    use midi_types::Note;
+
+    use super::*;
+    use crate::{
+        config::{Config, DEFAULT_TX_POWER},
+        tasks::{
+            ble::mock_controller::MockCentral, gpio::HitEventsChannel, led::LedPatternChannel,
+            nvs::PresetRequestsChannel,
+        },
+        test_support::{MockTime, run_until},
+    };
+
+    fn note_off(note: DrumNote, on_at: u64, velocity: u8, held: bool) -> PendingNoteOff {
+        let on_at = Instant::from_millis(on_at);
+        PendingNoteOff {
+            due: on_at + Duration::from_millis(50),
+            on_at,
+            velocity,
+            note,
+            msg: build_note_off(note, &Config::default()),
+            held,
+            until_release: None,
+        }
+    }
+
+    #[test]
+    fn held_note_off_goes_before_the_next_hit_of_its_note() {
+        let held = note_off(DrumNote::Snare, 0, 100, true);
+        assert!(held.is_cut_by(DrumNote::Snare, TriggerMode::Poly));
+        assert!(held.is_cut_by(DrumNote::Snare, TriggerMode::Mono));
+        assert!(!held.is_cut_by(DrumNote::BassDrum, TriggerMode::Poly));
+    }
+
+    #[test]
+    fn gated_note_off_goes_before_the_next_hit_of_its_note_in_mono_only() {
+        let gated = note_off(DrumNote::Snare, 0, 100, false);
+        assert!(!gated.is_cut_by(DrumNote::Snare, TriggerMode::Poly));
+        assert!(gated.is_cut_by(DrumNote::Snare, TriggerMode::Mono));
+        assert!(!gated.is_cut_by(DrumNote::BassDrum, TriggerMode::Mono));
+    }
+
+    /// Sounding notes hit at 0, 10 and 20 ms, the middle one the quietest.
+    fn sounding() -> [PendingNoteOff; 3] {
+        [
+            note_off(DrumNote::Snare, 0, 100, false),
+            note_off(DrumNote::BassDrum, 10, 40, false),
+            note_off(DrumNote::CrashCymbal1, 20, 90, false),
+        ]
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen_first() {
+        assert_eq!(voice_to_steal(&sounding(), VoiceStealing::Oldest), Some(0));
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen_first_the_oldest_of_them_on_a_tie() {
+        let mut note_offs = sounding();
+        assert_eq!(voice_to_steal(&note_offs, VoiceStealing::Quietest), Some(1));
+        note_offs[2].velocity = 40;
+        assert_eq!(voice_to_steal(&note_offs, VoiceStealing::Quietest), Some(1));
+        note_offs[0].velocity = 40;
+        assert_eq!(voice_to_steal(&note_offs, VoiceStealing::Quietest), Some(0));
+    }
+
+    #[test]
+    fn no_voice_to_steal_without_a_sounding_note() {
+        assert_eq!(voice_to_steal(&[], VoiceStealing::Oldest), None);
+        assert_eq!(voice_to_steal(&[], VoiceStealing::Quietest), None);
+    }
+
+    fn snare_packet(timestamp: u16, velocity: u8) -> MidiEventPacket {
+        let note_on = MidiMessage::NoteOn(Channel::C10, Note::new(38), velocity.into());
+        MidiEventPacket::add_timestamped(timestamp, note_on).build()
+    }
+
+    #[test]
+    fn identical_packet_within_the_window_is_a_duplicate() {
+        let window = Duration::from_millis(5);
+        let mut guard = DuplicateGuard::default();
+        let sent_at = Instant::from_millis(1_000);
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), sent_at, window));
+        assert!(guard.is_duplicate(
+            &snare_packet(0, 100),
+            sent_at + Duration::from_millis(4),
+            window
+        ));
+        // Duplicates don't extend the window.
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), sent_at + window, window));
+    }
+
+    #[test]
+    fn different_packet_within_the_window_is_not_a_duplicate() {
+        let window = Duration::from_millis(5);
+        let mut guard = DuplicateGuard::default();
+        let sent_at = Instant::from_millis(1_000);
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), sent_at, window));
+        // The same hit a millisecond later, which its timestamp tells apart.
+        let later = sent_at + Duration::from_millis(1);
+        assert!(!guard.is_duplicate(&snare_packet(1, 100), later, window));
+        assert!(!guard.is_duplicate(&snare_packet(1, 90), later, window));
+        // Compared with the last one sent only.
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), later, window));
+    }
+
+    #[test]
+    fn no_duplicates_without_a_window() {
+        let window = Duration::from_ticks(0);
+        let mut guard = DuplicateGuard::default();
+        let sent_at = Instant::from_millis(1_000);
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), sent_at, window));
+        assert!(!guard.is_duplicate(&snare_packet(0, 100), sent_at, window));
+    }
+
+    /// What [`peripheral_run`] shares with the rest of the firmware, in place of its statics.
+    struct Firmware {
+        status_signal: SensorsStatusSignal,
+        status_led: LedPatternChannel,
+        hit_events: HitEventsChannel,
+        hit_events_backpressure: HitEventsBackpressure,
+        priority_messages: PriorityMessagesChannel,
+        config: SharedConfig,
+        force_disconnect: ForceDisconnectSignal,
+        reload_pads: ReloadPadsSignal,
+        calibration: Calibration,
+        preset_requests: PresetRequestsChannel,
+    }
+
+    impl Firmware {
+        fn new() -> Self {
+            Self {
+                status_signal: SensorsStatusSignal::new(),
+                status_led: LedPatternChannel::new(),
+                hit_events: HitEventsChannel::new(),
+                hit_events_backpressure: HitEventsBackpressure::new(),
+                priority_messages: PriorityMessagesChannel::new(),
+                config: SharedConfig::new(Config::default()),
+                force_disconnect: ForceDisconnectSignal::new(),
+                reload_pads: ReloadPadsSignal::new(),
+                calibration: Calibration::new(),
+                preset_requests: PresetRequestsChannel::new(),
+            }
+        }
+
+        fn shared(&self) -> Shared<'_> {
+            Shared {
+                status_signal: &self.status_signal,
+                status_led: self.status_led.sender(),
+                hit_events: self.hit_events.receiver(),
+                hit_events_backpressure: &self.hit_events_backpressure,
+                priority_messages: self.priority_messages.receiver(),
+                thru_messages: self.priority_messages.sender(),
+                config: &self.config,
+                force_disconnect: &self.force_disconnect,
+                reload_pads: &self.reload_pads,
+                calibration: &self.calibration,
+                preset_requests: self.preset_requests.sender(),
+            }
+        }
+
+        /// The snare pad hit.
+        fn hit(&self) {
+            let pad = self.config.read(|c| {
+                c.pads
+                    .iter()
+                    .position(|pad| pad.note == DrumNote::Snare)
+                    .unwrap()
+            });
+            let hit = HitEvent {
+                timestamp: Instant::now(),
+                pad,
+                kind: HitKind::Note {
+                    note: DrumNote::Snare,
+                    velocity: 100,
+                    until_release: false,
+                },
+            };
+            assert!(self.hit_events.try_send(hit).is_ok());
+        }
+    }
+
+    /// Whether any of the MIDI packets notified is a hit of the snare.
+    fn has_snare_hit(notified: &[std::vec::Vec<u8>], config: &SharedConfig) -> bool {
+        let snare = config.read(|c| c.note_number(DrumNote::Snare));
+        notified.iter().any(|value| {
+            MidiEventPacket::from_gatt(value)
+                .unwrap()
+                .messages()
+                .any(|msg| matches!(msg, MidiMessage::NoteOn(_, note, _) if note == snare))
+        })
+    }
+
+    #[test]
+    fn peripheral_run_follows_the_sensors_and_the_link() {
+        // All in one run: the GATT server's storage can only be set up once per process.
+        let time = MockTime::lock();
+        let firmware = Firmware::new();
+        let (central, controller) = MockCentral::new();
+        let settle = || Timer::after(Duration::from_millis(100));
+        let subscribe = async || {
+            let midi = central
+                .discover(
+                    uuid!("7772E5DB-3868-4112-A1A9-F2669D106BF3")
+                        .as_raw()
+                        .try_into()
+                        .unwrap(),
+                )
+                .await;
+            // Its CCCD, right after it, to notifications.
+            central.write(midi + 1, &[0x01, 0x00]).await;
+            settle().await;
+            central.take_notified(midi);
+            midi
         };
+
+        let script = async {
+            settle().await;
+            assert!(
+                !central.is_advertising(),
+                "advertising with the sensors off"
+            );
+
+            firmware.status_signal.signal(SensorsStatus::On);
+            settle().await;
+            assert!(
+                central.is_advertising(),
+                "not advertising with the sensors on"
+            );
+            assert_eq!(central.advertising_tx_power(), Some(DEFAULT_TX_POWER));
+            firmware.status_signal.signal(SensorsStatus::Off);
+            settle().await;
+            assert!(
+                !central.is_advertising(),
+                "advertising after the sensors off"
+            );
+
+            firmware.status_signal.signal(SensorsStatus::On);
+            settle().await;
+            assert!(
+                central.is_advertising(),
+                "not advertising with the sensors back on"
+            );
+            // Nobody to notify, and stale once somebody connects.
+            firmware.hit();
+            settle().await;
+            central.connect();
+            settle().await;
+            let midi = subscribe().await;
+            assert!(
+                central.take_notified(midi).is_empty(),
+                "hit from before connecting"
+            );
+            firmware.hit();
+            settle().await;
+            assert!(
+                has_snare_hit(&central.take_notified(midi), &firmware.config),
+                "hit not notified while connected"
+            );
+
+            let tx_power = central
+                .discover(
+                    uuid!("9E1D0007-6A3B-4C6E-8F2D-2B7C4E5A1F00")
+                        .as_raw()
+                        .try_into()
+                        .unwrap(),
+                )
+                .await;
+            central.write(tx_power, &[-12i8 as u8]).await;
+
+            central.disconnect();
+            settle().await;
+            firmware.hit();
+            settle().await;
+            assert!(
+                central.take_notified(midi).is_empty(),
+                "hit notified once disconnected"
+            );
+            Timer::after(ReconnectBackoff::INITIAL_DELAY).await;
+            assert!(
+                central.is_advertising(),
+                "not advertising again once disconnected"
+            );
+            assert_eq!(central.advertising_tx_power(), Some(-12));
+
+            central.connect();
+            settle().await;
+            let midi = subscribe().await;
+            firmware.status_signal.signal(SensorsStatus::Off);
+            settle().await;
+            assert!(
+                !central.is_connected(),
+                "still connected with the sensors off"
+            );
+            assert!(
+                !central.is_advertising(),
+                "advertising with the sensors off"
+            );
+            firmware.hit();
+            settle().await;
+            assert!(
+                !has_snare_hit(&central.take_notified(midi), &firmware.config),
+                "hit notified with the sensors off"
+            );
+        };
+        let status_led = async {
+            loop {
+                firmware.status_led.receive().await;
+            }
+        };
+
+        let run = select3(
+            peripheral_run(controller, 1, firmware.shared()),
+            status_led,
+            script,
+        );
+        match run_until(&time, Instant::now() + Duration::from_secs(30), run) {
+            Some(Either3::Third(())) => {}
+            _ => panic!("script not done"),
+        }
     }
 }
@@ -0,0 +1,737 @@
+use defmt::warn;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use midi_types::{Channel, Control, MidiMessage};
+use trouble_host::prelude::*;
+
+use crate::{
+    config::{
+        CONFIG_VERSION, Config, PAD_COUNT, ProgramSelect, SharedConfig, VELOCITY_CURVE_POINTS,
+        blob::{self, MAX_BLOB_LEN, decode_velocity_range},
+        nvs::{MAX_PRESETS, PRESET_NAME_LEN},
+    },
+    tasks::gpio::{DrumNote, calibration::CalibrationReport},
+    tasks::nvs::PresetRequest,
+    tasks::telemetry::{HIT_LOG_LEN, HitLog},
+};
+
+pub const CONTROL_SERVICE_UUID: Uuid = uuid!("9E1D0000-6A3B-4C6E-8F2D-2B7C4E5A1F00");
+
+// `CONTROL_SERVICE_UUID`, as a literal: the macro converts any other expression with `.into()`, which
+// clippy finds useless on a `Uuid`.
+#[gatt_service(uuid = "9E1D0000-6A3B-4C6E-8F2D-2B7C4E5A1F00")]
+pub struct ControlService {
+    // Write a `ControlCommand` byte to execute it.
+    #[characteristic(uuid = "9E1D0001-6A3B-4C6E-8F2D-2B7C4E5A1F00", write)]
+    pub command: u8,
+    // `[program, bank MSB, bank LSB]` to select on connection. See `ProgramSelect::encode`.
+    #[characteristic(uuid = "9E1D0002-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub program_select: [u8; 3],
+    // What this firmware supports, for companion apps to adapt to. See `CAPABILITIES`, by its
+    // path as the macro names the characteristic's storage after the field.
+    #[characteristic(uuid = "9E1D0003-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, value = self::CAPABILITIES)]
+    pub capabilities: [u8; CAPABILITIES_LEN],
+    // Global MIDI channel followed by the per-note channel overrides. See `encode_channels`.
+    #[characteristic(uuid = "9E1D0004-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub channels: [u8; CHANNELS_LEN],
+    // Per-note velocity gain in percent, in `DrumNote::ALL` order. See `Config::velocity_gains`.
+    #[characteristic(uuid = "9E1D0005-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub velocity_gains: [u8; DrumNote::COUNT],
+    // Status and results of the last calibration. See `encode_calibration`.
+    #[characteristic(uuid = "9E1D0006-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, value = [0; CALIBRATION_LEN])]
+    pub calibration: [u8; CALIBRATION_LEN],
+    // Advertising transmit power in dBm (`i8`). See `Config::tx_power`.
+    #[characteristic(uuid = "9E1D0007-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub tx_power: i8,
+    // MIDI thru on (1) or off (0). See `Config::midi_thru`.
+    #[characteristic(uuid = "9E1D0008-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub midi_thru: bool,
+    // 0 empty, 1 Reset, 2 All Notes Off. See `Config::initial_midi_event`.
+    #[characteristic(uuid = "9E1D0009-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub initial_midi_event: u8,
+    // `[first, last]` MIDI channels of the MPE-style rotation, both 0xFF for none. See
+    // `Config::mpe_channels`.
+    #[characteristic(uuid = "9E1D000A-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub mpe_channels: [u8; 2],
+    // Hi-hat foot splash window in milliseconds (`u16`). See `Config::hi_hat_splash_window`.
+    #[characteristic(uuid = "9E1D000B-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub hi_hat_splash_window: u16,
+    // Hysteresis of the hi-hat foot splash window in milliseconds (`u16`). See
+    // `Config::hi_hat_splash_hysteresis`.
+    #[characteristic(uuid = "9E1D0013-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub hi_hat_splash_hysteresis: u16,
+    // `[min, max]` velocity of each pad, in `Config::pads` order. See `PadConfig::velocity_range`.
+    #[characteristic(uuid = "9E1D000C-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub velocity_ranges: [u8; VELOCITY_RANGES_LEN],
+    // Note map preset and note numbers. See `NoteMap::encode`.
+    #[characteristic(uuid = "9E1D000D-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub note_map: [u8; 1 + DrumNote::COUNT],
+    // Hit timestamp offset in milliseconds (`i16`). See `Config::timestamp_offset`.
+    #[characteristic(uuid = "9E1D000E-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub timestamp_offset: i16,
+    // Velocity curve preset and points. See `VelocityCurve::encode`.
+    #[characteristic(uuid = "9E1D000F-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub velocity_curve: [u8; 1 + VELOCITY_CURVE_POINTS],
+    // The last hit, decoded for companion apps to show without parsing MIDI. Notified at most
+    // every `LAST_HIT_INTERVAL`. See `encode_last_hit`.
+    #[characteristic(uuid = "9E1D0010-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, notify)]
+    pub last_hit: [u8; LAST_HIT_LEN],
+    // MIDI channel read for control messages, 0xFF for none. See `Config::control_channel`.
+    #[characteristic(uuid = "9E1D0011-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub control_channel: u8,
+    // How long before connecting hits are kept for, in milliseconds (`u16`). See
+    // `Config::pre_connection_window`.
+    #[characteristic(uuid = "9E1D0012-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub pre_connection_window: u16,
+    // Disconnect (1) or keep the connection (0) when the sensors switch off. See
+    // `Config::disconnect_on_sensors_off`.
+    #[characteristic(uuid = "9E1D0014-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub disconnect_on_sensors_off: bool,
+    // `[0, slot, 0...]` to load the config preset in `slot`, or `[1, slot, name...]` to store the
+    // current config in it. See `decode_preset_request`.
+    #[characteristic(uuid = "9E1D0015-6A3B-4C6E-8F2D-2B7C4E5A1F00", write)]
+    pub preset: [u8; PRESET_LEN],
+    // Window identical consecutive MIDI packets are dropped in, in milliseconds (`u16`). See
+    // `Config::duplicate_packet_window`.
+    #[characteristic(uuid = "9E1D0016-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub duplicate_packet_window: u16,
+    // The latest hits registered by the pads. See `encode_hit_log`.
+    #[characteristic(uuid = "9E1D0017-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, value = [0; HIT_LOG_VALUE_LEN])]
+    pub hit_log: [u8; HIT_LOG_VALUE_LEN],
+    // `[Config::max_polyphony, Config::voice_stealing]`, the latter encoded by
+    // `VoiceStealing::encode`.
+    #[characteristic(uuid = "9E1D0018-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub polyphony: [u8; 2],
+    // `Config::advertised_id`.
+    #[characteristic(uuid = "9E1D0019-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub advertised_id: u16,
+    // `Config::stuck_pad_timeout`, in milliseconds.
+    #[characteristic(uuid = "9E1D001A-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, write)]
+    pub stuck_pad_timeout: u16,
+    // The whole config as a blob, see `config::blob`, for backups. Zero-padded past the blob, and
+    // read in parts (ATT Read Blob) past the MTU, all of the snapshot taken when the read starts.
+    #[characteristic(uuid = "9E1D001B-6A3B-4C6E-8F2D-2B7C4E5A1F00", read, value = [0; MAX_BLOB_LEN])]
+    pub config_export: [u8; MAX_BLOB_LEN],
+    // A config blob to restore, written in chunks. See `ConfigWrites::import_chunk`.
+    #[characteristic(uuid = "9E1D001C-6A3B-4C6E-8F2D-2B7C4E5A1F00", write, value = [0; CONFIG_IMPORT_LEN])]
+    pub config_import: [u8; CONFIG_IMPORT_LEN],
+}
+
+const CHANNELS_LEN: usize = 1 + DrumNote::COUNT;
+
+const VELOCITY_RANGES_LEN: usize = PAD_COUNT * 2;
+
+const CALIBRATION_LEN: usize = 1 + PAD_COUNT * 6;
+
+const CAPABILITIES_LEN: usize = 8;
+
+pub const LAST_HIT_LEN: usize = 7;
+
+const PRESET_LEN: usize = 2 + PRESET_NAME_LEN;
+
+const HIT_LOG_VALUE_LEN: usize = 1 + HIT_LOG_LEN * LAST_HIT_LEN;
+
+/// Longest `config_import` write, the most a write carries at the largest ATT MTU the host's
+/// packets allow (247): the offset, then 242 bytes of the blob.
+const CONFIG_IMPORT_LEN: usize = 247 - 3;
+
+/// Least time between two notifications of the `last_hit` characteristic. The hits in between are
+/// coalesced into the latest one: plenty for visual feedback, and the MIDI notifications keep the
+/// link to themselves during fast rolls.
+pub const LAST_HIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Value of the `capabilities` characteristic:
+///
+/// | Byte | Content                                                   |
+/// |------|-----------------------------------------------------------|
+/// | 0    | Layout version of this value, currently 1                 |
+/// | 1    | Number of pads                                            |
+/// | 2..4 | Feature flags (`u16` little-endian), see [`feature_flags`] |
+/// | 4    | Config schema version                                     |
+/// | 5..8 | Firmware version (major, minor, patch)                    |
+///
+/// To stay forward-compatible, readers must ignore unknown feature flags as well as any bytes
+/// past the ones they know. New fields are only ever appended, and a change breaking the existing
+/// ones bumps the layout version.
+const CAPABILITIES: [u8; CAPABILITIES_LEN] = {
+    let flags = feature_flags::compiled_in().to_le_bytes();
+    [
+        1,
+        PAD_COUNT as u8,
+        flags[0],
+        flags[1],
+        CONFIG_VERSION,
+        parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+        parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+        parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+    ]
+};
+
+/// Bits of the feature flags in [`CAPABILITIES`]. Unassigned bits are reserved and always 0.
+pub mod feature_flags {
+    /// Built with the `trace-edges` feature.
+    pub const TRACE_EDGES: u16 = 1 << 0;
+    /// Built with the `running-status` feature.
+    pub const RUNNING_STATUS: u16 = 1 << 1;
+    /// Built with the `batched-note-off` feature.
+    pub const BATCHED_NOTE_OFF: u16 = 1 << 2;
+
+    pub(super) const fn compiled_in() -> u16 {
+        let mut flags = 0;
+        if cfg!(feature = "trace-edges") {
+            flags |= TRACE_EDGES;
+        }
+        if cfg!(feature = "running-status") {
+            flags |= RUNNING_STATUS;
+        }
+        if cfg!(feature = "batched-note-off") {
+            flags |= BATCHED_NOTE_OFF;
+        }
+        flags
+    }
+}
+
+/// Length of the [`advertised_service_data`].
+pub const SERVICE_DATA_LEN: usize = 6;
+
+/// The service data advertised under [`CONTROL_SERVICE_UUID`] (AD type 0x21, Service Data -
+/// 128-bit UUID), for companion apps to recognize the kit among the BLE MIDI devices around
+/// without connecting:
+///
+/// | Byte | Content                                         |
+/// |------|-------------------------------------------------|
+/// | 0    | Layout version of this value, currently 1       |
+/// | 1..4 | Firmware version (major, minor, patch)          |
+/// | 4..6 | `Config::advertised_id` (`u16` little-endian)   |
+///
+/// Extended like the `capabilities` value: readers must ignore any bytes past the ones they know.
+/// In the scan response rather than the advertising data, which the MIDI service UUID and the
+/// name already fill, so only active scans see it.
+pub fn advertised_service_data(advertised_id: u16) -> [u8; SERVICE_DATA_LEN] {
+    let id = advertised_id.to_le_bytes();
+    [
+        1,
+        CAPABILITIES[5],
+        CAPABILITIES[6],
+        CAPABILITIES[7],
+        id[0],
+        id[1],
+    ]
+}
+
+const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum ControlCommand {
+    /// Drop the current connection and go back to advertising.
+    Disconnect = 0x01,
+    /// Re-arm the pads with their current config, keeping the connection. See
+    /// [`ReloadPadsSignal`](crate::tasks::gpio::ReloadPadsSignal) for which settings need it.
+    ReloadPads = 0x02,
+    /// Measure the idle noise of the pads, reported by the `calibration` characteristic. The pads
+    /// must be left alone for
+    /// [`CALIBRATION_DURATION`](crate::tasks::gpio::calibration::CALIBRATION_DURATION).
+    Calibrate = 0x03,
+    /// Stage the config writes that follow rather than applying each, see [`ConfigWrites`].
+    BeginTransaction = 0x04,
+    /// Apply the config writes staged since `BeginTransaction` at once.
+    ApplyTransaction = 0x05,
+    /// Drop the config writes staged since `BeginTransaction`.
+    AbortTransaction = 0x06,
+    /// Load the next stored config preset, like the preset button. See
+    /// [`PresetRequest::Next`].
+    NextPreset = 0x07,
+}
+
+impl ControlCommand {
+    /// Control Change number that runs a command when sent on the
+    /// [control channel](Config::control_channel), with the command's byte as the value, e.g.
+    /// `B0 66 03` to calibrate with the control channel set to 0. One of the CCs left undefined by
+    /// the MIDI spec, so no host sends it meaning something else.
+    pub const MIDI_CONTROL: u8 = 102;
+
+    /// The command `msg` runs when read on `control_channel`, `Some(Err)` with the value if it's
+    /// a command CC with an unknown command, or `None` if it's not a command CC on that channel.
+    pub fn from_midi(msg: MidiMessage, control_channel: u8) -> Option<Result<Self, u8>> {
+        match msg {
+            MidiMessage::ControlChange(channel, control, value)
+                if channel == Channel::new(control_channel)
+                    && control == Control::new(Self::MIDI_CONTROL) =>
+            {
+                Some(Self::try_from(u8::from(value)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<u8> for ControlCommand {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::Disconnect),
+            0x02 => Ok(Self::ReloadPads),
+            0x03 => Ok(Self::Calibrate),
+            0x04 => Ok(Self::BeginTransaction),
+            0x05 => Ok(Self::ApplyTransaction),
+            0x06 => Ok(Self::AbortTransaction),
+            0x07 => Ok(Self::NextPreset),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Where the config written over GATT goes: into the [`SharedConfig`] right away, or within a
+/// transaction into a staged copy of it applied as a whole.
+///
+/// Writing the config one characteristic at a time goes through all the states in between (e.g.
+/// a new note map with the old channels), playing with and saving each one. Instead, a client can:
+/// 1. write [`BeginTransaction`](ControlCommand::BeginTransaction), which stages a copy of the
+///    current config;
+/// 2. write the config characteristics, each checked like always, but only changing the copy;
+/// 3. write [`ApplyTransaction`](ControlCommand::ApplyTransaction), which replaces the config with
+///    the copy at once. It's saved right away, and the pads are re-armed if a setting needing it
+///    changed.
+///
+/// A write rejected within a transaction leaves the copy as it was, so the transaction can go on.
+/// [`AbortTransaction`](ControlCommand::AbortTransaction) drops the copy instead, as does a
+/// disconnect or starting over with another `BeginTransaction`, and the config characteristics
+/// read the current config again. Changes made to it meanwhile by other means (e.g. the channel
+/// button) are overwritten when a transaction is applied.
+///
+/// A whole config can also be restored from a backup, see [`import_chunk`](Self::import_chunk),
+/// which goes into the staged copy within a transaction like any other write.
+pub struct ConfigWrites<'a> {
+    config: &'a SharedConfig,
+    staged: Option<Config>,
+    /// Whether a blob was imported into the staged copy, which then needs re-arming the pads
+    /// once applied like any import.
+    staged_import: bool,
+    /// Of the config blob being imported, the chunks received so far.
+    imported: heapless::Vec<u8, MAX_BLOB_LEN>,
+}
+
+impl<'a> ConfigWrites<'a> {
+    pub fn new(config: &'a SharedConfig) -> Self {
+        Self {
+            config,
+            staged: None,
+            staged_import: false,
+            imported: heapless::Vec::new(),
+        }
+    }
+
+    /// Whether a transaction is open.
+    pub fn staging(&self) -> bool {
+        self.staged.is_some()
+    }
+
+    /// Read the current config, not the staged one.
+    pub fn read<R>(&self, f: impl FnOnce(&Config) -> R) -> R {
+        self.config.read(f)
+    }
+
+    /// Change the staged config within a transaction, or else the current one.
+    pub fn update(&mut self, f: impl FnOnce(&mut Config)) {
+        match &mut self.staged {
+            Some(staged) => f(staged),
+            None => self.config.update(f),
+        }
+    }
+
+    /// Start a transaction, and return whether one already open was dropped for it.
+    pub fn begin(&mut self) -> bool {
+        self.staged_import = false;
+        self.staged.replace(self.config.read(|c| *c)).is_some()
+    }
+
+    /// Apply the staged config, and return whether the pads need re-arming for it, or `None` if
+    /// no transaction is open.
+    pub fn apply(&mut self) -> Option<bool> {
+        let staged = self.staged.take()?;
+        let imported = core::mem::take(&mut self.staged_import);
+        let rearm = self.config.update(|c| {
+            let rearm = imported
+                || c.hi_hat_splash_window != staged.hi_hat_splash_window
+                || c.hi_hat_splash_hysteresis != staged.hi_hat_splash_hysteresis;
+            *c = staged;
+            rearm
+        });
+        self.config.request_save();
+        Some(rearm)
+    }
+
+    /// Take a `config_import` write: `[offset, chunk...]`, the next chunk of a config blob (as
+    /// read from `config_export`) starting at the `offset` (`u16` little-endian) into it. The
+    /// chunks must be written in order, each at the offset where the previous one ended, and one
+    /// at offset 0 starts over, so a blob of any length up to [`MAX_BLOB_LEN`] goes over in
+    /// MTU-sized writes.
+    ///
+    /// Once the blob is complete (by the length in the header), it's checked like the one stored
+    /// in flash and replaces the config as a whole, returning `Some(true)`, as the pads then need
+    /// re-arming, or replaces the staged copy within a transaction and returns `Some(false)`, the
+    /// re-arming then left to [`apply`](Self::apply).
+    /// `None` while incomplete. A blob of another config version, malformed, or out of range is
+    /// rejected (`VALUE_NOT_ALLOWED`) on its last chunk, or on the header for the version,
+    /// leaving the config untouched; the import then has to start over.
+    pub fn import_chunk(&mut self, data: &[u8]) -> Result<Option<bool>, AttErrorCode> {
+        let (offset, chunk) = data
+            .split_first_chunk::<2>()
+            .ok_or(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        let offset = usize::from(u16::from_le_bytes(*offset));
+        if offset == 0 {
+            self.imported.clear();
+        }
+        if offset != self.imported.len() {
+            warn!(
+                "[gatt] config import chunk at {}, expected {}",
+                offset,
+                self.imported.len()
+            );
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        if self.imported.extend_from_slice(chunk).is_err() {
+            self.imported.clear();
+            return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+
+        if self.imported.len() < blob::HEADER_LEN {
+            return Ok(None);
+        }
+        let result = match blob::header(&self.imported) {
+            Some((CONFIG_VERSION, len)) if self.imported.len() < len => return Ok(None),
+            Some((CONFIG_VERSION, _)) => blob::decode(&self.imported).ok_or_else(|| {
+                warn!("[gatt] imported config blob invalid");
+                AttErrorCode::VALUE_NOT_ALLOWED
+            }),
+            Some((version, _)) => {
+                warn!(
+                    "[gatt] imported config blob of version {}, not {}",
+                    version, CONFIG_VERSION
+                );
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            }
+            None => {
+                warn!("[gatt] imported data not a config blob");
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            }
+        };
+        self.imported.clear();
+
+        let imported = result?;
+        Ok(Some(match &mut self.staged {
+            Some(staged) => {
+                *staged = imported;
+                self.staged_import = true;
+                false
+            }
+            None => {
+                self.config.update(|c| *c = imported);
+                self.config.request_save();
+                true
+            }
+        }))
+    }
+
+    /// Drop the staged config, and return whether a transaction was open.
+    pub fn abort(&mut self) -> bool {
+        self.staged_import = false;
+        self.staged.take().is_some()
+    }
+}
+
+/// Signaled to make the connection loop drop the current connection.
+pub type ForceDisconnectSignal = Signal<NoopRawMutex, ()>;
+
+/// Decode a `program_select` characteristic value, or `Err` if it's malformed.
+pub fn decode_program_select(data: &[u8]) -> Result<Option<ProgramSelect>, AttErrorCode> {
+    let bytes = data
+        .try_into()
+        .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+    ProgramSelect::decode(bytes).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)
+}
+
+/// Encode the `channels` characteristic value: the global MIDI channel followed by the per-note
+/// overrides in [`DrumNote::ALL`] order, `0xFF` meaning no override.
+pub fn encode_channels(config: &Config) -> [u8; CHANNELS_LEN] {
+    let mut value = [0xFF; CHANNELS_LEN];
+    value[0] = config.midi_channel;
+    for (v, channel) in value[1..].iter_mut().zip(config.note_channels) {
+        *v = channel.unwrap_or(0xFF);
+    }
+    value
+}
+
+/// Encode the `velocity_ranges` characteristic value: the `[min, max]` velocity of each pad in
+/// `Config::pads` order.
+pub fn encode_velocity_ranges(config: &Config) -> [u8; VELOCITY_RANGES_LEN] {
+    let mut value = [0; VELOCITY_RANGES_LEN];
+    for (chunk, pad) in value.as_chunks_mut::<2>().0.iter_mut().zip(&config.pads) {
+        *chunk = [pad.velocity_range.0, pad.velocity_range.1];
+    }
+    value
+}
+
+/// Decode a `velocity_ranges` characteristic value, or `Err` if it's malformed.
+pub fn decode_velocity_ranges(data: &[u8]) -> Result<[(u8, u8); PAD_COUNT], AttErrorCode> {
+    if data.len() != VELOCITY_RANGES_LEN {
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    }
+    let mut ranges = [(1, 127); PAD_COUNT];
+    for (range, &chunk) in ranges.iter_mut().zip(data.as_chunks::<2>().0) {
+        *range = decode_velocity_range(chunk).ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+    }
+    Ok(ranges)
+}
+
+/// Encode the `calibration` characteristic value: the
+/// [`CalibrationStatus`](crate::tasks::gpio::calibration::CalibrationStatus) byte, then for each
+/// pad in `Config::pads` order its number of glitches (`u16`) and longest glitch in microseconds
+/// (`u32`), both little-endian. The results are those of the last calibration that completed.
+pub fn encode_calibration((status, noise): CalibrationReport) -> [u8; CALIBRATION_LEN] {
+    let mut value = [0; CALIBRATION_LEN];
+    value[0] = status as u8;
+    for (chunk, pad) in value[1..].as_chunks_mut::<6>().0.iter_mut().zip(noise) {
+        let longest_glitch = u32::try_from(pad.longest_glitch.as_micros()).unwrap_or(u32::MAX);
+        chunk[..2].copy_from_slice(&pad.glitches.to_le_bytes());
+        chunk[2..].copy_from_slice(&longest_glitch.to_le_bytes());
+    }
+    value
+}
+
+/// Encode the `last_hit` characteristic value:
+///
+/// | Byte | Content                                                                     |
+/// |------|-----------------------------------------------------------------------------|
+/// | 0    | Index of the hit pad in `Config::pads`                                      |
+/// | 1    | Index of the played drum in [`DrumNote::ALL`]                               |
+/// | 2    | Velocity of the Note On sent, after the gains and curve                     |
+/// | 3..7 | Time of the hit in milliseconds since boot (`u32` little-endian, wrapping)  |
+pub fn encode_last_hit(
+    pad: usize,
+    note: DrumNote,
+    velocity: u8,
+    timestamp: Instant,
+) -> [u8; LAST_HIT_LEN] {
+    let mut value = [0; LAST_HIT_LEN];
+    value[0] = pad as u8;
+    value[1] = note.index() as u8;
+    value[2] = velocity;
+    value[3..].copy_from_slice(&(timestamp.as_millis() as u32).to_le_bytes());
+    value
+}
+
+/// Encode the `hit_log` characteristic value: the number of hits kept (up to [`HIT_LOG_LEN`]), then
+/// each hit oldest first, in the 7 bytes of [`encode_last_hit`] but for the velocity, which is the
+/// one sensed rather than the one sent. The bytes past the hits kept are zero.
+///
+/// Longer than a notification at the default ATT MTU, so clients read it in parts (ATT Read Blob)
+/// unless a bigger MTU was negotiated.
+pub fn encode_hit_log(log: &HitLog) -> [u8; HIT_LOG_VALUE_LEN] {
+    let mut value = [0; HIT_LOG_VALUE_LEN];
+    let mut count = 0;
+    let entries = value[1..].as_chunks_mut::<LAST_HIT_LEN>().0;
+    for (hit, entry) in log.hits().zip(entries) {
+        let timestamp = Instant::from_millis(hit.millis.into());
+        *entry = encode_last_hit(hit.pad.into(), hit.note, hit.velocity, timestamp);
+        count += 1;
+    }
+    value[0] = count;
+    value
+}
+
+/// Decode a `preset` characteristic value: `[0, slot, ...]` loads the preset in `slot`, with the
+/// name bytes ignored, and `[1, slot, name...]` stores the current config in it under `name`
+/// (zero-padded to [`PRESET_NAME_LEN`] bytes). `Err` if it's malformed or `slot` isn't below
+/// [`MAX_PRESETS`].
+pub fn decode_preset_request(data: &[u8]) -> Result<PresetRequest, AttErrorCode> {
+    let Some(([op, slot], name)) = data.split_first_chunk::<2>() else {
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    };
+    let name = name
+        .try_into()
+        .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+    if *slot >= MAX_PRESETS {
+        return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+    }
+    match op {
+        0 => Ok(PresetRequest::Load(*slot)),
+        1 => Ok(PresetRequest::Store(*slot, name)),
+        _ => Err(AttErrorCode::VALUE_NOT_ALLOWED),
+    }
+}
+
+/// Decode a `channels` characteristic value into `(midi_channel, note_channels)`, or `Err` if
+/// it's malformed or any channel is out of the 0..=15 range.
+pub fn decode_channels(data: &[u8]) -> Result<(u8, [Option<u8>; DrumNote::COUNT]), AttErrorCode> {
+    let Some((&midi_channel, overrides)) = data.split_first() else {
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    };
+    if overrides.len() != DrumNote::COUNT {
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    }
+    if midi_channel > 15 {
+        return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+    }
+
+    let mut note_channels = [None; DrumNote::COUNT];
+    for (channel, &v) in note_channels.iter_mut().zip(overrides) {
+        *channel = match v {
+            0..=15 => Some(v),
+            0xFF => None,
+            _ => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+        };
+    }
+    Ok((midi_channel, note_channels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_round_trip_through_their_encoding() {
+        let mut config = Config {
+            midi_channel: 3,
+            ..Config::default()
+        };
+        config.note_channels[DrumNote::Snare.index()] = Some(15);
+        config.note_channels[DrumNote::BassDrum.index()] = Some(0);
+        let value = encode_channels(&config);
+        assert_eq!(value[0], 3);
+        assert_eq!(value[1 + DrumNote::Snare.index()], 15);
+        assert_eq!(value[1 + DrumNote::CrashCymbal1.index()], 0xFF);
+        let (midi_channel, note_channels) = decode_channels(&value).unwrap();
+        assert_eq!(midi_channel, 3);
+        assert_eq!(note_channels, config.note_channels);
+    }
+
+    #[test]
+    fn malformed_channels_are_refused() {
+        let value = encode_channels(&Config::default());
+        let mut global_past_15 = value;
+        global_past_15[0] = 16;
+        let mut override_past_15 = value;
+        override_past_15[1] = 16;
+        for malformed in [&global_past_15[..], &override_past_15[..]] {
+            assert!(matches!(
+                decode_channels(malformed),
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            ));
+        }
+        for malformed in [&[][..], &value[..1], &value[..value.len() - 1]] {
+            assert!(matches!(
+                decode_channels(malformed),
+                Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+            ));
+        }
+    }
+
+    /// A `preset` value of `op` on `slot`, named `name`.
+    fn preset_request(op: u8, slot: u8, name: &[u8]) -> [u8; 2 + PRESET_NAME_LEN] {
+        let mut value = [0; 2 + PRESET_NAME_LEN];
+        value[..2].copy_from_slice(&[op, slot]);
+        value[2..][..name.len()].copy_from_slice(name);
+        value
+    }
+
+    #[test]
+    fn preset_requests_decode_to_a_load_or_a_store() {
+        let load = preset_request(0, 3, b"ignored");
+        assert!(matches!(
+            decode_preset_request(&load),
+            Ok(PresetRequest::Load(3))
+        ));
+        let store = preset_request(1, 0, b"Rock kit");
+        let Ok(PresetRequest::Store(0, name)) = decode_preset_request(&store) else {
+            panic!("not a store in slot 0");
+        };
+        assert_eq!(name, store[2..]);
+    }
+
+    #[test]
+    fn malformed_preset_requests_are_refused() {
+        for malformed in [
+            preset_request(0, MAX_PRESETS, b""),
+            preset_request(1, MAX_PRESETS, b"Rock kit"),
+            preset_request(2, 0, b""),
+        ] {
+            assert!(matches!(
+                decode_preset_request(&malformed),
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            ));
+        }
+        let value = preset_request(1, 0, b"Rock kit");
+        for malformed in [&value[..1], &value[..2], &value[..value.len() - 1]] {
+            assert!(matches!(
+                decode_preset_request(malformed),
+                Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+            ));
+        }
+    }
+
+    /// A `config_import` write of the whole blob of `config`, in a single chunk.
+    fn import_write(config: &Config) -> heapless::Vec<u8, { 2 + MAX_BLOB_LEN }> {
+        let mut blob = [0; MAX_BLOB_LEN];
+        let len = blob::encode(config, &mut blob).unwrap();
+        let mut write = heapless::Vec::new();
+        write.extend_from_slice(&[0, 0]).unwrap();
+        write.extend_from_slice(&blob[..len]).unwrap();
+        write
+    }
+
+    #[test]
+    fn import_rearms_the_pads() {
+        let config = SharedConfig::new(Config::default());
+        let mut writes = ConfigWrites::new(&config);
+        assert!(matches!(
+            writes.import_chunk(&import_write(&Config::default())),
+            Ok(Some(true))
+        ));
+    }
+
+    #[test]
+    fn import_within_a_transaction_rearms_the_pads_once_applied() {
+        let config = SharedConfig::new(Config::default());
+        let mut writes = ConfigWrites::new(&config);
+        let mut imported = Config::default();
+        imported.pads[0].note = DrumNote::CrashCymbal1;
+        writes.begin();
+        assert!(matches!(
+            writes.import_chunk(&import_write(&imported)),
+            Ok(Some(false))
+        ));
+        assert!(config.read(|c| c.pads[0].note != DrumNote::CrashCymbal1));
+        assert_eq!(writes.apply(), Some(true));
+        assert!(config.read(|c| c.pads[0].note == DrumNote::CrashCymbal1));
+        // Not the next transaction, without an import.
+        writes.begin();
+        assert_eq!(writes.apply(), Some(false));
+    }
+
+    #[test]
+    fn aborted_import_is_forgotten() {
+        let config = SharedConfig::new(Config::default());
+        let mut writes = ConfigWrites::new(&config);
+        writes.begin();
+        assert!(
+            writes
+                .import_chunk(&import_write(&Config::default()))
+                .is_ok()
+        );
+        assert!(writes.abort());
+        writes.begin();
+        assert_eq!(writes.apply(), Some(false));
+    }
+}
@@ -0,0 +1,306 @@
+//! A BLE controller for the host tests, scripted from the central's side: it answers the HCI
+//! commands of the host stack like a controller with a single link would, and connects,
+//! subscribes and disconnects when the test says so.
+
+use core::{cell::RefCell, future::poll_fn, task::Poll};
+use std::{collections::VecDeque, rc::Rc, vec::Vec};
+
+use bt_hci::{
+    ControllerToHostPacket, FromHciBytesError, HostToControllerPacket, PacketKind,
+    controller::ExternalController, transport::Transport,
+};
+
+/// Handle of the one connection there ever is.
+const CONN_HANDLE: u16 = 0x0001;
+/// LE ACL data packets the controller takes at once.
+const ACL_PACKETS: u8 = 8;
+
+/// Controllers (the transport to them, really) don't fail here, but the stack wants an error type
+/// it can report.
+#[derive(Debug, defmt::Format)]
+pub struct MockError;
+
+impl embedded_io::Error for MockError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+impl From<FromHciBytesError> for MockError {
+    fn from(_: FromHciBytesError) -> Self {
+        Self
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// HCI packets waiting to be read by the host, each of its kind.
+    to_host: VecDeque<(PacketKind, Vec<u8>)>,
+    advertising: bool,
+    /// The advertising TX power last set by the host, in dBm.
+    advertising_tx_power: Option<i8>,
+    connected: bool,
+    /// Attribute handle and value of each notification and indication sent by the host.
+    notified: Vec<(u16, Vec<u8>)>,
+    /// The other ATT PDUs sent by the host, e.g. the responses to the central's requests.
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl State {
+    fn event(&mut self, code: u8, params: &[u8]) {
+        let mut packet = Vec::from([code, params.len() as u8]);
+        packet.extend_from_slice(params);
+        self.to_host.push_back((PacketKind::Event, packet));
+    }
+
+    fn command_complete(&mut self, opcode: u16, return_params: &[u8]) {
+        let [low, high] = opcode.to_le_bytes();
+        let mut params = Vec::from([1, low, high, 0]);
+        params.extend_from_slice(return_params);
+        self.event(0x0E, &params);
+    }
+
+    fn command_status(&mut self, opcode: u16) {
+        let [low, high] = opcode.to_le_bytes();
+        self.event(0x0F, &[0, 1, low, high]);
+    }
+
+    fn disconnection_complete(&mut self, reason: u8) {
+        let [low, high] = CONN_HANDLE.to_le_bytes();
+        self.event(0x05, &[0, low, high, reason]);
+        self.connected = false;
+    }
+
+    /// `pdu` sent by the central over the ATT channel.
+    fn att(&mut self, pdu: &[u8]) {
+        let [handle_low, handle_high] = CONN_HANDLE.to_le_bytes();
+        let [len_low, len_high] = (pdu.len() as u16 + 4).to_le_bytes();
+        let [pdu_len_low, pdu_len_high] = (pdu.len() as u16).to_le_bytes();
+        // First automatically flushable packet of the L2CAP PDU, on the ATT channel.
+        let mut packet = Vec::from([
+            handle_low,
+            handle_high | 0x20,
+            len_low,
+            len_high,
+            pdu_len_low,
+            pdu_len_high,
+            0x04,
+            0x00,
+        ]);
+        packet.extend_from_slice(pdu);
+        self.to_host.push_back((PacketKind::AclData, packet));
+    }
+
+    fn command(&mut self, opcode: u16, params: &[u8]) {
+        match opcode {
+            // Reset, Set Event Mask (and Page 2), LE Set Event Mask, Host Buffer Size, LE Set
+            // Advertising Parameters, Data and Scan Response Data, and their extended
+            // advertising counterparts.
+            0x0C03 | 0x0C01 | 0x0C63 | 0x2001 | 0x0C33 | 0x2006 | 0x2008 | 0x2009 | 0x2037
+            | 0x2038 => self.command_complete(opcode, &[]),
+            // LE Read Number of Supported Advertising Sets.
+            0x203B => self.command_complete(opcode, &[1]),
+            // LE Set Extended Advertising Parameters, selecting the TX power asked for.
+            0x2036 => {
+                let tx_power = params[19];
+                self.advertising_tx_power = Some(tx_power as i8);
+                self.command_complete(opcode, &[tx_power]);
+            }
+            // LE Set Extended Advertising Enable, of the one set there is.
+            0x2039 => {
+                self.advertising = params[0] != 0;
+                self.command_complete(opcode, &[]);
+            }
+            // LE Read Filter Accept List Size.
+            0x200F => self.command_complete(opcode, &[8]),
+            // LE Read Buffer Size: 251-byte packets.
+            0x2002 => self.command_complete(opcode, &[251, 0, ACL_PACKETS]),
+            // Read BD_ADDR.
+            0x1009 => self.command_complete(opcode, &[0; 6]),
+            // LE Set Advertising Enable.
+            0x200A => {
+                self.advertising = params[0] != 0;
+                self.command_complete(opcode, &[]);
+            }
+            // Disconnect.
+            0x0406 => {
+                self.command_status(opcode);
+                if self.connected {
+                    // Connection terminated by the local host.
+                    self.disconnection_complete(0x16);
+                }
+            }
+            // LE Connection Update.
+            0x2013 => self.command_status(opcode),
+            _ => panic!("unexpected HCI command {opcode:#06x}"),
+        }
+    }
+
+    fn acl(&mut self, packet: &[u8]) {
+        let [handle_low, handle_high] = CONN_HANDLE.to_le_bytes();
+        // Number Of Completed Packets, giving the host its credit back right away.
+        self.event(0x13, &[1, handle_low, handle_high, 1, 0]);
+
+        // Past the ACL and L2CAP headers, of a PDU that's never fragmented with the default MTU.
+        let pdu = &packet[8..];
+        match pdu[0] {
+            // Handle Value Notification.
+            0x1B => self
+                .notified
+                .push((u16::from_le_bytes([pdu[1], pdu[2]]), Vec::from(&pdu[3..]))),
+            // Handle Value Indication, confirmed.
+            0x1D => {
+                self.notified
+                    .push((u16::from_le_bytes([pdu[1], pdu[2]]), Vec::from(&pdu[3..])));
+                self.att(&[0x1E]);
+            }
+            _ => self.responses.push_back(Vec::from(pdu)),
+        }
+    }
+}
+
+/// The transport of the [`MockController`], to the [`MockCentral`] scripting it.
+pub struct MockTransport(Rc<RefCell<State>>);
+
+impl embedded_io::ErrorType for MockTransport {
+    type Error = MockError;
+}
+
+impl Transport for MockTransport {
+    async fn read<'a>(&self, rx: &'a mut [u8]) -> Result<ControllerToHostPacket<'a>, MockError> {
+        let (kind, packet) = poll_fn(|_| match self.0.borrow_mut().to_host.pop_front() {
+            Some(packet) => Poll::Ready(packet),
+            None => Poll::Pending,
+        })
+        .await;
+        rx[..packet.len()].copy_from_slice(&packet);
+        let (packet, _) =
+            ControllerToHostPacket::from_hci_bytes_with_kind(kind, &rx[..packet.len()])?;
+        Ok(packet)
+    }
+
+    async fn write<T: HostToControllerPacket>(&self, packet: &T) -> Result<(), MockError> {
+        let mut bytes = [0; 512];
+        let len = packet.size();
+        packet
+            .write_hci(&mut bytes[..])
+            .unwrap_or_else(|_| panic!("HCI packet of {len} bytes"));
+        let bytes = &bytes[..len];
+        let mut state = self.0.borrow_mut();
+        match T::KIND {
+            PacketKind::Cmd => state.command(u16::from_le_bytes([bytes[0], bytes[1]]), &bytes[3..]),
+            PacketKind::AclData => state.acl(bytes),
+            kind => panic!("unexpected HCI packet of kind {}", kind as u8),
+        }
+        Ok(())
+    }
+}
+
+/// What the stack under test is given as its controller.
+pub type MockController = ExternalController<MockTransport, 4>;
+
+/// The central at the other end of the [`MockController`], for the test to script the link with.
+pub struct MockCentral(Rc<RefCell<State>>);
+
+impl MockCentral {
+    /// The central, and the controller to give the stack it connects to.
+    pub fn new() -> (Self, MockController) {
+        let state = Rc::new(RefCell::new(State::default()));
+        (
+            Self(state.clone()),
+            ExternalController::new(MockTransport(state)),
+        )
+    }
+
+    pub fn is_advertising(&self) -> bool {
+        self.0.borrow().advertising
+    }
+
+    pub fn advertising_tx_power(&self) -> Option<i8> {
+        self.0.borrow().advertising_tx_power
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.0.borrow().connected
+    }
+
+    /// A central connects to the advertising peripheral, which stops advertising.
+    pub fn connect(&self) {
+        let mut state = self.0.borrow_mut();
+        assert!(state.advertising, "connecting while not advertising");
+        let [low, high] = CONN_HANDLE.to_le_bytes();
+        // LE Connection Complete as the peripheral, every 7.5 ms with a 4 s supervision timeout.
+        state.event(
+            0x3E,
+            &[
+                0x01, 0, low, high, 0x01, 0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 6, 0, 0, 0, 0x90,
+                0x01, 0,
+            ],
+        );
+        // LE Advertising Set Terminated by the connection, after a single advertising event.
+        state.event(0x3E, &[0x12, 0, 0, low, high, 1]);
+        state.advertising = false;
+        state.connected = true;
+    }
+
+    /// The response of the peripheral to the `request` of the central.
+    async fn request(&self, request: &[u8]) -> Vec<u8> {
+        self.0.borrow_mut().att(request);
+        poll_fn(|_| match self.0.borrow_mut().responses.pop_front() {
+            Some(response) => Poll::Ready(response),
+            None => Poll::Pending,
+        })
+        .await
+    }
+
+    /// The central writes `value` to the attribute at `handle` (a Write Request).
+    pub async fn write(&self, handle: u16, value: &[u8]) {
+        let [low, high] = handle.to_le_bytes();
+        let mut request = Vec::from([0x12, low, high]);
+        request.extend_from_slice(value);
+        let response = self.request(&request).await;
+        assert_eq!(response, [0x13], "write of {handle:#06x} failed");
+    }
+
+    /// Handle of the value of the characteristic of 128-bit `uuid` (in the little endian order
+    /// of ATT), found the way a central does, by reading the characteristic declarations in turn.
+    pub async fn discover(&self, uuid: &[u8; 16]) -> u16 {
+        let mut start: u16 = 0x0001;
+        loop {
+            let [start_low, start_high] = start.to_le_bytes();
+            // Read By Type Request of the characteristic declarations (0x2803), up to the end.
+            let response = self
+                .request(&[0x08, start_low, start_high, 0xFF, 0xFF, 0x03, 0x28])
+                .await;
+            assert_eq!(response[0], 0x09, "characteristic not found");
+            // Declaration handle, properties, value handle and UUID for each, just one of the
+            // declarations of a 128-bit UUID fitting in the default MTU.
+            for declaration in response[2..].chunks(usize::from(response[1])) {
+                let value_handle = u16::from_le_bytes([declaration[3], declaration[4]]);
+                if declaration[5..] == uuid[..] {
+                    return value_handle;
+                }
+                start = value_handle + 1;
+            }
+        }
+    }
+
+    /// The central goes away, e.g. out of range.
+    pub fn disconnect(&self) {
+        let mut state = self.0.borrow_mut();
+        assert!(state.connected, "disconnecting while not connected");
+        // Remote user terminated connection.
+        state.disconnection_complete(0x13);
+    }
+
+    /// The values notified or indicated to the attribute at `handle` since the last call.
+    pub fn take_notified(&self, handle: u16) -> Vec<Vec<u8>> {
+        let mut state = self.0.borrow_mut();
+        let (taken, kept) = state
+            .notified
+            .drain(..)
+            .partition(|&(notified, _)| notified == handle);
+        state.notified = kept;
+        taken.into_iter().map(|(_, value)| value).collect()
+    }
+}
@@ -0,0 +1,88 @@
+use defmt::{info, warn};
+use embassy_time::Duration;
+use esp_hal::gpio::{AnyPin, Input, InputConfig, Pull};
+use midi_types::Channel;
+
+use crate::config::SharedConfig;
+use crate::midi::{note_channels_mask, panic_messages};
+use crate::tasks::ble::PriorityMessagesSender;
+use crate::tasks::gpio::WaitForStable;
+use crate::tasks::led::{LedPattern, LedPatternSender};
+#[cfg(feature = "preset-button")]
+use crate::tasks::nvs::{PresetRequest, PresetRequestsSender};
+
+/// Long enough to ride out the bounce of a tactile switch.
+const BUTTON_STABLE_DURATION: Duration = Duration::from_millis(20);
+
+/// Cycle the global MIDI channel on each press of the (active low) config button, for setups
+/// without a phone app.
+///
+/// The status LED flashes the new channel number as shown by most DAWs, i.e. 1 to 16. The change
+/// goes through the shared config, so it's persisted like any other.
+#[embassy_executor::task]
+pub async fn channel_button_task(
+    pin: AnyPin<'static>,
+    config: &'static SharedConfig,
+    status_led: LedPatternSender<'static>,
+) {
+    let mut button = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+    loop {
+        button.wait_for_stable_high(BUTTON_STABLE_DURATION).await;
+        button.wait_for_stable_low(BUTTON_STABLE_DURATION).await;
+
+        let channel = config.update(|c| {
+            c.midi_channel = (c.midi_channel + 1) % 16;
+            c.midi_channel
+        });
+        info!("[button] MIDI channel set to {}", channel + 1);
+        status_led.send(LedPattern::Flash(channel + 1)).await;
+    }
+}
+
+/// Silence everything on each press of the (active low) panic button, for when samples hang: All
+/// Sound Off and All Notes Off on every channel the notes can go out on.
+///
+/// They take the priority path, ahead of any buffered hits. Pressed while disconnected, they wait
+/// for the next connection, which they harmlessly start with.
+#[embassy_executor::task]
+pub async fn panic_button_task(
+    pin: AnyPin<'static>,
+    config: &'static SharedConfig,
+    priority_messages: PriorityMessagesSender<'static>,
+) {
+    let mut button = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+    loop {
+        button.wait_for_stable_high(BUTTON_STABLE_DURATION).await;
+        button.wait_for_stable_low(BUTTON_STABLE_DURATION).await;
+
+        let channels = config.read(note_channels_mask);
+        warn!("[button] panic, silencing channels {=u16:#06x}", channels);
+        for channel in (0..16).filter(|channel| channels & (1 << channel) != 0) {
+            for msg in panic_messages(Channel::new(channel)) {
+                priority_messages.send(msg).await;
+            }
+        }
+    }
+}
+
+/// Load the next stored config preset on each press of the (active low) preset button, with the
+/// `preset-button` feature. See [`persist_config_task`](crate::tasks::nvs::persist_config_task)
+/// for how it's applied and shown.
+#[cfg(feature = "preset-button")]
+#[embassy_executor::task]
+pub async fn preset_button_task(
+    pin: AnyPin<'static>,
+    preset_requests: PresetRequestsSender<'static>,
+) {
+    let mut button = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+    loop {
+        button.wait_for_stable_high(BUTTON_STABLE_DURATION).await;
+        button.wait_for_stable_low(BUTTON_STABLE_DURATION).await;
+
+        info!("[button] next preset");
+        preset_requests.send(PresetRequest::Next).await;
+    }
+}
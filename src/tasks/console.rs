@@ -0,0 +1,100 @@
+//! Line-based debug console over the USB Serial/JTAG port, for development builds with the
+//! `debug-console` feature.
+//!
+//! Commands are read from the port one per line (e.g. typed into `espflash monitor`), and answered
+//! through the `defmt` log, which owns the transmitting side of the same port:
+//!
+//! | Command      | Action                                                                   |
+//! |--------------|--------------------------------------------------------------------------|
+//! | `help`       | List the commands                                                        |
+//! | `config`     | Dump the current config                                                  |
+//! | `heap`       | Show the heap usage                                                      |
+//! | `disconnect` | Drop the BLE connection, like the `Disconnect` control command           |
+//! | `reload`     | Re-arm the pads, like the `ReloadPads` control command                   |
+//! | `selftest`   | Measure the idle noise of the pads, like the `Calibrate` control command |
+//! | `report`     | Show the results of the last `selftest`                                  |
+//!
+//! The actions go through the same signals as the
+//! [`ControlCommand`](crate::tasks::ble::control::ControlCommand)s written over GATT.
+
+use defmt::{info, warn};
+use embedded_io_async::Read;
+use esp_hal::{Async, usb_serial_jtag::UsbSerialJtag};
+use heapless::Vec;
+
+use crate::config::SharedConfig;
+use crate::tasks::ble::control::ForceDisconnectSignal;
+use crate::tasks::gpio::{ReloadPadsSignal, calibration::Calibration};
+use crate::tasks::telemetry;
+
+/// Longer lines are dropped as a whole.
+const MAX_LINE_LEN: usize = 32;
+
+#[embassy_executor::task]
+pub async fn debug_console_task(
+    usb: UsbSerialJtag<'static, Async>,
+    config: &'static SharedConfig,
+    force_disconnect: &'static ForceDisconnectSignal,
+    reload_pads: &'static ReloadPadsSignal,
+    calibration: &'static Calibration,
+) {
+    let (mut rx, _) = usb.split();
+    let mut line: Vec<u8, MAX_LINE_LEN> = Vec::new();
+    let mut overflowed = false;
+    let mut buf = [0; 16];
+
+    info!("[console] ready, type `help` for the commands");
+
+    loop {
+        let Ok(len) = rx.read(&mut buf).await else {
+            continue;
+        };
+        for &byte in &buf[..len] {
+            match byte {
+                b'\r' | b'\n' => {
+                    if overflowed {
+                        warn!("[console] line over {} bytes dropped", MAX_LINE_LEN);
+                    } else if !line.is_empty() {
+                        run_command(&line, config, force_disconnect, reload_pads, calibration);
+                    }
+                    line.clear();
+                    overflowed = false;
+                }
+                _ => overflowed |= line.push(byte).is_err(),
+            }
+        }
+    }
+}
+
+fn run_command(
+    line: &[u8],
+    config: &SharedConfig,
+    force_disconnect: &ForceDisconnectSignal,
+    reload_pads: &ReloadPadsSignal,
+    calibration: &Calibration,
+) {
+    match line.trim_ascii() {
+        b"" => {}
+        b"help" => {
+            info!("[console] commands: help, config, heap, disconnect, reload, selftest, report")
+        }
+        b"config" => config.read(|c| info!("[console] {}", c)),
+        b"heap" => info!(
+            "[console] heap: {} bytes used, {} free, {} used at peak",
+            esp_alloc::HEAP.used(),
+            esp_alloc::HEAP.free(),
+            telemetry::peak_heap_used()
+        ),
+        b"disconnect" => force_disconnect.signal(()),
+        b"reload" => reload_pads.signal(()),
+        b"selftest" => {
+            info!("[console] measuring pad noise, leave the pads alone");
+            calibration.request();
+        }
+        b"report" => {
+            let (status, noise) = calibration.report();
+            info!("[console] {}: {}", status, noise);
+        }
+        unknown => warn!("[console] unknown command {=[u8]:a}", unknown),
+    }
+}
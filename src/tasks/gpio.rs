@@ -1,20 +1,57 @@
-use core::{
-    cell::{Cell, RefCell},
-    pin::pin,
-};
-use defer::defer;
-use defmt::{debug, trace};
-use embassy_futures::select::select_slice;
+use core::{cell::Cell, cmp::Reverse, future::pending, pin::pin};
+use defmt::{debug, info, trace, warn};
+use embassy_futures::select::{Either, Either3, select, select_slice, select3};
 use embassy_sync::{
     blocking_mutex::raw::{NoopRawMutex, RawMutex},
     channel::{Channel, Receiver, TrySendError},
     signal::Signal,
 };
-use embassy_time::{Duration, Instant, Ticker, TimeoutError, Timer, with_timeout};
-use esp_hal::gpio::{AnyPin, Input, InputConfig, Output};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_deadline, with_timeout};
+#[cfg(not(test))]
+use esp_hal::gpio::{AnyPin, Input, InputConfig, Pull};
 use heapless::Vec;
 use midi_types::Note;
 
+#[cfg(not(test))]
+use crate::tasks::gpio::calibration::calibrate;
+#[cfg(not(test))]
+use crate::tasks::led::LedPatternSender;
+use crate::{
+    config::{PAD_COUNT, PadConfig, SensorPolarity, SensorPull, SharedConfig, TriggerEdge},
+    midi::scale_velocity,
+    tasks::gpio::{
+        calibration::{Calibration, CalibrationStatus},
+        velocity::{PadVelocitySource, VelocitySource},
+    },
+    tasks::telemetry::{COUNTERS, HIT_LOG},
+};
+
+pub mod calibration;
+pub mod velocity;
+
+/// The drums, numbered as their General MIDI note by default, see
+/// [`NoteMap`](crate::config::NoteMap).
+///
+/// # Hi-hat
+///
+/// The hi-hat is played by up to three pads: the pedal ([`PedalHiHat`](Self::PedalHiHat)), the
+/// bow zone of the cymbal ([`OpenHiHat`](Self::OpenHiHat)) and its edge zone
+/// ([`OpenHiHatEdge`](Self::OpenHiHatEdge)), each on its own pin. A dual-zone hi-hat pad is wired
+/// as two pads, one with each of the zone notes, and a single-zone one just leaves out the edge.
+/// What they play depends on the pedal:
+///
+/// | Played                 | Pedal open               | Pedal pressed                |
+/// |------------------------|--------------------------|------------------------------|
+/// | Bow zone               | `OpenHiHat` (46)         | `ClosedHiHat` (42)           |
+/// | Edge zone              | `OpenHiHatEdge` (26)     | `ClosedHiHatEdge` (22)       |
+/// | Pedal                  | `OpenHiHat` on a splash  | `PedalHiHat` (44), the chick |
+///
+/// A splash is the pedal released after a quick press, see
+/// [`Config::hi_hat_splash_window`](crate::config::Config::hi_hat_splash_window), which is off
+/// when zero (the default). The zone hits go by the pedal as of their own timestamp, see
+/// `PedalState`. Each of the notes is sent as the number the [`NoteMap`](crate::config::NoteMap)
+/// gives it, so a sampler's own hi-hat articulations are a custom note map away. The edge zone
+/// numbers are the ones of Roland's V-Drums modules, as General MIDI has no edge notes.
 #[derive(Copy, Clone, PartialEq, defmt::Format)]
 #[repr(u8)]
 pub enum DrumNote {
@@ -29,6 +66,71 @@ pub enum DrumNote {
     CrashCymbal1 = 49,
     CrashCymbal2 = 57,
     RideCymbal = 51,
+    /// Edge zone of the hi-hat, see the [hi-hat](Self#hi-hat) notes. After the other notes, for
+    /// them to keep their indices.
+    OpenHiHatEdge = 26,
+    ClosedHiHatEdge = 22,
+}
+
+impl DrumNote {
+    pub const COUNT: usize = 13;
+
+    /// All notes, in declaration order.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::BassDrum,
+        Self::Snare,
+        Self::ClosedHiHat,
+        Self::PedalHiHat,
+        Self::OpenHiHat,
+        Self::FloorTom,
+        Self::LowTom,
+        Self::HighTom,
+        Self::CrashCymbal1,
+        Self::CrashCymbal2,
+        Self::RideCymbal,
+        Self::OpenHiHatEdge,
+        Self::ClosedHiHatEdge,
+    ];
+
+    /// Position of the note in [`ALL`](Self::ALL).
+    pub const fn index(self) -> usize {
+        match self {
+            Self::BassDrum => 0,
+            Self::Snare => 1,
+            Self::ClosedHiHat => 2,
+            Self::PedalHiHat => 3,
+            Self::OpenHiHat => 4,
+            Self::FloorTom => 5,
+            Self::LowTom => 6,
+            Self::HighTom => 7,
+            Self::CrashCymbal1 => 8,
+            Self::CrashCymbal2 => 9,
+            Self::RideCymbal => 10,
+            Self::OpenHiHatEdge => 11,
+            Self::ClosedHiHatEdge => 12,
+        }
+    }
+
+    /// What a hi-hat zone pad of `self` plays with the pedal pressed, `self` for the other notes.
+    pub const fn closed(self) -> Self {
+        match self {
+            Self::OpenHiHat => Self::ClosedHiHat,
+            Self::OpenHiHatEdge => Self::ClosedHiHatEdge,
+            note => note,
+        }
+    }
+}
+
+impl TryFrom<u8> for DrumNote {
+    type Error = u8;
+
+    /// From the MIDI note number.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|&note| note as u8 == value)
+            .ok_or(value)
+    }
 }
 
 impl From<DrumNote> for Note {
@@ -44,176 +146,1301 @@ pub enum SensorsStatus {
 }
 pub type SensorsStatusSignal = Signal<NoopRawMutex, SensorsStatus>;
 
-pub type HitEventsChannel = Channel<NoopRawMutex, (Instant, DrumNote), 16>;
-pub type HitEventsReceiver<'ch> = Receiver<'ch, NoopRawMutex, (Instant, DrumNote), 16>;
+/// Signaled to make [`watch_gpios_task`] pick up changes to `Config::pads` without waiting for
+/// the sensors to be switched off and on again.
+///
+/// The note, polarity, pull, debounce profile, stable duration and gate following the sensor of a
+/// pad need this (or a sensors power cycle). The gate, minimum gate and trigger mode, like the rest of
+/// the config, are read on each hit and apply live.
+pub type ReloadPadsSignal = Signal<NoopRawMutex, ()>;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct HitEvent {
+    pub timestamp: Instant,
+    /// Index of the hit pad in `Config::pads`.
+    pub pad: usize,
+    pub kind: HitKind,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum HitKind {
+    Note {
+        /// Not necessarily the pad's note (e.g. the open hi-hat pad hit while the pedal is pressed
+        /// plays the closed hi-hat).
+        note: DrumNote,
+        velocity: u8,
+        /// The Note Off waits for the pad's [`Release`](Self::Release), decided here rather than
+        /// from the live config so that it's only ever set when a release is coming.
+        until_release: bool,
+    },
+    /// A [control pad](PadConfig::control) pressed or released.
+    Control { pressed: bool },
+    /// The pad released after a note hit, for the pads whose
+    /// [gate follows the sensor](PadConfig::gate_follows_sensor).
+    Release,
+}
+
+/// Hits buffered between detection and the BLE link. Once full, a new hit drops the oldest one
+/// (see `ForceSend`) rather than stalling the pads, so the depth is how far the link may fall
+/// behind, e.g. while notifications are held up by a slow connection interval, before hits get
+/// lost. A deeper buffer loses fewer hits, at a `HitEvent` of RAM each, but may play them late.
+pub const HIT_EVENTS_DEPTH: usize = 16;
+
+pub type HitEventsChannel = Channel<NoopRawMutex, HitEvent, HIT_EVENTS_DEPTH>;
+
+/// Whether the hits are piling up in the [`HitEventsChannel`], for both of its ends to adapt (e.g.
+/// by coalescing hits) before hits get dropped: set once the channel fills up to
+/// [`HIGH_WATER`](Self::HIGH_WATER), and cleared only once drained down to
+/// [`LOW_WATER`](Self::LOW_WATER), so that it doesn't flip on every hit around a single mark.
+///
+/// Follows `Channel::len`, which embassy-sync exposes on the channel and its receiver, so there's
+/// no counter of its own to keep in sync: whichever end changes the length updates this right
+/// after, from the new length.
+pub struct HitEventsBackpressure {
+    active: Cell<bool>,
+}
+
+impl HitEventsBackpressure {
+    pub const HIGH_WATER: usize = HIT_EVENTS_DEPTH * 3 / 4;
+    pub const LOW_WATER: usize = HIT_EVENTS_DEPTH / 4;
+
+    pub const fn new() -> Self {
+        Self {
+            active: Cell::new(false),
+        }
+    }
 
+    /// Update from the channel's new length `len`, returning whether it's active.
+    pub fn update(&self, len: usize) -> bool {
+        let active = match self.active.get() {
+            false => len >= Self::HIGH_WATER,
+            true => len > Self::LOW_WATER,
+        };
+        if active != self.active.get() {
+            debug!("Hit events backpressure {} at {} queued", active, len);
+            self.active.set(active);
+        }
+        active
+    }
+}
+pub type HitEventsReceiver<'ch> = Receiver<'ch, NoopRawMutex, HitEvent, HIT_EVENTS_DEPTH>;
+
+#[cfg(not(test))]
 #[embassy_executor::task]
 pub async fn watch_gpios_task(
-    pins_notes_map: [(AnyPin<'static>, DrumNote); 10],
+    pins: [AnyPin<'static>; PAD_COUNT],
+    config: &'static SharedConfig,
     status_signal: &'static SensorsStatusSignal,
     hit_events: &'static HitEventsChannel,
+    backpressure: &'static HitEventsBackpressure,
+    reload: &'static ReloadPadsSignal,
+    calibration: &'static Calibration,
+    velocity_source: PadVelocitySource,
+    status_led: LedPatternSender<'static>,
 ) {
-    let mut pins_notes_map =
-        pins_notes_map.map(|(pin, note)| (Input::new(pin, InputConfig::default()), note));
+    // A pad still held after a reload (e.g. the hi-hat pedal) can't be re-armed until released.
+    // Rather than hanging, give up after this and report the sensors off.
+    const REARM_TIMEOUT: Duration = Duration::from_millis(500);
+    // Sensors switching on can dip back low for a bit as their supply settles, which would pass
+    // for hits. Those are ignored for this long after the sensors are detected on.
+    const SENSORS_SETTLE_DURATION: Duration = Duration::from_millis(200);
+
+    #[cfg(feature = "raw-hits")]
+    warn!("[gpio] raw-hits build: no debounce nor velocity processing, for bench testing only");
+
+    let mut inputs = pins.map(|pin| Input::new(pin, InputConfig::default()));
+    let stuck = find_stuck_pins(&mut inputs, &config.read(|c| c.pads))
+        .await
+        .map(Cell::new);
+    let mut reloading = false;
 
     loop {
-        select_slice(pin!(
-            pins_notes_map
-                .iter_mut()
-                .map(|(pin, ..)| pin.wait_for_stable_high())
-                .collect::<Vec<_, 10>>()
-                .as_mut_slice()
-        ))
+        // Config changes to the pads are picked up each time before the sensors are switched on,
+        // or on reload.
+        reload.reset();
+        let (pads, hi_hat_splash, warmup_hits, stuck_pad_timeout) = config.read(|c| {
+            let hi_hat_splash = SplashThreshold {
+                window: c.hi_hat_splash_window,
+                hysteresis: c.hi_hat_splash_hysteresis,
+            };
+            (c.pads, hi_hat_splash, c.warmup_hits, c.stuck_pad_timeout)
+        });
+        for (pin, pad) in inputs.iter_mut().zip(&pads) {
+            pin.apply_config(&input_config(pad));
+        }
+
+        // Hits are only watched for from idle, so no hit is made up of a pad already held when
+        // (re-)arming.
+        let armed = select(
+            select_slice(pin!(
+                inputs
+                    .iter_mut()
+                    .zip(&pads)
+                    .zip(&stuck)
+                    .map(|((pin, pad), stuck)| async move {
+                        match pad.polarity {
+                            // Would pass for the sensors switched on.
+                            SensorPolarity::Normal if stuck.get() => pending().await,
+                            SensorPolarity::Normal => {
+                                pin.wait_for_stable_high(pad.stable_duration).await
+                            }
+                            // Idles low like when the sensors are off, so it can't tell.
+                            SensorPolarity::Inverted => pending().await,
+                        }
+                    })
+                    .collect::<Vec<_, PAD_COUNT>>()
+                    .as_mut_slice()
+            )),
+            async {
+                if reloading {
+                    Timer::after(REARM_TIMEOUT).await
+                } else {
+                    pending().await
+                }
+            },
+        )
         .await;
+        let rearming = core::mem::take(&mut reloading);
+        if rearming && matches!(armed, Either::Second(())) {
+            debug!("Pads not idle after reload");
+            status_signal.signal(SensorsStatus::Off);
+            continue;
+        }
+        // Ignored by the BLE side after a reload, the sensors never went off for it.
         status_signal.signal(SensorsStatus::On);
 
+        // The sensors are already on and settled when re-arming. When they were already on at
+        // boot, they may have just been switched on too.
+        let settled_at = if rearming {
+            Instant::now()
+        } else {
+            Instant::now() + SENSORS_SETTLE_DURATION
+        };
+        let counted_pads = pads
+            .iter()
+            .zip(&stuck)
+            .filter(|(pad, stuck)| pad.polarity == SensorPolarity::Normal && !stuck.get())
+            .count() as u8;
         let shared_state = SharedPinsState {
             pin_high_count: Cell::new(0),
-            is_pedal_hi_hat_pressed: Cell::new(false),
+            counted_pads,
+            stuck: &stuck,
+            stuck_pad_timeout,
+            pedal_hi_hat: PedalState::new(),
+            settled_at,
+            hi_hat_splash,
+            warmup_hits: if rearming { 0 } else { warmup_hits },
+            backpressure,
         };
 
-        select_slice(pin!(
-            pins_notes_map
-                .iter_mut()
-                .map(|(pin, note)| watch_pin_for_hits(pin, *note, &shared_state, hit_events))
-                .collect::<Vec<_, 10>>()
-                .as_mut_slice()
-        ))
+        // Polled in slice order, so the pads scanned first come first. See
+        // `PadConfig::scan_priority`.
+        let mut scanned: Vec<_, PAD_COUNT> = inputs.iter_mut().zip(pads).enumerate().collect();
+        scanned.sort_unstable_by_key(|&(index, (_, pad))| (Reverse(pad.scan_priority), index));
+
+        // The futures are collected into a stack `Vec` once per arming, not per hit: each one
+        // loops over all the hits of its pad until the sensors go off or the pads are reloaded.
+        // So the hits only ever pay for polling, and building them anew on each arming is what
+        // lets them pick up the new pad configs.
+        let watched = select3(
+            select_slice(pin!(
+                scanned
+                    .into_iter()
+                    .map(|(index, (pin, pad))| {
+                        watch_pin_for_hits(
+                            pin,
+                            index,
+                            pad,
+                            &shared_state,
+                            &velocity_source,
+                            hit_events,
+                        )
+                    })
+                    .collect::<Vec<_, PAD_COUNT>>()
+                    .as_mut_slice()
+            )),
+            reload.wait(),
+            calibration.wait_requested(),
+        )
         .await;
-        status_signal.signal(SensorsStatus::Off);
+        match watched {
+            Either3::First(()) => status_signal.signal(SensorsStatus::Off),
+            Either3::Second(()) => {
+                debug!("Reloading pads config");
+                reloading = true;
+            }
+            Either3::Third(()) => {
+                info!("[gpio] calibrating");
+                calibration.set_report((CalibrationStatus::Running, calibration.report().1));
+                let report = match calibrate(&mut inputs, &pads, status_led).await {
+                    Some(noise) => (CalibrationStatus::Done, noise),
+                    None => (CalibrationStatus::Busy, calibration.report().1),
+                };
+                info!("[gpio] calibration {}", report);
+                calibration.set_report(report);
+                // Re-armed like after a reload, for the hits that were in progress.
+                reloading = true;
+            }
+        }
     }
 }
 
-struct SharedPinsState {
+/// Boot self-check for wiring mistakes: pins already away from the level the sensors give them
+/// when switched off. Non-fatal, the pins are only logged.
+///
+/// All the normal pads being high just means the sensors are already on (powered before the MCU),
+/// so it's only a subset of them that's flagged. Those are left out of detecting the sensors off,
+/// as they'd otherwise keep it from ever happening, and of detecting them on, as they'd pass for
+/// it, until they're seen low, like the pads found stuck later on (see
+/// [`Config::stuck_pad_timeout`](crate::config::Config::stuck_pad_timeout)). They still play when
+/// hit.
+///
+/// Sensors already on at boot are then handled like being switched on: detected on right away,
+/// without any hit, and with the same settling time. A pad already held at boot only plays once
+/// released and hit again.
+#[cfg(not(test))]
+async fn find_stuck_pins(
+    inputs: &mut [Input<'_>; PAD_COUNT],
+    pads: &[PadConfig; PAD_COUNT],
+) -> [bool; PAD_COUNT] {
+    for (pin, pad) in inputs.iter_mut().zip(pads) {
+        pin.apply_config(&input_config(pad));
+    }
+    // Let the pulls settle.
+    Timer::after_millis(10).await;
+
+    let all_normal_high = inputs
+        .iter()
+        .zip(pads)
+        .filter(|(_, pad)| pad.polarity == SensorPolarity::Normal)
+        .all(|(pin, _)| pin.is_high());
+
+    if all_normal_high {
+        info!("[gpio] sensors already on at boot");
+    }
+
+    let mut stuck = [false; PAD_COUNT];
+    for (index, (pin, pad)) in inputs.iter().zip(pads).enumerate() {
+        match pad.polarity {
+            SensorPolarity::Normal if !all_normal_high && pin.is_high() => {
+                warn!(
+                    "[gpio] pad {} ({}) is high at boot while others are low. Stuck or miswired? \
+                    Leaving it out of the sensors off detection.",
+                    index, pad.note
+                );
+                stuck[index] = true;
+            }
+            // Not counted anyway, so just let it be known.
+            SensorPolarity::Inverted if pin.is_high() => {
+                warn!(
+                    "[gpio] inverted pad {} ({}) is high at boot. Held, stuck or miswired?",
+                    index, pad.note
+                );
+            }
+            _ => {}
+        }
+    }
+    stuck
+}
+
+#[cfg(not(test))]
+fn input_config(pad: &PadConfig) -> InputConfig {
+    let pull = match pad.pull {
+        Some(SensorPull::None) => Pull::None,
+        Some(SensorPull::Up) => Pull::Up,
+        Some(SensorPull::Down) => Pull::Down,
+        None => match pad.polarity {
+            SensorPolarity::Normal => Pull::None,
+            // Keep the pin at the idle level while the sensor isn't driving it high.
+            SensorPolarity::Inverted => Pull::Down,
+        },
+    };
+    InputConfig::default().with_pull(pull)
+}
+
+struct SharedPinsState<'a> {
+    /// Number of [`Normal`](SensorPolarity::Normal) pads currently idle high. Inverted pads idle
+    /// low just like when the sensors are off, so they're left out, as are the `stuck` ones.
     pin_high_count: Cell<u8>,
-    is_pedal_hi_hat_pressed: Cell<bool>,
+    /// Of the pads, how many were counted in `pin_high_count` when arming.
+    counted_pads: u8,
+    /// Pads left out of `pin_high_count` for staying high while the others are low, see
+    /// [`Config::stuck_pad_timeout`](crate::config::Config::stuck_pad_timeout), until seen low
+    /// again. Outlives the arming, for the next one.
+    stuck: &'a [Cell<bool>; PAD_COUNT],
+    stuck_pad_timeout: Duration,
+    pedal_hi_hat: PedalState,
+    /// Until when the sensors may still be settling after switching on, not to be taken for hits.
+    settled_at: Instant,
+    hi_hat_splash: SplashThreshold,
+    /// [`Config::warmup_hits`](crate::config::Config::warmup_hits), if the sensors have just been
+    /// switched on, zero otherwise.
+    warmup_hits: u8,
+    backpressure: &'a HitEventsBackpressure,
+}
+
+/// Whether the hi-hat pedal is pressed, as of when it was pressed or released rather than of when
+/// that was processed.
+///
+/// A hit of a hi-hat zone pad and a pedal transition detected at about the same time are
+/// processed in whichever order their tasks get to run, which may not be the order they happened
+/// in. Resolving the hit against the pedal at its own timestamp makes up for that, as long as the
+/// two are processed within a pedal transition of each other: only the last transition is kept,
+/// so a hit processed two transitions late (a full press and release in between, tens of
+/// milliseconds at the least) gets the state from before the last one.
+struct PedalState {
+    pressed: Cell<bool>,
+    changed_at: Cell<Instant>,
+}
+
+impl PedalState {
+    fn new() -> Self {
+        Self {
+            pressed: Cell::new(false),
+            changed_at: Cell::new(Instant::MIN),
+        }
+    }
+
+    fn set(&self, pressed: bool, at: Instant) {
+        self.pressed.set(pressed);
+        self.changed_at.set(at);
+    }
+
+    /// Whether the pedal was pressed at `timestamp`, which can be before the last transition.
+    fn pressed_at(&self, timestamp: Instant) -> bool {
+        if timestamp >= self.changed_at.get() {
+            self.pressed.get()
+        } else {
+            !self.pressed.get()
+        }
+    }
+}
+
+/// Tells a foot splash from a chick by how long the hi-hat pedal was held, see
+/// [`Config::hi_hat_splash_window`](crate::config::Config::hi_hat_splash_window) and
+/// [`Config::hi_hat_splash_hysteresis`](crate::config::Config::hi_hat_splash_hysteresis).
+#[derive(Clone, Copy)]
+struct SplashThreshold {
+    window: Duration,
+    hysteresis: Duration,
+}
+
+impl SplashThreshold {
+    /// Whether the pedal pressed and released after `held` is a splash, given whether the
+    /// previous press was one in `last_splash`, which is updated.
+    fn is_splash(&self, held: Duration, last_splash: &mut bool) -> bool {
+        let zero = Duration::from_ticks(0);
+        let threshold = if self.window == zero {
+            None
+        } else if *last_splash {
+            Some(self.window + self.hysteresis)
+        } else {
+            Some(self.window.checked_sub(self.hysteresis).unwrap_or(zero))
+        };
+        *last_splash = threshold.is_some_and(|threshold| held <= threshold);
+        *last_splash
+    }
 }
 
 async fn watch_pin_for_hits(
-    pin: &mut Input<'_>,
-    note: DrumNote,
-    state: &SharedPinsState,
+    pin: &mut impl WaitForSensor,
+    index: usize,
+    pad: PadConfig,
+    state: &SharedPinsState<'_>,
+    velocity_source: &impl VelocitySource,
     hit_events: &HitEventsChannel,
 ) {
+    let note = pad.note;
+    let mut last_hit: Option<Instant> = None;
+    // Stuck pins would keep the sensors from ever being detected off.
+    let mut counted = pad.polarity == SensorPolarity::Normal && !state.stuck[index].get();
+
+    #[cfg(feature = "trace-edges")]
+    let pin = &mut EdgeTracer {
+        input: pin,
+        traced: note == TRACE_EDGES_NOTE,
+    };
+
+    let mut control_pressed = false;
+    // A note hit whose Note Off waits for the release.
+    let mut note_held = false;
+    // When the hi-hat pedal was last pressed, until it's released.
+    let mut pedal_pressed_at: Option<Instant> = None;
+    let mut last_splash = false;
+    let mut rate_guard = HitRateGuard::new(Instant::now());
+    let mut warmup_hits_left = state.warmup_hits;
+    // Hits on the release, see `TriggerEdge::Release`.
+    let on_release = pad.trigger_edge == TriggerEdge::Release
+        && pad.control.is_none()
+        && note != DrumNote::PedalHiHat;
+    // Whether the last hit already waited for the release.
+    let mut released = false;
+
     loop {
         {
-            pin.wait_for_stable_high().await;
+            if !core::mem::take(&mut released) {
+                pin.wait_for_stable_release(pad.polarity, pad.stable_duration)
+                    .await;
+
+                if counted {
+                    state.pin_high_count.update(|c| c + 1);
+                }
+            }
 
-            state.pin_high_count.update(|c| c + 1);
+            if core::mem::take(&mut control_pressed) {
+                let hit_event = HitEvent {
+                    timestamp: Instant::now(),
+                    pad: index,
+                    kind: HitKind::Control { pressed: false },
+                };
+                send_hit_event(hit_events, state.backpressure, hit_event);
+                debug!("Released {}", hit_event);
+            }
+
+            if core::mem::take(&mut note_held) {
+                let hit_event = HitEvent {
+                    timestamp: Instant::now(),
+                    pad: index,
+                    kind: HitKind::Release,
+                };
+                send_hit_event(hit_events, state.backpressure, hit_event);
+                debug!("Released {}", hit_event);
+            }
 
             if note == DrumNote::PedalHiHat {
-                state.is_pedal_hi_hat_pressed.set(false);
+                let now = Instant::now();
+                state.pedal_hi_hat.set(false, now);
+
+                if let Some(pressed_at) = pedal_pressed_at.take()
+                    && state
+                        .hi_hat_splash
+                        .is_splash(now - pressed_at, &mut last_splash)
+                {
+                    let hit_event = HitEvent {
+                        timestamp: now,
+                        pad: index,
+                        kind: HitKind::Note {
+                            note: DrumNote::OpenHiHat,
+                            velocity: velocity_source.velocity(index).await,
+                            until_release: false,
+                        },
+                    };
+                    send_hit_event(hit_events, state.backpressure, hit_event);
+                    debug!("Splash {}", hit_event);
+                }
             }
 
             trace!("Unhit {}", note);
         }
 
         {
-            pin.wait_for_stable_low().await;
-            let timestamp = Instant::now();
+            let stuck_wait = async {
+                match counted {
+                    true => wait_until_stuck(state).await,
+                    false => pending().await,
+                }
+            };
+            let hit = select(
+                pin.wait_for_stable_hit(pad.polarity, pad.stable_duration),
+                stuck_wait,
+            )
+            .await;
+            if let Either::Second(()) = hit {
+                warn!(
+                    "[gpio] pad {} ({}) still high {} after the others went low. Stuck? Leaving \
+                    it out of the sensors off detection until it goes low.",
+                    index, note, state.stuck_pad_timeout
+                );
+                state.stuck[index].set(true);
+                counted = false;
+                state.pin_high_count.update(|c| c - 1);
+                if state.pin_high_count.get() == 0 {
+                    // The other pins are all low, so the sensors are off after all.
+                    break;
+                }
+                pin.wait_for_stable_hit(pad.polarity, pad.stable_duration)
+                    .await;
+            }
+            let pressed_at = Instant::now();
 
-            state.pin_high_count.update(|c| c - 1);
-            if state.pin_high_count.get() == 0 {
-                // All pins are low. Probably sensors are turned off, so we're exiting.
-                // (It's unlikely that all pads are hit at the same instance.)
-                break;
+            if counted {
+                state.pin_high_count.update(|c| c - 1);
+                if state.pin_high_count.get() == 0 {
+                    // All pins are low. Probably sensors are turned off, so we're exiting.
+                    // (It's unlikely that all pads are hit at the same instance.)
+                    break;
+                }
             }
 
-            if note == DrumNote::PedalHiHat {
-                state.is_pedal_hi_hat_pressed.set(true);
+            if state.stuck[index].replace(false) {
+                // Counted from its next release, like the other pads. The hit plays, the same as
+                // the hits of a stuck pad still do.
+                info!("[gpio] pad {} ({}) low again, no longer stuck", index, note);
+                counted = pad.polarity == SensorPolarity::Normal;
             }
 
-            let note = if note == DrumNote::OpenHiHat && state.is_pedal_hi_hat_pressed.get() {
-                DrumNote::ClosedHiHat
+            if cfg!(feature = "raw-hits") {
+                send_raw_hit(index, note, pressed_at, state, velocity_source, hit_events).await;
+                continue;
+            }
+
+            let timestamp = if on_release {
+                pin.wait_for_stable_release(pad.polarity, pad.stable_duration)
+                    .await;
+                if counted {
+                    state.pin_high_count.update(|c| c + 1);
+                }
+                released = true;
+                Instant::now()
             } else {
-                note
+                pressed_at
+            };
+
+            if timestamp < state.settled_at {
+                trace!("Rejected settling {}", note);
+                continue;
+            }
+
+            if warmup_hits_left > 0 {
+                warmup_hits_left -= 1;
+                trace!("Rejected warmup {}", note);
+                continue;
+            }
+
+            let hold =
+                last_hit.and_then(|last_hit| pad.debounce.retrigger_hold(timestamp - last_hit));
+            let released_too_soon = match hold {
+                None => false,
+                // Held for as long as it was already.
+                Some(hold) if released => timestamp - pressed_at < hold,
+                Some(hold) => with_timeout(hold, pin.wait_for_release(pad.polarity))
+                    .await
+                    .is_ok(),
             };
-            let hit_event = (timestamp, note);
+            if released_too_soon {
+                // Most likely ringing from the previous hit.
+                trace!("Rejected retrigger {}", note);
+                continue;
+            }
+            last_hit = Some(timestamp);
+
+            if rate_guard.is_exceeded(timestamp) {
+                warn!(
+                    "[gpio] pad {} ({}) hit more than {} times in {}. Faulty sensor? Disabled until \
+                    it stays released for {}.",
+                    index,
+                    note,
+                    HitRateGuard::MAX_HITS,
+                    HitRateGuard::WINDOW,
+                    HitRateGuard::QUIET_DURATION
+                );
+                pin.wait_for_stable_release(pad.polarity, HitRateGuard::QUIET_DURATION)
+                    .await;
+                rate_guard = HitRateGuard::new(Instant::now());
+                warn!("[gpio] pad {} ({}) re-enabled", index, note);
+                continue;
+            }
 
-            hit_events.force_send(hit_event);
+            let kind = if pad.control.is_some() {
+                control_pressed = true;
+                HitKind::Control { pressed: true }
+            } else {
+                if note == DrumNote::PedalHiHat {
+                    // The pedal closing is a hit of its own, emitted below as the chick sound.
+                    // It's never substituted, so it can't double-fire with the closed hi-hat note,
+                    // which only comes from striking a hi-hat zone pad while the pedal is held.
+                    state.pedal_hi_hat.set(true, timestamp);
+                    pedal_pressed_at = Some(timestamp);
+                }
+
+                let closed = note.closed();
+                let note = if closed != note && state.pedal_hi_hat.pressed_at(timestamp) {
+                    closed
+                } else {
+                    note
+                };
+                let velocity = velocity_source.velocity(index).await;
+                let velocity = if released {
+                    velocity
+                } else {
+                    merge_double_triggers(pin, &pad, index, timestamp, velocity, velocity_source)
+                        .await
+                };
+                note_held = pad.gate_follows_sensor && !released;
+                HitKind::Note {
+                    note,
+                    velocity,
+                    until_release: note_held,
+                }
+            };
+            let hit_event = HitEvent {
+                timestamp,
+                pad: index,
+                kind,
+            };
+
+            send_hit_event(hit_events, state.backpressure, hit_event);
             debug!("Hit {}", hit_event);
 
-            const HIT_DEBOUNCE_TIME: Duration = Duration::from_millis(30);
-            Timer::at(timestamp + HIT_DEBOUNCE_TIME).await;
+            Timer::at(timestamp + pad.debounce.min_interval()).await;
+
+            if let Some(repeat) = pad.repeat
+                && let HitKind::Note { note, velocity, .. } = kind
+                && note != DrumNote::PedalHiHat
+                && !released
+                && repeat.interval > Duration::from_ticks(0)
+            {
+                let mut velocity = velocity;
+                let mut next = timestamp + repeat.interval;
+                while with_deadline(
+                    next.max(Instant::now()),
+                    pin.wait_for_stable_release(pad.polarity, pad.stable_duration),
+                )
+                .await
+                .is_err()
+                {
+                    velocity = scale_velocity(velocity, repeat.velocity_scale);
+                    let hit_event = HitEvent {
+                        timestamp: Instant::now(),
+                        pad: index,
+                        kind: HitKind::Note {
+                            note,
+                            velocity,
+                            until_release: false,
+                        },
+                    };
+                    send_hit_event(hit_events, state.backpressure, hit_event);
+                    trace!("Repeat {}", hit_event);
+                    next += repeat.interval;
+                }
+                // Already waited for, like for the hits on the release.
+                if counted {
+                    state.pin_high_count.update(|c| c + 1);
+                }
+                released = true;
+            }
+        }
+    }
+}
+
+/// Wait until the pad, still counted high, has stayed the last one high for the
+/// [`stuck_pad_timeout`](crate::config::Config::stuck_pad_timeout), which a pad latched high
+/// does when the sensors switch off. Checked a few times per timeout rather than tracked on each
+/// edge of the other pads, to keep that off the hit path. Never with the timeout at zero, nor
+/// with a single pad counted, which is always the last one high.
+async fn wait_until_stuck(state: &SharedPinsState<'_>) {
+    if state.stuck_pad_timeout == Duration::from_ticks(0) || state.counted_pads < 2 {
+        return pending().await;
+    }
+    let mut alone_since: Option<Instant> = None;
+    loop {
+        Timer::after(state.stuck_pad_timeout / 4).await;
+        let now = Instant::now();
+        if state.pin_high_count.get() != 1 {
+            alone_since = None;
+        } else if now - *alone_since.get_or_insert(now) >= state.stuck_pad_timeout {
+            return;
+        }
+    }
+}
+
+/// With the `raw-hits` feature, for validating the sensors and their wiring on the bench (e.g.
+/// driving a pin with a signal generator): every stable hit of the pad, i.e. its level staying
+/// at the hit level for the [`stable_duration`](PadConfig::stable_duration) after staying
+/// released for as long, plays the pad's note right away, at the velocity as sensed. So the note
+/// rate is the raw trigger rate the sensor gives, up to how fast the stable durations allow.
+///
+/// Bypassed are everything that would hide it: the settling time and warm-up hits, the
+/// [`debounce`](PadConfig::debounce), the double trigger merging, the [`HitRateGuard`], and the
+/// trigger edge, hi-hat and control pad handling. The velocity gains, curve, humanization, range
+/// and fixed velocity are bypassed by [`build_note_on`](crate::midi::build_note_on). The gate and
+/// note map still apply.
+///
+/// Not for playing: a bouncing sensor plays every bounce. Hence the feature refuses release
+/// builds.
+async fn send_raw_hit(
+    index: usize,
+    note: DrumNote,
+    timestamp: Instant,
+    state: &SharedPinsState<'_>,
+    velocity_source: &impl VelocitySource,
+    hit_events: &HitEventsChannel,
+) {
+    let kind = HitKind::Note {
+        note,
+        velocity: velocity_source.velocity(index).await,
+        until_release: false,
+    };
+    let hit_event = HitEvent {
+        timestamp,
+        pad: index,
+        kind,
+    };
+    send_hit_event(hit_events, state.backpressure, hit_event);
+    trace!("Raw hit {}", hit_event);
+}
+
+/// The velocity of the stroke hit at `timestamp` with `velocity`, raised by the further humps of
+/// it within the pad's [`double_trigger_window`](PadConfig::double_trigger_window).
+async fn merge_double_triggers(
+    pin: &mut impl WaitForSensor,
+    pad: &PadConfig,
+    index: usize,
+    timestamp: Instant,
+    mut velocity: u8,
+    velocity_source: &impl VelocitySource,
+) -> u8 {
+    if pad.double_trigger_window == Duration::from_ticks(0) {
+        return velocity;
+    }
+    // A hump left released at the deadline is picked up by the next wait for the release, which
+    // then returns right away.
+    let window_end = timestamp + pad.double_trigger_window;
+    while with_deadline(window_end, async {
+        pin.wait_for_stable_release(pad.polarity, pad.stable_duration)
+            .await;
+        pin.wait_for_stable_hit(pad.polarity, pad.stable_duration)
+            .await;
+    })
+    .await
+    .is_ok()
+    {
+        velocity = velocity.max(velocity_source.velocity(index).await);
+        trace!("Merged double trigger {}", pad.note);
+    }
+    velocity
+}
+
+/// Protects the BLE link and the host from a faulty sensor oscillating around its threshold, by
+/// disabling a pad hit more than [`MAX_HITS`](Self::MAX_HITS) times within a
+/// [`WINDOW`](Self::WINDOW). The pad is re-enabled once it has stayed released for
+/// [`QUIET_DURATION`](Self::QUIET_DURATION).
+struct HitRateGuard {
+    window_start: Instant,
+    hits: u8,
+}
+
+impl HitRateGuard {
+    const WINDOW: Duration = Duration::from_secs(1);
+    /// Well above the fastest rolls played on a single pad, which stay under ~30 hits a second.
+    const MAX_HITS: u8 = 40;
+    const QUIET_DURATION: Duration = Duration::from_secs(2);
+
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            hits: 0,
+        }
+    }
+
+    /// Count a hit at `timestamp`, returning whether it's one too many.
+    fn is_exceeded(&mut self, timestamp: Instant) -> bool {
+        if timestamp - self.window_start >= Self::WINDOW {
+            *self = Self::new(timestamp);
         }
+        self.hits = self.hits.saturating_add(1);
+        self.hits > Self::MAX_HITS
     }
 }
 
-trait WaitForStable {
-    /// Minimum duration the input level is unchanged to be considered stable.
-    const STABLE_DURATION: Duration;
+/// Raw (unfiltered) waits for the input level, which [`WaitForStable`] builds on.
+pub(crate) trait WaitForLevel {
+    async fn wait_for_high(&mut self);
+    async fn wait_for_low(&mut self);
+}
+
+#[cfg(not(test))]
+impl WaitForLevel for Input<'_> {
+    async fn wait_for_high(&mut self) {
+        Input::wait_for_high(self).await
+    }
+
+    async fn wait_for_low(&mut self) {
+        Input::wait_for_low(self).await
+    }
+}
 
+pub(crate) trait WaitForStable {
     /// Wait until the pin is high, accounting for noise when the input level is stabilizing.
-    async fn wait_for_stable_high(&mut self);
+    ///
+    /// The level must be unchanged for `stable_duration` to be considered stable.
+    async fn wait_for_stable_high(&mut self, stable_duration: Duration);
     /// Wait until the pin is low, accounting for noise when the input level is stabilizing.
-    async fn wait_for_stable_low(&mut self);
+    ///
+    /// The level must be unchanged for `stable_duration` to be considered stable.
+    async fn wait_for_stable_low(&mut self, stable_duration: Duration);
 }
 
-impl WaitForStable for Input<'_> {
-    const STABLE_DURATION: Duration = Duration::from_micros(150);
-
-    async fn wait_for_stable_high(&mut self) {
+impl<T: WaitForLevel> WaitForStable for T {
+    async fn wait_for_stable_high(&mut self, stable_duration: Duration) {
         loop {
             self.wait_for_high().await;
 
-            if with_timeout(Self::STABLE_DURATION, self.wait_for_low()).await == Err(TimeoutError) {
-                // Unchanged for the STABLE_DURATION.
+            if with_timeout(stable_duration, self.wait_for_low()).await == Err(TimeoutError) {
+                // Unchanged for the stable_duration.
                 break;
             }
         }
     }
 
-    async fn wait_for_stable_low(&mut self) {
+    async fn wait_for_stable_low(&mut self, stable_duration: Duration) {
         loop {
             self.wait_for_low().await;
 
-            if with_timeout(Self::STABLE_DURATION, self.wait_for_high()).await == Err(TimeoutError)
-            {
-                // Unchanged for the STABLE_DURATION.
+            if with_timeout(stable_duration, self.wait_for_high()).await == Err(TimeoutError) {
+                // Unchanged for the stable_duration.
                 break;
             }
         }
     }
 }
 
+/// Waits in terms of the pad's sensor, whichever level it's triggered at.
+trait WaitForSensor: WaitForLevel + WaitForStable {
+    async fn wait_for_stable_hit(&mut self, polarity: SensorPolarity, stable_duration: Duration) {
+        match polarity {
+            SensorPolarity::Normal => self.wait_for_stable_low(stable_duration).await,
+            SensorPolarity::Inverted => self.wait_for_stable_high(stable_duration).await,
+        }
+    }
+
+    async fn wait_for_stable_release(
+        &mut self,
+        polarity: SensorPolarity,
+        stable_duration: Duration,
+    ) {
+        match polarity {
+            SensorPolarity::Normal => self.wait_for_stable_high(stable_duration).await,
+            SensorPolarity::Inverted => self.wait_for_stable_low(stable_duration).await,
+        }
+    }
+
+    async fn wait_for_hit(&mut self, polarity: SensorPolarity) {
+        match polarity {
+            SensorPolarity::Normal => self.wait_for_low().await,
+            SensorPolarity::Inverted => self.wait_for_high().await,
+        }
+    }
+
+    async fn wait_for_release(&mut self, polarity: SensorPolarity) {
+        match polarity {
+            SensorPolarity::Normal => self.wait_for_high().await,
+            SensorPolarity::Inverted => self.wait_for_low().await,
+        }
+    }
+}
+
+impl<T: WaitForLevel> WaitForSensor for T {}
+
+/// Pad whose raw edges are logged with the `trace-edges` feature, to see the actual bounce
+/// pattern that [`WaitForStable`] filters out with the pad's `stable_duration`.
+#[cfg(feature = "trace-edges")]
+const TRACE_EDGES_NOTE: DrumNote = DrumNote::Snare;
+
+/// Logs every edge of the input if `traced`.
+#[cfg(feature = "trace-edges")]
+struct EdgeTracer<'a, P> {
+    input: &'a mut P,
+    traced: bool,
+}
+
+#[cfg(feature = "trace-edges")]
+impl<P: WaitForLevel> WaitForLevel for EdgeTracer<'_, P> {
+    async fn wait_for_high(&mut self) {
+        self.input.wait_for_high().await;
+        if self.traced {
+            info!(
+                "[edges] {} rising at {=u64:us}",
+                TRACE_EDGES_NOTE,
+                Instant::now().as_micros()
+            );
+        }
+    }
+
+    async fn wait_for_low(&mut self) {
+        self.input.wait_for_low().await;
+        if self.traced {
+            info!(
+                "[edges] {} falling at {=u64:us}",
+                TRACE_EDGES_NOTE,
+                Instant::now().as_micros()
+            );
+        }
+    }
+}
+
+/// Send `hit_event` to the BLE side, counting and logging it for the telemetry.
+fn send_hit_event(
+    hit_events: &HitEventsChannel,
+    backpressure: &HitEventsBackpressure,
+    hit_event: HitEvent,
+) {
+    if let HitKind::Note { note, velocity, .. } = hit_event.kind {
+        COUNTERS.hits.increment();
+        HIT_LOG.record(hit_event.timestamp, hit_event.pad, note, velocity);
+    }
+    if hit_events.force_send(hit_event) {
+        COUNTERS.hits_dropped.increment();
+    }
+    backpressure.update(hit_events.len());
+}
+
 trait ForceSend<T> {
-    /// Force to send the message. Overwrite old if full.
-    fn force_send(&self, message: T);
+    /// Force to send the message. Overwrite old if full, returning whether it was.
+    fn force_send(&self, message: T) -> bool;
 }
 
 impl<M, T, const N: usize> ForceSend<T> for Channel<M, T, N>
 where
     M: RawMutex,
 {
-    fn force_send(&self, mut message: T) {
+    fn force_send(&self, mut message: T) -> bool {
+        let mut overwritten = false;
         while let Err(e) = self.try_send(message) {
             match e {
                 TrySendError::Full(m) => {
                     message = m;
-                    let _ = self.try_receive();
+                    overwritten |= self.try_receive().is_ok();
                 }
             }
         }
+        overwritten
     }
 }
 
-pub async fn blink(output: &mut Output<'_>, interval: Duration) -> ! {
-    let mut ticker = Ticker::every(interval);
-    let initial_level = output.output_level();
-    trace!(
-        "Start blinking {} with interval {}. Initially {}.",
-        output, interval, initial_level
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockTime, run_until};
 
-    let output = RefCell::new(output);
-    defer!({
-        let mut output = output.borrow_mut();
-        trace!("Stop blinking {}. Resetting to {}.", *output, initial_level);
-        output.set_level(initial_level);
-    });
+    /// An input replaying a waveform: its level starts `low` and toggles at each of the `edges`,
+    /// given in µs from when it's created.
+    struct ScriptedPin {
+        start: Instant,
+        edges: &'static [u64],
+    }
 
-    loop {
-        output.borrow_mut().toggle();
-        ticker.next().await;
+    impl ScriptedPin {
+        fn new(edges: &'static [u64]) -> Self {
+            Self {
+                start: Instant::now(),
+                edges,
+            }
+        }
+
+        fn is_high_at(&self, instant: Instant) -> bool {
+            let elapsed = (instant - self.start).as_micros();
+            self.edges.iter().filter(|&&edge| edge <= elapsed).count() % 2 == 1
+        }
+
+        async fn wait_for(&mut self, high: bool) {
+            while self.is_high_at(Instant::now()) != high {
+                let elapsed = (Instant::now() - self.start).as_micros();
+                match self.edges.iter().find(|&&edge| edge > elapsed) {
+                    Some(&edge) => Timer::at(self.start + Duration::from_micros(edge)).await,
+                    None => pending().await,
+                }
+            }
+        }
+    }
+
+    impl WaitForLevel for ScriptedPin {
+        async fn wait_for_high(&mut self) {
+            self.wait_for(true).await
+        }
+
+        async fn wait_for_low(&mut self) {
+            self.wait_for(false).await
+        }
+    }
+
+    /// How long after `start` it takes the pin to read stably high for `stable_duration`.
+    fn stable_high_after(time: &MockTime, edges: &'static [u64], stable_duration: u64) -> u64 {
+        let mut pin = ScriptedPin::new(edges);
+        let start = pin.start;
+        run_until(
+            time,
+            start + Duration::from_millis(100),
+            pin.wait_for_stable_high(Duration::from_micros(stable_duration)),
+        )
+        .expect("never stable");
+        (Instant::now() - start).as_micros()
+    }
+
+    #[test]
+    fn longer_stable_duration_rejects_a_glitch_a_shorter_one_passes() {
+        let time = MockTime::lock();
+        // A 300 µs glitch at 1 ms, then high for good from 5 ms.
+        const EDGES: &[u64] = &[1_000, 1_300, 5_000];
+        let short = stable_high_after(&time, EDGES, PadConfig::DEFAULT_STABLE_DURATION.as_micros());
+        assert!((1_150..1_300).contains(&short), "{short}");
+        let long = stable_high_after(&time, EDGES, 500);
+        assert!((5_500..5_700).contains(&long), "{long}");
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn short_pedal_press_is_a_splash_long_one_a_chick() {
+        let threshold = SplashThreshold {
+            window: ms(100),
+            hysteresis: ms(0),
+        };
+        let mut last_splash = false;
+        assert!(threshold.is_splash(ms(60), &mut last_splash));
+        assert!(threshold.is_splash(ms(100), &mut last_splash));
+        assert!(!threshold.is_splash(ms(101), &mut last_splash));
+        assert!(!threshold.is_splash(ms(500), &mut last_splash));
+        assert!(threshold.is_splash(ms(99), &mut last_splash));
+    }
+
+    #[test]
+    fn no_splash_without_a_window() {
+        let threshold = SplashThreshold {
+            window: ms(0),
+            hysteresis: ms(20),
+        };
+        let mut last_splash = true;
+        assert!(!threshold.is_splash(ms(0), &mut last_splash));
+        assert!(!threshold.is_splash(ms(10), &mut last_splash));
+    }
+
+    /// The 100 ms window with 20 ms of hysteresis of the example of
+    /// [`Config::hi_hat_splash_hysteresis`](crate::config::Config::hi_hat_splash_hysteresis).
+    const HYSTERESIS: SplashThreshold = SplashThreshold {
+        window: Duration::from_millis(100),
+        hysteresis: Duration::from_millis(20),
+    };
+
+    #[test]
+    fn slow_close_is_a_chick() {
+        let mut last_splash = false;
+        assert!(!HYSTERESIS.is_splash(ms(300), &mut last_splash));
+        let mut last_splash = true;
+        assert!(!HYSTERESIS.is_splash(ms(300), &mut last_splash));
+    }
+
+    #[test]
+    fn fast_close_open_is_a_splash() {
+        let mut last_splash = false;
+        assert!(HYSTERESIS.is_splash(ms(40), &mut last_splash));
+        let mut last_splash = true;
+        assert!(HYSTERESIS.is_splash(ms(40), &mut last_splash));
+    }
+
+    #[test]
+    fn borderline_presses_keep_the_last_classification() {
+        let mut last_splash = false;
+        let from_chicks =
+            [110, 95, 110, 81].map(|held| HYSTERESIS.is_splash(ms(held), &mut last_splash));
+        assert_eq!(from_chicks, [false; 4]);
+        assert!(HYSTERESIS.is_splash(ms(80), &mut last_splash));
+        let from_splash =
+            [95, 110, 120, 105].map(|held| HYSTERESIS.is_splash(ms(held), &mut last_splash));
+        assert_eq!(from_splash, [true; 4]);
+        assert!(!HYSTERESIS.is_splash(ms(121), &mut last_splash));
+        assert!(!HYSTERESIS.is_splash(ms(95), &mut last_splash));
+    }
+
+    /// Velocities of the hits sensed in turn.
+    struct ScriptedVelocities(Cell<&'static [u8]>);
+
+    impl VelocitySource for ScriptedVelocities {
+        async fn velocity(&self, _pad: usize) -> u8 {
+            let (&velocity, rest) = self.0.get().split_first().expect("no velocity left");
+            self.0.set(rest);
+            velocity
+        }
+    }
+
+    /// The velocity of a stroke of two humps, as merged with `double_trigger_window`.
+    fn double_hump_velocity(time: &MockTime, double_trigger_window: Duration) -> u8 {
+        // Hit at 0 at velocity 60, then a second hump from 2 ms to 3 ms, at 90.
+        let mut pin = ScriptedPin::new(&[1_000, 2_000, 3_000]);
+        let velocities = ScriptedVelocities(Cell::new(&[90]));
+        let pad = PadConfig::new(DrumNote::Snare).with_double_trigger_window(double_trigger_window);
+        let merged = merge_double_triggers(&mut pin, &pad, 0, Instant::now(), 60, &velocities);
+        run_until(time, Instant::now() + ms(100), merged).expect("never merged")
+    }
+
+    #[test]
+    fn double_trigger_window_merges_the_humps_of_a_stroke() {
+        let time = MockTime::lock();
+        assert_eq!(double_hump_velocity(&time, ms(5)), 90);
+    }
+
+    #[test]
+    fn humps_past_the_double_trigger_window_are_left_to_the_debounce() {
+        let time = MockTime::lock();
+        assert_eq!(double_hump_velocity(&time, ms(0)), 60);
+        assert_eq!(double_hump_velocity(&time, ms(1)), 60);
+    }
+
+    #[test]
+    fn pedal_state_as_of_the_hit() {
+        let pedal = PedalState::new();
+        let hit_at = Instant::from_millis(1_000);
+        assert!(!pedal.pressed_at(hit_at));
+        // Pressed after the hit was captured, before it's processed: the hit was still open.
+        pedal.set(true, hit_at + ms(2));
+        assert!(!pedal.pressed_at(hit_at));
+        assert!(pedal.pressed_at(hit_at + ms(2)));
+        // Released after a hit while pressed: the hit was closed.
+        pedal.set(false, hit_at + ms(10));
+        assert!(pedal.pressed_at(hit_at + ms(5)));
+        assert!(!pedal.pressed_at(hit_at + ms(10)));
+    }
+
+    /// Strokes of 3 ms at 50 ms, so while the sensors settle, then at 300, 400 and 500 ms, on a
+    /// pin idling high from the start.
+    const STROKES: &[u64] = &[
+        0, 50_000, 53_000, 300_000, 303_000, 400_000, 403_000, 500_000, 503_000,
+    ];
+
+    /// The hit events of `pad`, its pin replaying `edges` and its hits sensed at `velocities` in
+    /// turn, with the sensors switched on at the start and settled 200 ms later. The timestamps are in ms from the start.
+    fn watched_hits(
+        time: &MockTime,
+        edges: &'static [u64],
+        pad: PadConfig,
+        warmup_hits: u8,
+        velocities: &'static [u8],
+    ) -> Vec<(u64, HitKind), HIT_EVENTS_DEPTH> {
+        let mut pin = ScriptedPin::new(edges);
+        let start = pin.start;
+        let stuck = [const { Cell::new(false) }; PAD_COUNT];
+        let backpressure = HitEventsBackpressure::new();
+        let state = SharedPinsState {
+            // Another pad idling high, so that the hits aren't taken for the sensors going off.
+            pin_high_count: Cell::new(1),
+            counted_pads: 2,
+            stuck: &stuck,
+            stuck_pad_timeout: ms(0),
+            pedal_hi_hat: PedalState::new(),
+            settled_at: start + ms(200),
+            hi_hat_splash: SplashThreshold {
+                window: ms(0),
+                hysteresis: ms(0),
+            },
+            warmup_hits,
+            backpressure: &backpressure,
+        };
+        let velocities = ScriptedVelocities(Cell::new(velocities));
+        let hit_events = HitEventsChannel::new();
+        let watched = watch_pin_for_hits(&mut pin, 0, pad, &state, &velocities, &hit_events);
+        assert!(run_until(time, start + ms(1_000), watched).is_none());
+        let mut hits = Vec::new();
+        while let Ok(hit_event) = hit_events.try_receive() {
+            let at = (hit_event.timestamp - start).as_millis();
+            assert!(hits.push((at, hit_event.kind)).is_ok());
+        }
+        hits
+    }
+
+    /// When the hits of the [`STROKES`] of a snare were played.
+    #[cfg(not(feature = "raw-hits"))]
+    fn played_strokes(time: &MockTime, warmup_hits: u8) -> Vec<u64, HIT_EVENTS_DEPTH> {
+        let pad = PadConfig::new(DrumNote::Snare);
+        let hits = watched_hits(time, STROKES, pad, warmup_hits, &[100; 4]);
+        hits.iter().map(|&(at, _)| at).collect()
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn hits_while_the_sensors_settle_are_ignored() {
+        let time = MockTime::lock();
+        assert_eq!(played_strokes(&time, 0), [300, 400, 500]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn first_hit_after_switching_on_is_ignored() {
+        let time = MockTime::lock();
+        assert_eq!(played_strokes(&time, 1), [400, 500]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn warmup_hits_are_each_ignored_then_the_real_hits_play() {
+        let time = MockTime::lock();
+        assert_eq!(played_strokes(&time, 2), [500]);
+        assert_eq!(played_strokes(&time, 3), []);
+    }
+
+    /// A press at 300 ms, held down until 800 ms.
+    #[test]
+    #[cfg(feature = "raw-hits")]
+    fn raw_hits_play_every_stable_hit_at_the_sensed_velocity() {
+        use crate::{
+            config::{Config, VelocityCurve},
+            midi::{XorShift32, build_note_on},
+        };
+        use midi_types::MidiMessage;
+
+        let time = MockTime::lock();
+        let pad = PadConfig::new(DrumNote::Snare);
+        let hits = watched_hits(&time, STROKES, pad, 1, &[30, 60, 90, 120]);
+        let played = hits.iter().map(|(at, kind)| match *kind {
+            HitKind::Note {
+                note: DrumNote::Snare,
+                velocity,
+                until_release: false,
+            } => (*at, velocity),
+            _ => panic!("not a snare note at {at} ms"),
+        });
+        // Settling and warming up, or not.
+        assert!(played.eq([(50, 30), (300, 60), (400, 90), (500, 120)]));
+        // And sent as sensed, the config's velocity processing notwithstanding.
+        let mut config = Config::default();
+        config.velocity_gains[DrumNote::Snare.index()] = 200;
+        config.velocity_curve = VelocityCurve::Hard;
+        config.humanize_velocity = 20;
+        for pad in &mut config.pads {
+            pad.velocity_range = (70, 80);
+        }
+        let note_on = build_note_on(0, DrumNote::Snare, 30, &config, &mut XorShift32::new(1));
+        assert!(matches!(note_on, MidiMessage::NoteOn(_, _, velocity) if u8::from(velocity) == 30));
+    }
+
+    const PRESS_AND_HOLD: &[u64] = &[0, 300_000, 800_000];
+
+    #[test]
+    #[cfg(not(feature = "raw-hits"))]
+    fn gate_following_the_sensor_releases_with_it() {
+        let time = MockTime::lock();
+        let pad = PadConfig::new(DrumNote::CrashCymbal1).with_gate_follows_sensor();
+        let hits = watched_hits(&time, PRESS_AND_HOLD, pad, 0, &[100; 4]);
+        assert!(matches!(
+            hits[..],
+            [
+                (
+                    300,
+                    HitKind::Note {
+                        until_release: true,
+                        ..
+                    }
+                ),
+                (800, HitKind::Release)
+            ]
+        ));
+    }
+
+    #[test]
+    fn fixed_gate_ignores_the_release() {
+        let time = MockTime::lock();
+        let pad = PadConfig::new(DrumNote::CrashCymbal1);
+        let hits = watched_hits(&time, PRESS_AND_HOLD, pad, 0, &[100; 4]);
+        assert!(matches!(
+            hits[..],
+            [(
+                300,
+                HitKind::Note {
+                    until_release: false,
+                    ..
+                }
+            )]
+        ));
     }
 }
@@ -0,0 +1,136 @@
+//! Measuring the idle noise of the pads: how often and for how long each one glitches away from
+//! its idle level while nobody plays, to pick a `stable_duration` riding it out.
+
+use core::{cell::Cell, pin::pin};
+
+use embassy_futures::select::select_slice;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::NoopRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, with_timeout};
+#[cfg(not(test))]
+use esp_hal::gpio::Input;
+use heapless::Vec;
+
+use super::WaitForSensor;
+use crate::config::{PAD_COUNT, PadConfig, SensorPolarity};
+use crate::tasks::led::{LedPattern, LedPatternSender};
+
+/// How long the pads are listened to. They must be left alone meanwhile, as a hit counts as a
+/// (long) glitch.
+pub const CALIBRATION_DURATION: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum CalibrationStatus {
+    NotRun = 0,
+    Running = 1,
+    /// Not run because a pad was being hit when requested.
+    Busy = 2,
+    Done = 3,
+}
+
+/// Idle noise of a pad measured by calibration.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct PadNoise {
+    /// Number of times the level left idle.
+    pub glitches: u16,
+    /// Longest time spent away from idle. A `stable_duration` above this filters all the ones
+    /// measured.
+    pub longest_glitch: Duration,
+}
+
+pub type CalibrationReport = (CalibrationStatus, [PadNoise; PAD_COUNT]);
+
+/// Calibration requests and the last report, shared between
+/// [`watch_gpios_task`](super::watch_gpios_task) running them and whoever asks for them.
+pub struct Calibration {
+    requested: Signal<NoopRawMutex, ()>,
+    report: Mutex<NoopRawMutex, Cell<CalibrationReport>>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Calibration {
+    pub const fn new() -> Self {
+        Self {
+            requested: Signal::new(),
+            report: Mutex::new(Cell::new((
+                CalibrationStatus::NotRun,
+                [PadNoise {
+                    glitches: 0,
+                    longest_glitch: Duration::from_ticks(0),
+                }; PAD_COUNT],
+            ))),
+        }
+    }
+
+    /// Ask for a calibration. Hits aren't played while it runs.
+    pub fn request(&self) {
+        self.requested.signal(());
+    }
+
+    pub fn report(&self) -> CalibrationReport {
+        self.report.lock(Cell::get)
+    }
+
+    pub(super) async fn wait_requested(&self) {
+        self.requested.wait().await
+    }
+
+    pub(super) fn set_report(&self, report: CalibrationReport) {
+        self.report.lock(|r| r.set(report));
+    }
+}
+
+/// Listen to the pads for [`CALIBRATION_DURATION`], or `None` if any of them isn't idle to begin
+/// with, i.e. a hit is in progress. Shown on the status LED as [`LedPattern::Calibrating`] while
+/// listening.
+#[cfg(not(test))]
+pub(super) async fn calibrate(
+    inputs: &mut [Input<'_>; PAD_COUNT],
+    pads: &[PadConfig; PAD_COUNT],
+    status_led: LedPatternSender<'_>,
+) -> Option<[PadNoise; PAD_COUNT]> {
+    let is_idle = |pin: &Input<'_>, pad: &PadConfig| match pad.polarity {
+        SensorPolarity::Normal => pin.is_high(),
+        SensorPolarity::Inverted => pin.is_low(),
+    };
+    if !inputs.iter().zip(pads).all(|(pin, pad)| is_idle(pin, pad)) {
+        return None;
+    }
+
+    status_led
+        .send(LedPattern::Calibrating(CALIBRATION_DURATION))
+        .await;
+    let mut noise = [PadNoise::default(); PAD_COUNT];
+    // Never completes on its own, only times out.
+    let _ = with_timeout(
+        CALIBRATION_DURATION,
+        select_slice(pin!(
+            inputs
+                .iter_mut()
+                .zip(pads)
+                .zip(&mut noise)
+                .map(|((pin, pad), noise)| async move {
+                    loop {
+                        pin.wait_for_hit(pad.polarity).await;
+                        let start = Instant::now();
+                        pin.wait_for_release(pad.polarity).await;
+
+                        noise.glitches = noise.glitches.saturating_add(1);
+                        noise.longest_glitch = noise.longest_glitch.max(start.elapsed());
+                    }
+                })
+                .collect::<Vec<_, PAD_COUNT>>()
+                .as_mut_slice()
+        )),
+    )
+    .await;
+    Some(noise)
+}
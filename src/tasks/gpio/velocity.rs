@@ -0,0 +1,98 @@
+//! Where the velocity of a hit comes from. The pads themselves only give a digital hit, so
+//! sensing how hard they're hit takes a separate analog reading, such as an ADC channel per pad.
+
+#[cfg(all(feature = "adc-velocity", not(test)))]
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+#[cfg(all(feature = "adc-velocity", not(test)))]
+use esp_hal::{
+    Async,
+    analog::adc::{Adc, AdcConfig, AdcPin, Attenuation},
+    peripherals::{ADC1, GPIO4, GPIO5},
+};
+
+use crate::midi::DEFAULT_VELOCITY;
+
+/// Velocity of a hit, sampled right after it's detected.
+///
+/// Called concurrently for different pads, so implementations sharing a bus (e.g. an ADS1115 on
+/// I2C or an MCP3008 on SPI, for more analog channels than the ESP32-C3's ADC1 has) must
+/// serialize the accesses themselves, e.g. with an async mutex. Sampling should take well under
+/// the pad's debounce interval, as the pad isn't watched meanwhile.
+pub trait VelocitySource {
+    /// Velocity (1..=127) of the hit just detected on the pad at `pad` in `Config::pads`.
+    async fn velocity(&self, pad: usize) -> u8;
+}
+
+/// Every hit at [`DEFAULT_VELOCITY`], for pads without analog sensing.
+pub struct FixedVelocity;
+
+impl VelocitySource for FixedVelocity {
+    async fn velocity(&self, _pad: usize) -> u8 {
+        DEFAULT_VELOCITY
+    }
+}
+
+/// With the `adc-velocity` feature, the two pads last in `Config::pads` (the bass drum and the
+/// snare by default) sampled on the ESP32-C6's ADC1, for their velocity. The other pads play at
+/// [`DEFAULT_VELOCITY`].
+///
+/// | Pad | ADC1 pin |
+/// |-----|----------|
+/// | 8   | GPIO4    |
+/// | 9   | GPIO5    |
+///
+/// Each pin reads the peak of the pad's sensor, held past the hit's digital trigger: e.g. a piezo
+/// through a Schottky diode into 100 nF with a 1 MΩ bleed to ground, clamped to 3.3 V. Full scale
+/// is around 3.3 V at the 11 dB attenuation, mapped linearly to the velocity.
+///
+/// No ESP32-C3 support: its ADC1 pins (GPIO0 to GPIO4) are all pads or the panic button already,
+/// and ADC2 can't be used alongside the radio. An external ADC on I2C or SPI would be the way
+/// there, as another [`VelocitySource`].
+#[cfg(all(feature = "adc-velocity", not(test)))]
+pub struct AdcVelocity {
+    channels: Mutex<NoopRawMutex, AdcChannels>,
+}
+
+#[cfg(all(feature = "adc-velocity", not(test)))]
+struct AdcChannels {
+    adc: Adc<'static, ADC1<'static>, Async>,
+    pad_8: AdcPin<GPIO4<'static>, ADC1<'static>>,
+    pad_9: AdcPin<GPIO5<'static>, ADC1<'static>>,
+}
+
+#[cfg(all(feature = "adc-velocity", not(test)))]
+impl AdcVelocity {
+    pub fn new(adc1: ADC1<'static>, gpio4: GPIO4<'static>, gpio5: GPIO5<'static>) -> Self {
+        let mut config = AdcConfig::new();
+        let pad_8 = config.enable_pin(gpio4, Attenuation::_11dB);
+        let pad_9 = config.enable_pin(gpio5, Attenuation::_11dB);
+        let adc = Adc::new(adc1, config).into_async();
+        Self {
+            channels: Mutex::new(AdcChannels { adc, pad_8, pad_9 }),
+        }
+    }
+}
+
+#[cfg(all(feature = "adc-velocity", not(test)))]
+impl VelocitySource for AdcVelocity {
+    async fn velocity(&self, pad: usize) -> u8 {
+        if !matches!(pad, 8 | 9) {
+            return DEFAULT_VELOCITY;
+        }
+        let mut channels = self.channels.lock().await;
+        let AdcChannels { adc, pad_8, pad_9 } = &mut *channels;
+        let reading = match pad {
+            8 => adc.read_oneshot(pad_8).await,
+            _ => adc.read_oneshot(pad_9).await,
+        };
+        // 12 bits down to 7.
+        (reading >> 5).clamp(1, 127) as u8
+    }
+}
+
+/// The velocity source the firmware is built with.
+#[cfg(any(not(feature = "adc-velocity"), test))]
+pub type PadVelocitySource = FixedVelocity;
+/// The velocity source the firmware is built with.
+#[cfg(all(feature = "adc-velocity", not(test)))]
+pub type PadVelocitySource = AdcVelocity;
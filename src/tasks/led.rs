@@ -0,0 +1,202 @@
+use core::future::pending;
+use defer::defer;
+use defmt::trace;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{Channel, Receiver, Sender},
+};
+use embassy_time::{Duration, Ticker, Timer, with_timeout};
+#[cfg(not(test))]
+use esp_hal::gpio::Output;
+
+#[cfg(all(feature = "ws2812", not(test)))]
+pub mod ws2812;
+
+/// What the status LED shows.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum LedPattern {
+    Off,
+    /// Toggle every `interval` until told otherwise.
+    Blink(Duration),
+    /// Toggle every `interval` for `duration`, then turn off.
+    Burst {
+        interval: Duration,
+        duration: Duration,
+    },
+    /// Flash `count` times, then go back to the pattern it interrupted. For feedback on user
+    /// input.
+    Flash(u8),
+    /// Flash `count` times, pause and repeat forever. For errors which stop the firmware.
+    ErrorCode(u8),
+    /// Lit for `duration`, then go back to the pattern it interrupted, like a flash. For the pads
+    /// being calibrated, to be left alone meanwhile.
+    Calibrating(Duration),
+}
+
+impl LedPattern {
+    /// Color of the pattern on an RGB LED. Blinking is for advertising and the burst for a new
+    /// connection.
+    const fn color(self) -> Color {
+        match self {
+            Self::Off => Color::OFF,
+            Self::Blink(_) => Color::BLUE,
+            Self::Burst { .. } => Color::GREEN,
+            Self::Flash(_) => Color::WHITE,
+            Self::ErrorCode(_) => Color::RED,
+            Self::Calibrating(_) => Color::YELLOW,
+        }
+    }
+
+    /// Whether the pattern goes back to the one it interrupted when done.
+    const fn is_transient(self) -> bool {
+        matches!(self, Self::Flash(_) | Self::Calibrating(_))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    // Dim, a WS2812 at full brightness is blinding.
+    pub const OFF: Self = Self::rgb(0, 0, 0);
+    pub const RED: Self = Self::rgb(32, 0, 0);
+    pub const GREEN: Self = Self::rgb(0, 32, 0);
+    pub const BLUE: Self = Self::rgb(0, 0, 32);
+    pub const WHITE: Self = Self::rgb(20, 20, 20);
+    pub const YELLOW: Self = Self::rgb(24, 16, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// A status LED which can be lit with a color, or just on/off for a single-color one.
+pub trait StatusLight {
+    fn set(&mut self, color: Color);
+}
+
+/// The on-board LED, active low. Lit for any color but [`Color::OFF`].
+#[cfg(not(test))]
+impl StatusLight for Output<'_> {
+    fn set(&mut self, color: Color) {
+        self.set_level((color == Color::OFF).into());
+    }
+}
+
+/// The status LED the firmware is built for: a WS2812 on the `ws2812` feature, otherwise the
+/// plain on-board LED.
+#[cfg(all(feature = "ws2812", not(test)))]
+pub type StatusLed = ws2812::Ws2812<'static>;
+#[cfg(all(not(feature = "ws2812"), not(test)))]
+pub type StatusLed = Output<'static>;
+
+pub type LedPatternChannel = Channel<NoopRawMutex, LedPattern, 4>;
+pub type LedPatternSender<'ch> = Sender<'ch, NoopRawMutex, LedPattern, 4>;
+pub type LedPatternReceiver<'ch> = Receiver<'ch, NoopRawMutex, LedPattern, 4>;
+
+/// Show the patterns sent to the status LED, each one replacing the previous.
+#[cfg(not(test))]
+#[embassy_executor::task]
+pub async fn status_led_task(mut led: StatusLed, patterns: LedPatternReceiver<'static>) -> ! {
+    // The last pattern that isn't transient, to go back to after one.
+    let mut base = LedPattern::Off;
+    let mut current = base;
+    loop {
+        match select(show(&mut led, current), patterns.receive()).await {
+            Either::First(()) => {
+                // Only a transient pattern goes back to what it interrupted, others end off.
+                if !current.is_transient() {
+                    base = LedPattern::Off;
+                }
+                current = base;
+            }
+            Either::Second(pattern) => {
+                if !pattern.is_transient() {
+                    base = pattern;
+                }
+                current = pattern;
+            }
+        }
+    }
+}
+
+/// Returns once a pattern that doesn't last forever is done.
+async fn show(led: &mut impl StatusLight, pattern: LedPattern) {
+    const FLASH_PAUSE: Duration = Duration::from_millis(600);
+    const ERROR_CODE_PAUSE: Duration = Duration::from_millis(1200);
+
+    let color = pattern.color();
+    led.set(Color::OFF);
+    match pattern {
+        LedPattern::Off => pending().await,
+        LedPattern::Blink(interval) => blink(led, color, interval).await,
+        LedPattern::Burst { interval, duration } => {
+            let _ = with_timeout(duration, blink(led, color, interval)).await;
+        }
+        LedPattern::Flash(count) => {
+            flash(led, color, count).await;
+            Timer::after(FLASH_PAUSE).await;
+        }
+        LedPattern::ErrorCode(count) => loop {
+            flash(led, color, count).await;
+            Timer::after(ERROR_CODE_PAUSE).await;
+        },
+        LedPattern::Calibrating(duration) => {
+            let mut led = OffOnDrop(led);
+            led.set(color);
+            Timer::after(duration).await;
+        }
+    }
+}
+
+/// Flash `led` `count` times with `color`. Left off when done, or when cancelled (dropped)
+/// mid-flash.
+async fn flash(led: &mut impl StatusLight, color: Color, count: u8) {
+    const FLASH: Duration = Duration::from_millis(200);
+
+    let mut led = OffOnDrop(led);
+    for _ in 0..count {
+        led.set(color);
+        Timer::after(FLASH).await;
+        led.set(Color::OFF);
+        Timer::after(FLASH).await;
+    }
+}
+
+/// Blink `led` with `color`, starting lit. Never returns, so it's always ended by being cancelled
+/// (dropped), e.g. by a `select` or `with_timeout`, which leaves the LED off whatever the point of
+/// the blink.
+pub async fn blink(led: &mut impl StatusLight, color: Color, interval: Duration) -> ! {
+    let mut ticker = Ticker::every(interval);
+    trace!("Start blinking {} with interval {}.", color, interval);
+
+    defer!(trace!("Stop blinking {}.", color));
+    let mut led = OffOnDrop(led);
+    let mut lit = false;
+    loop {
+        lit = !lit;
+        led.set(if lit { color } else { Color::OFF });
+        ticker.next().await;
+    }
+}
+
+/// Turns the LED off when dropped, for the patterns to leave it in a defined state however they
+/// end, cancellation included, rather than wherever they were at.
+struct OffOnDrop<'a, L: StatusLight>(&'a mut L);
+
+impl<L: StatusLight> StatusLight for OffOnDrop<'_, L> {
+    fn set(&mut self, color: Color) {
+        self.0.set(color);
+    }
+}
+
+impl<L: StatusLight> Drop for OffOnDrop<'_, L> {
+    fn drop(&mut self) {
+        self.0.set(Color::OFF);
+    }
+}
@@ -0,0 +1,68 @@
+//! Single WS2812 (NeoPixel) addressable LED driven by the RMT peripheral, like the one on GPIO8 of
+//! the ESP32-C3-DevKitM-1 and similar boards.
+
+use esp_hal::{
+    Blocking,
+    gpio::{Level, interconnect::PeripheralOutput},
+    rmt::{Channel, PulseCode, Tx, TxChannelConfig, TxChannelCreator},
+};
+
+use super::{Color, StatusLight};
+
+// In ticks of the 80 MHz RMT clock, i.e. 12.5 ns.
+const T0H: u16 = 32; // 0.4 µs
+const T0L: u16 = 68; // 0.85 µs
+const T1H: u16 = 64; // 0.8 µs
+const T1L: u16 = 36; // 0.45 µs
+
+/// 24 bits of color plus the end marker.
+const FRAME_LEN: usize = 24 + 1;
+
+pub struct Ws2812<'d> {
+    // Taken for the duration of a transmission.
+    channel: Option<Channel<'d, Blocking, Tx>>,
+}
+
+impl<'d> Ws2812<'d> {
+    /// `rmt_channel` must run off an 80 MHz RMT clock, e.g. a channel of
+    /// `Rmt::new(peripherals.RMT, Rate::from_mhz(80))`.
+    pub fn new(
+        rmt_channel: impl TxChannelCreator<'d, Blocking>,
+        pin: impl PeripheralOutput<'d>,
+    ) -> Result<Self, esp_hal::rmt::Error> {
+        let config = TxChannelConfig::default()
+            .with_clk_divider(1)
+            .with_idle_output_level(Level::Low)
+            .with_idle_output(true)
+            .with_carrier_modulation(false);
+        let channel = rmt_channel.configure_tx(pin, config)?;
+        Ok(Self {
+            channel: Some(channel),
+        })
+    }
+}
+
+impl StatusLight for Ws2812<'_> {
+    /// Blocks for the ~30 µs the frame takes to send.
+    fn set(&mut self, color: Color) {
+        let mut frame: [u32; FRAME_LEN] = [PulseCode::empty(); FRAME_LEN];
+        // Sent in GRB order, most significant bit first.
+        let bits = u32::from_be_bytes([0, color.g, color.r, color.b]);
+        for (i, pulse) in frame[..24].iter_mut().enumerate() {
+            *pulse = if bits & (1 << (23 - i)) != 0 {
+                PulseCode::new(Level::High, T1H, Level::Low, T1L)
+            } else {
+                PulseCode::new(Level::High, T0H, Level::Low, T0L)
+            };
+        }
+
+        let Some(channel) = self.channel.take() else {
+            return;
+        };
+        self.channel = match channel.transmit(&frame).map(|t| t.wait()) {
+            Ok(Ok(channel)) | Ok(Err((_, channel))) => Some(channel),
+            // The channel is gone along with the failed transaction, leaving the LED as is.
+            Err(_) => None,
+        };
+    }
+}
@@ -0,0 +1,142 @@
+use defmt::{Debug2Format, error, info, warn};
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{Channel, Receiver, Sender},
+};
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(not(test))]
+use crate::config::nvs::Nvs;
+use crate::config::{
+    SharedConfig,
+    nvs::{MAX_PRESETS, PresetName},
+};
+use crate::tasks::gpio::ReloadPadsSignal;
+use crate::tasks::led::{LedPattern, LedPatternSender};
+
+/// How long the config has to stay unchanged before it's saved, so that a burst of changes (e.g.
+/// dragging a velocity curve point around in a companion app) wears the flash once rather than
+/// once per change.
+const SAVE_IDLE_DELAY: Duration = Duration::from_secs(2);
+/// Longest a change waits to be saved while the config keeps changing.
+const MAX_SAVE_DELAY: Duration = Duration::from_secs(10);
+
+/// What to do with the config presets stored besides the config, see [`persist_config_task`].
+#[derive(Clone, Copy, defmt::Format)]
+pub enum PresetRequest {
+    /// Load the stored preset following the last one loaded or stored, wrapping around and
+    /// skipping the empty slots.
+    Next,
+    /// Load the preset in the slot.
+    Load(u8),
+    /// Store the current config as the preset in the slot, under the name.
+    Store(u8, PresetName),
+}
+
+pub type PresetRequestsChannel = Channel<NoopRawMutex, PresetRequest, 2>;
+pub type PresetRequestsReceiver<'ch> = Receiver<'ch, NoopRawMutex, PresetRequest, 2>;
+pub type PresetRequestsSender<'ch> = Sender<'ch, NoopRawMutex, PresetRequest, 2>;
+
+/// Save the config once it has stayed unchanged for [`SAVE_IDLE_DELAY`] (or after
+/// [`MAX_SAVE_DELAY`] of changes), or right away when
+/// [requested](SharedConfig::request_save), e.g. by applying a config transaction.
+///
+/// A change is only safe from a reset once saved, which is logged: one still waiting for the delay
+/// to pass is lost to a reset, and the config stored before it is loaded on boot.
+///
+/// Also serves the [`PresetRequest`]s, as the owner of the flash. A loaded preset replaces the
+/// whole config live: the pads are re-armed with it, the status LED flashes the preset's slot
+/// number (1 to [`MAX_PRESETS`]), and it's then saved as the config like any change, so it's still
+/// the one in use after a reset.
+#[cfg(not(test))]
+#[embassy_executor::task]
+pub async fn persist_config_task(
+    mut nvs: Nvs,
+    config: &'static SharedConfig,
+    preset_requests: PresetRequestsReceiver<'static>,
+    reload_pads: &'static ReloadPadsSignal,
+    status_led: LedPatternSender<'static>,
+) {
+    // Of the last preset loaded or stored, for `PresetRequest::Next` to go on from.
+    let mut active_preset: Option<u8> = None;
+
+    loop {
+        match select3(
+            config.wait_updated(),
+            config.wait_save_requested(),
+            preset_requests.receive(),
+        )
+        .await
+        {
+            Either3::First(()) => {
+                let deadline = Instant::now() + MAX_SAVE_DELAY;
+                loop {
+                    let idle = Timer::at((Instant::now() + SAVE_IDLE_DELAY).min(deadline));
+                    match select3(idle, config.wait_updated(), config.wait_save_requested()).await {
+                        Either3::Second(()) => {}
+                        Either3::First(()) | Either3::Third(()) => break,
+                    }
+                }
+            }
+            Either3::Second(()) => {}
+            Either3::Third(request) => {
+                let loaded = match request {
+                    PresetRequest::Next => {
+                        let first = active_preset.map_or(0, |slot| (slot + 1) % MAX_PRESETS);
+                        let found = (0..MAX_PRESETS)
+                            .map(|i| (first + i) % MAX_PRESETS)
+                            .find_map(|slot| nvs.load_preset(slot).map(|preset| (slot, preset)));
+                        if found.is_none() {
+                            warn!("[nvs] no preset stored to switch to");
+                        }
+                        found
+                    }
+                    PresetRequest::Load(slot) => {
+                        let found = nvs.load_preset(slot).map(|preset| (slot, preset));
+                        if found.is_none() {
+                            warn!("[nvs] no preset stored in slot {}", slot);
+                        }
+                        found
+                    }
+                    PresetRequest::Store(slot, name) => {
+                        match nvs.save_preset(slot, &name, &config.read(|c| *c)) {
+                            Ok(()) => {
+                                info!(
+                                    "[nvs] config stored as preset {} ({=[u8]:a})",
+                                    slot,
+                                    trim_name(&name)
+                                );
+                                active_preset = Some(slot);
+                            }
+                            Err(e) => error!("[nvs] failed to store preset: {}", Debug2Format(&e)),
+                        }
+                        None
+                    }
+                };
+                if let Some((slot, (name, preset))) = loaded {
+                    config.update(|c| *c = preset);
+                    reload_pads.signal(());
+                    info!("[nvs] preset {} ({=[u8]:a}) loaded", slot, trim_name(&name));
+                    active_preset = Some(slot);
+                    status_led.send(LedPattern::Flash(slot + 1)).await;
+                }
+                // Saved through the update made by loading, once it stops changing.
+                continue;
+            }
+        }
+
+        let snapshot = config.read(|c| *c);
+        match nvs.save(&snapshot) {
+            Ok(true) => info!("[nvs] config saved"),
+            Ok(false) => info!("[nvs] config unchanged, not saved"),
+            Err(e) => error!("[nvs] failed to save config: {}", Debug2Format(&e)),
+        }
+    }
+}
+
+/// `name` without its zero padding.
+fn trim_name(name: &PresetName) -> &[u8] {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    &name[..len]
+}
@@ -0,0 +1,236 @@
+//! Health counters for long sessions, logged every [`TELEMETRY_INTERVAL`] to be checked without a
+//! debugger attached, and the [`HIT_LOG`] of the latest hits.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::info;
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::tasks::gpio::DrumNote;
+
+/// How often [`telemetry_task`] logs the counters.
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the heap usage is sampled for [`peak_heap_used`]. Allocations freed again in between
+/// samples go unseen, which the BLE controller's buffers, allocated once and kept, aren't.
+const HEAP_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counted from wherever it happens, hence a static rather than yet another reference threaded
+/// through the tasks.
+pub static COUNTERS: Counters = Counters::new();
+
+pub struct Counters {
+    /// Note hits detected, whether or not they made it to the BLE link.
+    pub hits: Counter,
+    /// Hits overwritten in the full hit events channel before being sent.
+    pub hits_dropped: Counter,
+    /// Notifications that errored or timed out.
+    pub notify_failures: Counter,
+    /// BLE connections established.
+    pub connections: Counter,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            hits: Counter::new(),
+            hits_dropped: Counter::new(),
+            notify_failures: Counter::new(),
+            connections: Counter::new(),
+        }
+    }
+}
+
+/// The latest note hits as registered by the pads, for telling what the firmware saw from what
+/// the host got, when hits go missing or play the wrong drum. Read over GATT, see
+/// [`encode_hit_log`](crate::tasks::ble::control::encode_hit_log).
+pub static HIT_LOG: HitLog = HitLog::new();
+
+/// Hits kept by [`HIT_LOG`]. Each takes 8 bytes of RAM.
+pub const HIT_LOG_LEN: usize = 32;
+
+/// A ring buffer of the latest [`HIT_LOG_LEN`] hits, overwriting the oldest one when full.
+///
+/// Made of plain atomic loads and stores, like the [`Counter`]s, so recording a hit takes no lock
+/// and is only a few stores on the hit path. Each entry is two words, `[millis, pad << 16 | note
+/// << 8 | velocity]`, with the same executor caveat as the counters for them to stay consistent.
+pub struct HitLog {
+    entries: [[AtomicU32; 2]; HIT_LOG_LEN],
+    /// Hits recorded since boot, wrapping. The next entry written is at this modulo the length.
+    recorded: AtomicU32,
+}
+
+/// A hit of the [`HitLog`].
+#[derive(Clone, Copy, defmt::Format)]
+pub struct LoggedHit {
+    /// Timestamp of the hit, in milliseconds since boot, wrapping at `u32::MAX`.
+    pub millis: u32,
+    /// Index in `Config::pads`.
+    pub pad: u8,
+    /// The drum registered, before the note map, e.g. the closed hi-hat for the open hi-hat pad
+    /// hit with the pedal pressed.
+    pub note: DrumNote,
+    /// As sensed, before the velocity gains, curve and range.
+    pub velocity: u8,
+}
+
+impl HitLog {
+    const fn new() -> Self {
+        Self {
+            entries: [const { [const { AtomicU32::new(0) }; 2] }; HIT_LOG_LEN],
+            recorded: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, timestamp: Instant, pad: usize, note: DrumNote, velocity: u8) {
+        let recorded = self.recorded.load(Ordering::Relaxed);
+        let [millis, hit] = &self.entries[recorded as usize % HIT_LOG_LEN];
+        millis.store(timestamp.as_millis() as u32, Ordering::Relaxed);
+        hit.store(
+            (pad as u32) << 16 | (note.index() as u32) << 8 | u32::from(velocity),
+            Ordering::Relaxed,
+        );
+        self.recorded
+            .store(recorded.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// The hits kept, oldest first.
+    pub fn hits(&self) -> impl Iterator<Item = LoggedHit> + '_ {
+        let recorded = self.recorded.load(Ordering::Relaxed) as usize;
+        let len = recorded.min(HIT_LOG_LEN);
+        (recorded - len..recorded).map(|i| {
+            let [millis, hit] = &self.entries[i % HIT_LOG_LEN];
+            let hit = hit.load(Ordering::Relaxed);
+            LoggedHit {
+                millis: millis.load(Ordering::Relaxed),
+                pad: (hit >> 16) as u8,
+                note: DrumNote::ALL[(hit >> 8) as u8 as usize],
+                velocity: hit as u8,
+            }
+        })
+    }
+}
+
+static PEAK_HEAP_USED: AtomicU32 = AtomicU32::new(0);
+
+/// Most heap bytes seen in use since boot, sampled every [`HEAP_SAMPLE_INTERVAL`].
+///
+/// What the heap can be cut down to in `main.rs`, with some margin for what was missed in between
+/// samples and for the features not exercised during the session (e.g. MIDI thru or a second
+/// connection), after running the heaviest session of the feature set built with. Running out
+/// shows as the out-of-memory blinking of the panic handler.
+pub fn peak_heap_used() -> usize {
+    PEAK_HEAP_USED.load(Ordering::Relaxed) as usize
+}
+
+#[cfg(not(test))]
+fn sample_heap() {
+    let used = esp_alloc::HEAP.used() as u32;
+    if used > PEAK_HEAP_USED.load(Ordering::Relaxed) {
+        PEAK_HEAP_USED.store(used, Ordering::Relaxed);
+    }
+}
+
+/// A wrapping event count since boot.
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    /// A plain load and store, as the ESP32-C3 (riscv32imc) has no atomic read-modify-write. Fine
+    /// as long as all the counting is done from tasks of the same executor, which don't preempt
+    /// each other, and not from interrupts.
+    pub fn increment(&self) {
+        let count = self.0.load(Ordering::Relaxed);
+        self.0.store(count.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(test))]
+#[embassy_executor::task]
+pub async fn telemetry_task() {
+    let mut ticker = Ticker::every(HEAP_SAMPLE_INTERVAL);
+    let samples_per_log = (TELEMETRY_INTERVAL.as_ticks() / HEAP_SAMPLE_INTERVAL.as_ticks()).max(1);
+    let mut samples = 0;
+    loop {
+        ticker.next().await;
+        sample_heap();
+        samples += 1;
+        if samples < samples_per_log {
+            continue;
+        }
+        samples = 0;
+        info!(
+            "[telemetry] {} hits, {} dropped, {} notify failures, {} reconnects, {} heap bytes \
+            free, {} used at peak",
+            COUNTERS.hits.get(),
+            COUNTERS.hits_dropped.get(),
+            COUNTERS.notify_failures.get(),
+            COUNTERS.connections.get().saturating_sub(1),
+            esp_alloc::HEAP.free(),
+            peak_heap_used()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::ble::control::encode_hit_log;
+
+    fn record_hits(log: &HitLog, count: usize) {
+        for i in 0..count {
+            let note = DrumNote::ALL[i % DrumNote::ALL.len()];
+            log.record(Instant::from_millis(i as u64), i % 4, note, i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn hit_log_keeps_the_latest_hits_oldest_first() {
+        let log = HitLog::new();
+        assert_eq!(log.hits().count(), 0);
+        record_hits(&log, HIT_LOG_LEN + 3);
+        let millis: std::vec::Vec<_> = log.hits().map(|hit| hit.millis).collect();
+        assert_eq!(
+            millis,
+            (3..HIT_LOG_LEN as u32 + 3).collect::<std::vec::Vec<_>>()
+        );
+        let last = log.hits().last().unwrap();
+        let i = HIT_LOG_LEN + 2;
+        assert_eq!(last.pad as usize, i % 4);
+        assert!(last.note == DrumNote::ALL[i % DrumNote::ALL.len()]);
+        assert_eq!(last.velocity as usize, i + 1);
+    }
+
+    #[test]
+    fn hit_log_encodes_the_hits_kept_then_zeros() {
+        let log = HitLog::new();
+        assert!(encode_hit_log(&log).iter().all(|&byte| byte == 0));
+        let note = DrumNote::ALL[1];
+        log.record(Instant::from_millis(0x0102_0304), 2, note, 90);
+        log.record(Instant::from_millis(0x0102_0310), 3, note, 100);
+        let value = encode_hit_log(&log);
+        assert_eq!(value[0], 2);
+        assert_eq!(value[1..8], [2, 1, 90, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(value[8..15], [3, 1, 100, 0x10, 0x03, 0x02, 0x01]);
+        assert!(value[15..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn full_hit_log_fills_the_value() {
+        let log = HitLog::new();
+        record_hits(&log, HIT_LOG_LEN + 3);
+        let value = encode_hit_log(&log);
+        assert_eq!(usize::from(value[0]), HIT_LOG_LEN);
+        // The last hit, at the very end.
+        let i = HIT_LOG_LEN + 2;
+        assert_eq!(
+            value[value.len() - 7..][..3],
+            [(i % 4) as u8, (i % DrumNote::ALL.len()) as u8, i as u8 + 1]
+        );
+    }
+}
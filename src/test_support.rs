@@ -0,0 +1,65 @@
+//! What the host tests need in place of the firmware's runtime: a `defmt` logger, and a way to
+//! run the async code against the mock time driver of `embassy-time`.
+
+use core::{
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use std::sync::{Mutex, MutexGuard};
+
+use embassy_time::{Duration, Instant, MockDriver};
+
+/// The logs go nowhere, the tests check the outcomes.
+#[defmt::global_logger]
+struct NoLogger;
+
+unsafe impl defmt::Logger for NoLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+/// Wakes the executor of the `embassy-executor` tasks, which the tests never spawn but link.
+#[unsafe(no_mangle)]
+fn __pender(_context: *mut ()) {}
+
+/// How far the time is moved on each time the future under test is pending.
+const TIME_STEP: Duration = Duration::from_micros(100);
+
+/// The mock time is global, so the tests depending on it take turns through this.
+static TIME: Mutex<()> = Mutex::new(());
+
+/// Exclusive use of the mock time, which a test holds for as long as it runs futures through
+/// [`run_until`].
+pub struct MockTime(#[allow(dead_code)] MutexGuard<'static, ()>);
+
+impl MockTime {
+    /// Wait for the other tests to be done with the mock time. It keeps going on from where they
+    /// left it, so the tests go by [`Instant::now`] rather than by absolute instants.
+    pub fn lock() -> Self {
+        Self(TIME.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+/// Run `future` to completion, moving the time on by a small step whenever it's pending, as if
+/// everything it waits for happened on a timer. Gives up with `None` at `deadline`, which a
+/// future meant to never complete (e.g. a task loop) ends with.
+pub fn run_until<F: Future>(_time: &MockTime, deadline: Instant, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        MockDriver::get().advance(TIME_STEP);
+    }
+}
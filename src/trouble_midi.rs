@@ -1,16 +1,25 @@
-use embassy_time::Instant;
-use midi_convert::render_slice::MidiRenderSlice;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use midi_convert::{parse::MidiTryParseSlice, render_slice::MidiRenderSlice};
 use midi_types::MidiMessage;
 use trouble_host::{prelude::*, types::gatt_traits::FromGattError};
 
 pub const MIDI_SERVICE_UUID: Uuid = uuid!("03B80E5A-EDE8-4B33-A751-6CE34EC4C700");
 
-#[gatt_service(uuid = MIDI_SERVICE_UUID)]
+// `MIDI_SERVICE_UUID`, as a literal: the macro converts any other expression with `.into()`, which
+// clippy finds useless on a `Uuid`.
+#[gatt_service(uuid = "03B80E5A-EDE8-4B33-A751-6CE34EC4C700")]
 pub struct MidiService {
-    #[characteristic(uuid = "7772E5DB-3868-4112-A1A9-F2669D106BF3", read, write_without_response, notify, value = MidiMessage::Reset.into())]
-    pub midi_event: BleMidiPacket<5>,
+    #[characteristic(uuid = "7772E5DB-3868-4112-A1A9-F2669D106BF3", read, write_without_response, notify, indicate, value = MidiMessage::Reset.into())]
+    pub midi_event: MidiEventPacket,
 }
 
+/// Packets of the `midi_event` characteristic, with room for two 3-byte messages, e.g. a Note On
+/// and its Note Off, or for a bank select and its program change (CC 0 and CC 32 sharing their
+/// status byte, then the Program Change), which a host then applies at once. Well within the 20
+/// bytes a notification carries at the default ATT MTU.
+pub type MidiEventPacket = BleMidiPacket<11>;
+
 pub trait AsTimestamp {
     fn as_timestamp(&self) -> u16;
 }
@@ -33,9 +42,33 @@ impl AsTimestamp for Instant {
     }
 }
 
+/// A timestamp shifted by a signed number of milliseconds, wrapping around like the timestamps do.
+pub struct Shifted<T>(pub T, pub i16);
+
+impl<T: AsTimestamp> AsTimestamp for Shifted<T> {
+    fn as_timestamp(&self) -> u16 {
+        // Only the low 13 bits are sent, which wrap the same with the whole `u16` wrapping.
+        self.0.as_timestamp().wrapping_add_signed(self.1)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct BleMidiPacket<const CAP: usize> {
     buffer: [u8; CAP],
     len: usize,
+    /// Status in effect after the last message, if built by [`BleMidiPacketBuilder`].
+    running_status: Option<u8>,
+}
+
+/// Longest [`MidiMessage`] rendered: a status byte and 2 data bytes. System exclusive, the only
+/// longer MIDI message, isn't one.
+const MAX_MESSAGE_LEN: usize = 3;
+
+/// `msg` as MIDI bytes, and how many of them it takes.
+fn render(msg: MidiMessage) -> ([u8; MAX_MESSAGE_LEN], usize) {
+    let mut rendered = [0; MAX_MESSAGE_LEN];
+    let len = msg.render_slice(&mut rendered);
+    (rendered, len)
 }
 
 fn is_system_msg_status_byte(status: u8) -> bool {
@@ -44,7 +77,7 @@ fn is_system_msg_status_byte(status: u8) -> bool {
 
 impl<const CAP: usize> BleMidiPacket<CAP> {
     const MIN_SIZE: usize = 3; // Header + Timestamp + Single MIDI status byte
-    const MIN_CAP: usize = 5; // Header + Timestamp + MIDI status byte + 2 Data bytes
+    const MIN_CAP: usize = 2 + MAX_MESSAGE_LEN; // Header + Timestamp + The longest message
 
     pub fn add_timestamped(
         timestamp: impl AsTimestamp,
@@ -52,7 +85,7 @@ impl<const CAP: usize> BleMidiPacket<CAP> {
     ) -> BleMidiPacketBuilder<CAP> {
         const { assert!(CAP >= Self::MIN_CAP) };
 
-        let millis = timestamp.as_timestamp();
+        let millis = timestamp.as_timestamp() & 0x1FFF;
         let header = 0x80 | ((millis >> 7) as u8 & 0x3F);
         let timestamp = 0x80 | (millis as u8 & 0x7F);
 
@@ -60,22 +93,191 @@ impl<const CAP: usize> BleMidiPacket<CAP> {
         buffer[0] = header;
         buffer[1] = timestamp;
 
-        let len = msg.render_slice(&mut buffer[2..]);
-        let packet = Self {
-            buffer,
-            len: 2 + len,
-        };
-
+        // Rendered on its own first, so the buffer is never written past what's checked to fit.
+        // Can't fail with the `MIN_CAP` asserted above, but a `CAP` too small for a message would
+        // panic here rather than end up with a truncated one.
+        let (rendered, len) = render(msg);
+        assert!(
+            2 + len <= CAP,
+            "MIDI message of {} bytes over the packet capacity",
+            len
+        );
+        buffer[2..][..len].copy_from_slice(&rendered[..len]);
         let running_status = if is_system_msg_status_byte(buffer[2]) {
             None
         } else {
             Some(buffer[2])
         };
+        let packet = Self {
+            buffer,
+            len: 2 + len,
+            running_status,
+        };
 
         BleMidiPacketBuilder {
             packet,
             running_status,
-            timestamp_byte: timestamp,
+            last_millis: millis,
+        }
+    }
+}
+
+/// The status byte last sent on a connection, for leaving it out of the following packets while
+/// it stays the same (running status across packets).
+///
+/// If the receiver misses a packet, it misreads the following ones until a status byte comes
+/// again, so this should only be used where packets are reliably delivered.
+#[derive(Default)]
+pub struct RunningStatus(Option<u8>);
+
+impl RunningStatus {
+    /// Forget the last status byte, e.g. on an error, so that the next packet carries one again.
+    pub fn reset(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl<const CAP: usize> BleMidiPacket<CAP> {
+    /// Leave out the status byte of the first message of this packet if it's the same as the last
+    /// one in `running_status`, then remember the packet's last one. A system message resets the
+    /// running status. Only for packets built by [`BleMidiPacketBuilder`].
+    pub fn with_running_status(mut self, running_status: &mut RunningStatus) -> Self {
+        let status = self.buffer[2];
+        if !is_system_msg_status_byte(status) && running_status.0 == Some(status) {
+            self.buffer.copy_within(3..self.len, 2);
+            self.len -= 1;
+        }
+        running_status.0 = self.running_status;
+        self
+    }
+}
+
+impl<const CAP: usize> BleMidiPacket<CAP> {
+    /// `msgs` all at `timestamp`, in order and in as few packets as they fit, for messages that
+    /// belong together (e.g. the Note Ons of a layered hit, or an NRPN). Panics if they need more
+    /// than `N` packets.
+    pub fn pack<const N: usize>(
+        timestamp: u16,
+        msgs: impl IntoIterator<Item = MidiMessage>,
+    ) -> Vec<Self, N> {
+        let mut packets = Vec::new();
+        let mut builder: Option<BleMidiPacketBuilder<CAP>> = None;
+        for msg in msgs {
+            builder = Some(match builder {
+                None => Self::add_timestamped(timestamp, msg),
+                Some(builder) => match builder.add_timestamped(timestamp, msg) {
+                    Ok(builder) => builder,
+                    Err(full) => {
+                        assert!(packets.push(full.build()).is_ok(), "too many packets");
+                        Self::add_timestamped(timestamp, msg)
+                    }
+                },
+            });
+        }
+        if let Some(builder) = builder {
+            assert!(packets.push(builder.build()).is_ok(), "too many packets");
+        }
+        packets
+    }
+
+    /// A packet without any MIDI, not even a header: what the BLE MIDI spec has a read of the
+    /// characteristic return.
+    pub const fn empty() -> Self {
+        Self {
+            buffer: [0; CAP],
+            len: 0,
+            running_status: None,
+        }
+    }
+
+    /// The raw bytes of this packet, header and timestamps included, as sent or received.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// The MIDI messages in this packet, without their timestamps. System exclusive and malformed
+    /// messages are skipped.
+    pub fn messages(&self) -> Messages<'_> {
+        Messages {
+            bytes: self.as_bytes().get(1..).unwrap_or_default(),
+            running_status: None,
+        }
+    }
+}
+
+/// Iterator over the messages of a [`BleMidiPacket`], see [`BleMidiPacket::messages`].
+///
+/// The packets come from whatever the central writes, so any bytes are expected. On malformed
+/// input, the iterator:
+/// - never panics: the packet is only ever read through checked splits, and a message is at
+///   most a status and 2 data bytes;
+/// - always ends: each step consumes at least one byte, so it never yields more items than the
+///   packet has bytes;
+/// - ends early rather than guessing, at data bytes without a running status to apply them to, at
+///   a message cut short by the end of the packet or by a byte with the high bit set (which
+///   `midi-types` would take for a note or value over 127, and panic on), and at SysEx;
+/// - skips what parses as no [`MidiMessage`], having consumed it, as well as, in debug builds,
+///   the Control Changes of controller 127 and the Program Changes to program 127, valid as they
+///   are but debug-asserted against by `midi-types`.
+pub struct Messages<'a> {
+    /// What's left of the packet past its header.
+    bytes: &'a [u8],
+    running_status: Option<u8>,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = MidiMessage;
+
+    fn next(&mut self) -> Option<MidiMessage> {
+        loop {
+            let (&first, rest) = self.bytes.split_first()?;
+            // A status byte always follows a timestamp byte. Data bytes right after a timestamp,
+            // or right after a whole message, continue the running status.
+            let status = if first & 0x80 != 0 {
+                self.bytes = rest;
+                match self.bytes.split_first() {
+                    Some((&status, rest)) if status & 0x80 != 0 => {
+                        self.bytes = rest;
+                        status
+                    }
+                    _ => self.running_status?,
+                }
+            } else {
+                self.running_status?
+            };
+
+            let data_len = match status {
+                // Spans packets and can embed timestamps, not worth parsing here.
+                0xF0 => {
+                    self.bytes = &[];
+                    return None;
+                }
+                0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+                0x80..=0xEF | 0xF2 => 2,
+                _ => 0,
+            };
+            let (data, rest) = self.bytes.split_at_checked(data_len)?;
+            if data.iter().any(|&byte| byte & 0x80 != 0) {
+                self.bytes = &[];
+                return None;
+            }
+            self.bytes = rest;
+            if !is_system_msg_status_byte(status) {
+                self.running_status = Some(status);
+            } else if status < 0xF8 {
+                // Real-time messages leave the running status alone, other system ones cancel it.
+                self.running_status = None;
+            }
+
+            if cfg!(debug_assertions) && matches!(status & 0xF0, 0xB0 | 0xC0) && data[0] == 127 {
+                continue;
+            }
+
+            let mut raw = [status, 0, 0];
+            raw[1..=data_len].copy_from_slice(data);
+            if let Ok(msg) = MidiMessage::try_parse_slice(&raw[..=data_len]) {
+                return Some(msg);
+            }
         }
     }
 }
@@ -93,11 +295,12 @@ impl<const CAP: usize> From<MidiMessage> for BleMidiPacket<CAP> {
 }
 
 impl<const CAP: usize> AsGatt for BleMidiPacket<CAP> {
-    const MIN_SIZE: usize = Self::MIN_SIZE;
+    // To allow `empty`.
+    const MIN_SIZE: usize = 0;
     const MAX_SIZE: usize = CAP;
 
     fn as_gatt(&self) -> &[u8] {
-        &self.buffer[..self.len]
+        self.as_bytes()
     }
 }
 
@@ -108,26 +311,228 @@ impl<const CAP: usize> FromGatt for BleMidiPacket<CAP> {
         } else {
             let mut buffer = [0; CAP];
             let len = data.len();
+            // Copied as is, see `as_bytes` and `messages` to get at it.
             buffer[..len].copy_from_slice(data);
-            // Copy data directly without parsing. Provide some way to get the data from the packet
-            // later if we need it?
 
-            Ok(Self { buffer, len })
+            Ok(Self {
+                buffer,
+                len,
+                running_status: None,
+            })
         }
     }
 }
 
-#[allow(unused)]
 pub struct BleMidiPacketBuilder<const CAP: usize> {
     packet: BleMidiPacket<CAP>,
     running_status: Option<u8>,
-    timestamp_byte: u8,
+    /// Timestamp of the last message, in the 13 bits of milliseconds a packet carries.
+    last_millis: u16,
 }
 
 impl<const CAP: usize> BleMidiPacketBuilder<CAP> {
-    pub fn build(self) -> BleMidiPacket<CAP> {
+    /// Latest a message can be added at after the previous one. Timestamp bytes only carry the
+    /// low 7 bits of the milliseconds, the receiver inferring a wrap-around from a timestamp going
+    /// backwards, so a step has to stay under 128 ms.
+    pub const MAX_TIMESTAMP_STEP: Duration = Duration::from_millis(127);
+
+    pub fn build(mut self) -> BleMidiPacket<CAP> {
+        self.packet.running_status = self.running_status;
         self.packet
     }
 
-    // TODO: Add builder functions for making running status MIDI packets.
+    /// Append `msg` at `timestamp`, leaving its status byte out if it's the same as the previous
+    /// message's (running status). Gives the builder back unchanged as `Err` if there's no room,
+    /// or if `timestamp` is before the previous message or more than
+    /// [`MAX_TIMESTAMP_STEP`](Self::MAX_TIMESTAMP_STEP) after it.
+    pub fn add_timestamped(
+        mut self,
+        timestamp: impl AsTimestamp,
+        msg: MidiMessage,
+    ) -> Result<Self, Self> {
+        let millis = timestamp.as_timestamp() & 0x1FFF;
+        if u64::from(millis.wrapping_sub(self.last_millis) & 0x1FFF)
+            > Self::MAX_TIMESTAMP_STEP.as_millis()
+        {
+            return Err(self);
+        }
+
+        let (rendered, len) = render(msg);
+        let status = rendered[0];
+        let is_system_msg = is_system_msg_status_byte(status);
+        let bytes = if !is_system_msg && self.running_status == Some(status) {
+            &rendered[1..len]
+        } else {
+            &rendered[..len]
+        };
+        if self.packet.len + 1 + bytes.len() > CAP {
+            return Err(self);
+        }
+
+        let packet = &mut self.packet;
+        packet.buffer[packet.len] = 0x80 | (millis as u8 & 0x7F);
+        packet.buffer[packet.len + 1..][..bytes.len()].copy_from_slice(bytes);
+        packet.len += 1 + bytes.len();
+        self.running_status = (!is_system_msg).then_some(status);
+        self.last_millis = millis;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_types::{Channel, Note, Value7};
+
+    use super::*;
+    use crate::midi::XorShift32;
+
+    /// The messages of a packet made of `bytes`, checking the parser's invariants on the way.
+    fn parse(bytes: &[u8]) -> std::vec::Vec<MidiMessage> {
+        let messages: std::vec::Vec<_> = Messages {
+            bytes,
+            running_status: None,
+        }
+        .take(bytes.len() + 1)
+        .collect();
+        assert!(
+            messages.len() <= bytes.len(),
+            "{} messages out of {bytes:02x?}",
+            messages.len()
+        );
+        messages
+    }
+
+    #[test]
+    fn messages_of_any_short_packet() {
+        for len in 0..=2 {
+            for n in 0..1u32 << (8 * len) {
+                parse(&n.to_le_bytes()[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn messages_of_random_packets() {
+        let mut rng = XorShift32::new(0x1234_5678);
+        let mut bytes = [0; 64];
+        for _ in 0..100_000 {
+            let len = rng.next_u32() as usize % (bytes.len() + 1);
+            for byte in &mut bytes[..len] {
+                // Mostly status and timestamp bytes, for the parser to get past the first ones.
+                *byte = rng.next_u32() as u8
+                    | if rng.next_u32().is_multiple_of(4) {
+                        0
+                    } else {
+                        0x80
+                    };
+            }
+            parse(&bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn messages_end_at_malformed_input() {
+        // Data bytes with no running status to apply them to.
+        assert_eq!(parse(&[0x80, 0x24, 0x7F]), []);
+        // A Note On cut short, by the end of the packet or by the next timestamp.
+        assert_eq!(parse(&[0x80, 0x90, 0x24]), []);
+        assert_eq!(parse(&[0x80, 0x90, 0x24, 0x81, 0xF8]), []);
+        // SysEx, and whatever follows it.
+        assert_eq!(parse(&[0x80, 0xF0, 0x01, 0x80, 0xF8]), []);
+    }
+
+    #[test]
+    fn messages_of_built_packet() {
+        let note_on = MidiMessage::NoteOn(Channel::C10, Note::new(38), Value7::new(100));
+        let note_off = MidiMessage::NoteOff(Channel::C10, Note::new(38), Value7::new(0));
+        let packet = BleMidiPacket::<11>::add_timestamped(0u16, note_on)
+            .add_timestamped(5u16, note_off)
+            .unwrap_or_else(|_| panic!("no room for the Note Off"))
+            .build();
+        assert_eq!(
+            parse(packet.as_bytes().get(1..).unwrap()),
+            [note_on, note_off]
+        );
+    }
+
+    #[test]
+    fn note_on_and_off_batched_in_one_packet() {
+        let note_on = MidiMessage::NoteOn(Channel::C10, Note::new(38), Value7::new(100));
+        let note_off = MidiMessage::NoteOff(Channel::C10, Note::new(38), Value7::new(0));
+        // Across a wrap of the packet's 7-bit timestamps.
+        let (on_at, off_at) = (0x1F70u16, 0x1F70u16.wrapping_add(0x30));
+        let packet = MidiEventPacket::add_timestamped(on_at, note_on)
+            .add_timestamped(off_at, note_off)
+            .unwrap_or_else(|_| panic!("gate too long for one packet"))
+            .build();
+        let bytes = packet.as_bytes();
+        assert_eq!(bytes[0] & 0x3F, ((on_at & 0x1FFF) >> 7) as u8);
+        assert_eq!(bytes[1], 0x80 | (on_at as u8 & 0x7F));
+        assert_eq!(bytes[5], 0x80 | (off_at as u8 & 0x7F));
+        assert_eq!(parse(&bytes[1..]), [note_on, note_off]);
+    }
+
+    #[test]
+    fn note_off_past_the_timestamp_range_needs_its_own_packet() {
+        let note_on = MidiMessage::NoteOn(Channel::C10, Note::new(38), Value7::new(100));
+        let note_off = MidiMessage::NoteOff(Channel::C10, Note::new(38), Value7::new(0));
+        let step = BleMidiPacketBuilder::<11>::MAX_TIMESTAMP_STEP.as_millis() as u16;
+        assert!(
+            MidiEventPacket::add_timestamped(0u16, note_on)
+                .add_timestamped(step, note_off)
+                .is_ok()
+        );
+        assert!(
+            MidiEventPacket::add_timestamped(0u16, note_on)
+                .add_timestamped(step + 1, note_off)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn message_over_a_small_capacity_is_refused() {
+        let note_on = MidiMessage::NoteOn(Channel::C10, Note::new(38), Value7::new(100));
+        // Room for the Note On only, then for a timestamp and a status byte more.
+        let full = BleMidiPacket::<5>::add_timestamped(0u16, note_on);
+        let Err(full) = full.add_timestamped(0u16, MidiMessage::TimingClock) else {
+            panic!("Timing Clock added past the capacity");
+        };
+        assert_eq!(full.build().as_bytes(), [0x80, 0x80, 0x99, 38, 100]);
+        assert!(
+            BleMidiPacket::<7>::add_timestamped(0u16, note_on)
+                .add_timestamped(0u16, MidiMessage::TimingClock)
+                .is_ok()
+        );
+        // A Note Off more takes a timestamp and 3 bytes, its status not being the running one.
+        let note_off = MidiMessage::NoteOff(Channel::C10, Note::new(38), Value7::new(0));
+        assert!(
+            BleMidiPacket::<8>::add_timestamped(0u16, note_on)
+                .add_timestamped(0u16, note_off)
+                .is_err()
+        );
+    }
+
+    /// The 13 bits of milliseconds a packet carries of `timestamp`.
+    fn packet_millis(timestamp: impl AsTimestamp) -> u16 {
+        timestamp.as_timestamp() & 0x1FFF
+    }
+
+    #[test]
+    fn shifted_timestamp() {
+        assert_eq!(packet_millis(Shifted(1_000u16, -20)), 980);
+        assert_eq!(packet_millis(Shifted(1_000u16, 20)), 1_020);
+        assert_eq!(packet_millis(Shifted(1_000u16, 0)), 1_000);
+        let now = Instant::from_millis(123_456);
+        assert_eq!(packet_millis(Shifted(now, 0)), packet_millis(now));
+    }
+
+    #[test]
+    fn shifted_timestamp_wraps_like_the_packet_timestamps() {
+        assert_eq!(packet_millis(Shifted(10u16, -20)), 0x1FFF - 9);
+        assert_eq!(packet_millis(Shifted(0x1FFFu16, 1)), 0);
+        assert_eq!(packet_millis(Shifted(0xFFFFu16, 1)), 0);
+        assert_eq!(packet_millis(Shifted(0u16, -1)), 0x1FFF);
+        // Back across the wrap of the 13 bits.
+        assert_eq!(packet_millis(Shifted(0x2005u16, -10)), 0x1FFB);
+    }
 }